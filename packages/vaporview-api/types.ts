@@ -81,9 +81,12 @@ export type SavedSignalSeparator = {
 
 // #region Event Types
 
+// Times are in the units of the file. Times too large for a number to hold
+// exactly are decimal strings.
+
 export interface MarkerSetEvent {
   uri: string;
-  time: number;
+  time: number | string;
   units: string;
 }
 
@@ -106,7 +109,7 @@ export interface ValueLinkEvent {
   numberFormat: string;
   value: string;
   formattedValue: string;
-  time: number;
+  time: number | string;
 }
 
 export interface ViewerDropEvent {
@@ -149,7 +152,7 @@ export type VariableAction = 'add' | 'remove' | 'reveal' | 'addLink';
 
 /** Arguments for `waveformViewer.setMarker` */
 export interface SetMarkerArgs {
-  time: number;
+  time: number | string;
   units?: string;
   markerType?: number;
   uri?: string;
@@ -163,7 +166,7 @@ export interface GetViewerStateArgs {
 /** Arguments for `waveformViewer.getValuesAtTime` */
 export interface GetValuesAtTimeArgs {
   uri?: string;
-  time?: number;
+  time?: number | string;
   instancePaths: string[];
 }
 
@@ -185,8 +188,8 @@ export interface ValuesAtTimeResult {
 export interface ViewerState {
   extensionVersion: string | undefined;
   fileName: string;
-  markerTime: number | null;
-  altMarkerTime: number | null;
+  markerTime: number | string | null;
+  altMarkerTime: number | string | null;
   displayTimeUnit: string;
   selectedSignal: { name: string; msb: number; lsb: number } | null;
   zoomRatio: number;
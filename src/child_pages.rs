@@ -0,0 +1,193 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use wellen::{Hierarchy, ItemRef, ScopeRef};
+
+use crate::error::{ErrorKind, LoadError};
+use crate::hierarchy_filter::{scope_visible, var_visible, HierarchyFilter};
+
+/*
+  Paging through the children of a scope with getchildrenpage(). The first
+  call lists the visible children of the scope once, scopes first, in the
+  order asked for, and later calls continue from the position in the token.
+  A token holds the listing in its upper 32 bits and the position of the
+  next child in the lower 32 bits, so each page only costs the children it
+  returns. A listing is dropped when its last page is returned, when the
+  same scope is listed again, or when it is the oldest of more than
+  MAX_LISTINGS, so scopes that are not paged to the end are not kept.
+ */
+
+const MAX_LISTINGS: usize = 16;
+
+#[derive(Clone, Copy)]
+pub enum ChildOrder {
+  // As in the file
+  File,
+  // By name, with numbers in names and bit indices compared by value, so
+  // that data[2] comes before data[10]
+  Natural,
+  Name,
+  // By scope or var type, then in natural order
+  Type,
+}
+
+impl ChildOrder {
+  pub fn parse(order: &str) -> Result<Self, LoadError> {
+    match order {
+      "file" => Ok(ChildOrder::File),
+      "natural" => Ok(ChildOrder::Natural),
+      "name" => Ok(ChildOrder::Name),
+      "type" => Ok(ChildOrder::Type),
+      _ => Err(LoadError::new(ErrorKind::InvalidArgument, format!("Unknown child order {:?}", order))),
+    }
+  }
+}
+
+/// Compares digit runs by value and everything else by character
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+  let (mut a_chars, mut b_chars) = (a.char_indices().peekable(), b.char_indices().peekable());
+  loop {
+    match (a_chars.peek().copied(), b_chars.peek().copied()) {
+      (None, None) => {return a.cmp(b);},
+      (None, Some(_)) => {return Ordering::Less;},
+      (Some(_), None) => {return Ordering::Greater;},
+      (Some((a_start, a_char)), Some((b_start, b_char))) if a_char.is_ascii_digit() && b_char.is_ascii_digit() => {
+        let a_end = a[a_start..].find(|c: char| !c.is_ascii_digit()).map_or(a.len(), |end| a_start + end);
+        let b_end = b[b_start..].find(|c: char| !c.is_ascii_digit()).map_or(b.len(), |end| b_start + end);
+        let a_digits = a[a_start..a_end].trim_start_matches('0');
+        let b_digits = b[b_start..b_end].trim_start_matches('0');
+        let ordering = a_digits.len().cmp(&b_digits.len()).then_with(|| a_digits.cmp(b_digits));
+        if ordering != Ordering::Equal {return ordering;}
+        while a_chars.next_if(|(i, _)| *i < a_end).is_some() {}
+        while b_chars.next_if(|(i, _)| *i < b_end).is_some() {}
+      },
+      (Some((_, a_char)), Some((_, b_char))) => {
+        if a_char != b_char {return a_char.cmp(&b_char);}
+        a_chars.next();
+        b_chars.next();
+      },
+    }
+  }
+}
+
+struct ChildKey<'h> {
+  child: ItemRef,
+  is_var: bool,
+  name: &'h str,
+  tpe: String,
+  // Bit index of a var, or -1
+  index: i64,
+}
+
+/// Visible children of a scope, scopes first, in the given order
+fn list_children(hierarchy: &Hierarchy, filter: &Option<HierarchyFilter>, scope: ScopeRef, order: ChildOrder) -> Vec<ItemRef> {
+  let parent = &hierarchy[scope];
+  let scopes = parent.scopes(hierarchy).filter(|s| scope_visible(filter, *s)).map(ItemRef::Scope);
+  let vars = parent.vars(hierarchy).filter(|v| var_visible(filter, *v)).map(ItemRef::Var);
+  let children = scopes.chain(vars);
+  if let ChildOrder::File = order {
+    return children.collect();
+  }
+
+  let mut keys: Vec<ChildKey> = children.map(|child| match child {
+    ItemRef::Scope(s) => {
+      let scope = &hierarchy[s];
+      ChildKey { child, is_var: false, name: scope.name(hierarchy), tpe: format!("{:?}", scope.scope_type()), index: -1 }
+    },
+    ItemRef::Var(v) => {
+      let var = &hierarchy[v];
+      let index = var.index().map_or(-1, |i| i.lsb());
+      ChildKey { child, is_var: true, name: var.name(hierarchy), tpe: format!("{:?}", var.var_type()), index }
+    },
+  }).collect();
+
+  keys.sort_by(|a, b| {
+    let ordering = match order {
+      ChildOrder::Name => a.name.cmp(b.name),
+      ChildOrder::Type => a.tpe.cmp(&b.tpe).then_with(|| natural_cmp(a.name, b.name)),
+      _ => natural_cmp(a.name, b.name),
+    };
+    a.is_var.cmp(&b.is_var).then(ordering).then(a.index.cmp(&b.index))
+  });
+  keys.into_iter().map(|key| key.child).collect()
+}
+
+struct Listing {
+  id: u32,
+  scope: ScopeRef,
+  children: Vec<ItemRef>,
+}
+
+pub struct ChildPages {
+  // Oldest first
+  listings: VecDeque<Listing>,
+  next_listing: u32,
+}
+
+impl ChildPages {
+  pub fn new() -> Self {
+    ChildPages { listings: VecDeque::new(), next_listing: 0 }
+  }
+
+  fn get(&self, id: u32) -> Option<&Listing> {
+    self.listings.iter().find(|listing| listing.id == id)
+  }
+
+  fn remove(&mut self, id: u32) {
+    self.listings.retain(|listing| listing.id != id);
+  }
+
+  /// Lists the children of a scope, and returns the token of the first page
+  pub fn start(&mut self, hierarchy: &Hierarchy, filter: &Option<HierarchyFilter>, scope: ScopeRef, order: ChildOrder) -> u64 {
+    let id = self.next_listing;
+    self.next_listing = self.next_listing.wrapping_add(1);
+    self.listings.retain(|listing| listing.scope != scope);
+    if self.listings.len() >= MAX_LISTINGS {
+      self.listings.pop_front();
+    }
+    self.listings.push_back(Listing { id, scope, children: list_children(hierarchy, filter, scope, order) });
+    (id as u64) << 32
+  }
+
+  /// The children from the position of the token on
+  pub fn children(&self, token: u64) -> Result<&[ItemRef], LoadError> {
+    let position = (token & 0xFFFFFFFF) as usize;
+    match self.get((token >> 32) as u32) {
+      Some(listing) if position <= listing.children.len() => Ok(&listing.children[position..]),
+      _ => Err(LoadError::new(ErrorKind::InvalidArgument, format!("Unknown or expired token {}", token))),
+    }
+  }
+
+  /// The token of the page after `count` more children, or None after the
+  /// last page, which drops the listing
+  pub fn advance(&mut self, token: u64, count: u32) -> Option<u64> {
+    let id = (token >> 32) as u32;
+    let next = token + count as u64;
+    let total = self.get(id).map_or(0, |listing| listing.children.len() as u64);
+    if (next & 0xFFFFFFFF) < total {
+      Some(next)
+    } else {
+      self.remove(id);
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn orders_digit_runs_by_value() {
+    let mut names = vec!["b1", "reg10", "a", "reg2", "reg1x", "reg", "reg02", "reg99999999999999999999999"];
+    names.sort_by(|a, b| natural_cmp(a, b));
+    assert_eq!(names, ["a", "b1", "reg", "reg1x", "reg02", "reg2", "reg10", "reg99999999999999999999999"]);
+  }
+
+  #[test]
+  fn breaks_ties_by_text() {
+    assert_eq!(natural_cmp("x01", "x1"), Ordering::Less);
+    assert_eq!(natural_cmp("x1", "x1"), Ordering::Equal);
+    assert_eq!(natural_cmp("ä2_1", "ä2_01"), Ordering::Greater);
+    assert_eq!(natural_cmp("data[7]", "data[10]"), Ordering::Less);
+  }
+}
@@ -53,4 +53,43 @@ export function logScaleFromUnits(unit: string | undefined) {
     case 'ks': return 3;
     default: return 0;
   }
-}
\ No newline at end of file
+}
+
+// Times are 64 bit in waveform files. The webview holds them as numbers,
+// relative to the time origin of the document so that they stay exact, see
+// WaveformDumpMetadata.timeOrigin.
+
+// Adds a time difference, as a bigint once the sum is past what a number holds exactly
+export function addTime(time: number | bigint, delta: number | bigint): number | bigint {
+  if (typeof time === "number" && typeof delta === "number" && time + delta <= Number.MAX_SAFE_INTEGER) {
+    return time + delta;
+  }
+  return BigInt(time) + BigInt(delta);
+}
+
+// A time relative to the origin as an absolute time, which is a decimal
+// string if a number can't hold it exactly
+export function absoluteTime(time: number, origin: bigint): number | string {
+  if (origin === 0n) {return time;}
+  const absolute = origin + BigInt(Math.round(time));
+  return absolute <= BigInt(Number.MAX_SAFE_INTEGER) ? Number(absolute) : absolute.toString();
+}
+
+// A time, which may be a decimal string, relative to the origin
+export function relativeTime(time: number | bigint | string, origin: bigint): number {
+  if (origin === 0n && typeof time === "number") {return time;}
+  return Number(BigInt(time) - origin);
+}
+
+// A time relative to the origin, scaled by timeScale * 10^logScale, as an
+// exact decimal string
+export function formatTime(time: number, origin: bigint, timeScale: number, logScale: number): string {
+  const scaled = (origin + BigInt(Math.round(time))) * BigInt(timeScale);
+  if (logScale >= 0) {return (scaled * 10n ** BigInt(logScale)).toString();}
+  const digits   = -logScale;
+  const negative = scaled < 0n;
+  const text     = (negative ? -scaled : scaled).toString().padStart(digits + 1, "0");
+  const fraction = text.slice(-digits).replace(/0+$/, "");
+  const integer  = (negative ? "-" : "") + text.slice(0, -digits);
+  return fraction === "" ? integer : integer + "." + fraction;
+}
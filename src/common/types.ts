@@ -82,10 +82,15 @@ export type WaveformDumpMetadata = {
   netlistIdCount: number;
   signalIdCount: number;
   timeTableCount: number;
+  // Relative to timeOrigin
   timeEnd: number;
   minTimeStep: number;
   timeScale: number;
   timeUnit: string;
+  // Decimal time that the webview's times are relative to. It is 0 unless
+  // the file has times too large for a number to hold exactly, then it is
+  // the first time, so the times stay exact as numbers.
+  timeOrigin: string;
 };
 
 export interface InitMessage {
@@ -241,4 +246,12 @@ export type CompressedValueChangeDataChunk = {
   originalSize: number;
 };
 
+export type TimeTableChunk = {
+  command: 'update-time-table-chunk';
+  compressedDataChunk: number[];
+  totalChunks: number;
+  chunkNum: number;
+  timeTableLength: number;
+};
+
 export type RowItemContextMenuEvent = NetlistVariableContext | CustomVariableContext | SignalGroupContext | SignalSeparatorContext;
\ No newline at end of file
@@ -0,0 +1,172 @@
+use std::io::{self, BufRead, Cursor, Read, Seek, SeekFrom};
+use flate2::bufread::MultiGzDecoder;
+use lzma_rust2::XzReader;
+use ruzstd::decoding::{FrameDecoder, StreamingDecoder};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+  Gzip,
+  Zstd,
+  Xz,
+}
+
+impl Compression {
+  /// Detects a compressed file from its first bytes
+  pub fn detect(magic: &[u8]) -> Option<Compression> {
+    if magic.starts_with(&[0x1f, 0x8b]) {
+      Some(Compression::Gzip)
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+      Some(Compression::Zstd)
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+      Some(Compression::Xz)
+    } else {
+      None
+    }
+  }
+}
+
+enum Decoder<R: BufRead> {
+  Gzip(MultiGzDecoder<R>),
+  Zstd(Box<StreamingDecoder<R, FrameDecoder>>),
+  Xz(Box<XzReader<R>>),
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+  fn open(compression: Compression, mut input: R) -> io::Result<Self> {
+    input.seek(SeekFrom::Start(0))?;
+    Ok(match compression {
+      Compression::Gzip => Decoder::Gzip(MultiGzDecoder::new(input)),
+      Compression::Zstd => Decoder::Zstd(Box::new(StreamingDecoder::new(input).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?)),
+      Compression::Xz => Decoder::Xz(Box::new(XzReader::new(input, true))),
+    })
+  }
+
+  fn into_inner(self) -> R {
+    match self {
+      Decoder::Gzip(d) => d.into_inner(),
+      Decoder::Zstd(d) => (*d).into_inner(),
+      Decoder::Xz(d) => (*d).into_inner(),
+    }
+  }
+}
+
+/// Decompresses a file on the fly, so that wellen can read it like any other
+/// input. The decompressed data can only be produced in order, so seeking
+/// forwards skips data and seeking backwards starts over from the beginning.
+/// wellen only seeks back while detecting the file format, so this is cheap
+/// for the streamed formats. Seeking from the end needs the decompressed
+/// size, which is found by decompressing the whole file once.
+pub struct DecompressReader<R: BufRead + Seek> {
+  compression: Compression,
+  decoder: Option<Decoder<R>>,
+  position: u64,
+  length: Option<u64>,
+}
+
+impl<R: BufRead + Seek> DecompressReader<R> {
+  pub fn new(compression: Compression, input: R) -> io::Result<Self> {
+    let decoder = Decoder::open(compression, input)?;
+    Ok(DecompressReader { compression, decoder: Some(decoder), position: 0, length: None })
+  }
+
+  /// The decompressed size, which is found on the first call by reading to
+  /// the end and seeking back
+  pub fn length(&mut self) -> io::Result<u64> {
+    if let Some(length) = self.length {return Ok(length);}
+    let position = self.position;
+    io::copy(self, &mut io::sink())?;
+    let length = self.position;
+    self.length = Some(length);
+    self.seek(SeekFrom::Start(position))?;
+    Ok(length)
+  }
+
+  fn restart(&mut self) -> io::Result<()> {
+    self.position = 0;
+    match self.decoder.take() {
+      Some(decoder) => {
+        self.decoder = Some(Decoder::open(self.compression, decoder.into_inner())?);
+        Ok(())
+      },
+      None => Err(io::Error::other("Decompressor failed to restart")),
+    }
+  }
+}
+
+impl<R: BufRead + Seek> Read for DecompressReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let bytes_read = match &mut self.decoder {
+      Some(Decoder::Gzip(d)) => d.read(buf)?,
+      Some(Decoder::Zstd(d)) => d.read(buf)?,
+      Some(Decoder::Xz(d)) => d.read(buf)?,
+      None => {return Err(io::Error::other("Decompressor failed to restart"));}
+    };
+    self.position += bytes_read as u64;
+    Ok(bytes_read)
+  }
+}
+
+impl<R: BufRead + Seek> Seek for DecompressReader<R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let target = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+      SeekFrom::End(offset) => self.length()?.checked_add_signed(offset),
+    };
+    let target = target.ok_or_else(|| {
+      io::Error::new(io::ErrorKind::Unsupported, format!("Unsupported seek in compressed file: {:?}", pos))
+    })?;
+
+    if target < self.position {self.restart()?;}
+    let skip = target - self.position;
+    io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+    Ok(self.position)
+  }
+
+  fn stream_position(&mut self) -> io::Result<u64> {Ok(self.position)}
+}
+
+/// Decompresses a file held in memory, or returns None if it decompresses to
+/// more than limit bytes
+pub fn decompress_bounded(compression: Compression, file: Vec<u8>, limit: u64) -> io::Result<Option<Vec<u8>>> {
+  let mut decompressed = Vec::new();
+  DecompressReader::new(compression, Cursor::new(file))?.take(limit.saturating_add(1)).read_to_end(&mut decompressed)?;
+  Ok(if decompressed.len() as u64 > limit {None} else {Some(decompressed)})
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use flate2::write::GzEncoder;
+  use std::io::Write;
+
+  fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+  }
+
+  #[test]
+  fn seeks_from_the_end() {
+    let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    let mut reader = DecompressReader::new(Compression::Gzip, Cursor::new(gzip(&data))).unwrap();
+    let mut start = [0; 10];
+    reader.read_exact(&mut start).unwrap();
+    assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+    assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), data.len() as u64 - 4);
+    let mut end = Vec::new();
+    reader.read_to_end(&mut end).unwrap();
+    assert_eq!(end, &data[data.len() - 4..]);
+    reader.seek(SeekFrom::Start(10)).unwrap();
+    let mut next = [0; 10];
+    reader.read_exact(&mut next).unwrap();
+    assert_eq!(next, data[10..20]);
+  }
+
+  #[test]
+  fn bounds_the_decompressed_size() {
+    let data = vec![b'x'; 10_000];
+    assert_eq!(decompress_bounded(Compression::Gzip, gzip(&data), 10_000).unwrap(), Some(data.clone()));
+    assert_eq!(decompress_bounded(Compression::Gzip, gzip(&data), 9_999).unwrap(), None);
+  }
+}
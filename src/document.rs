@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Cursor};
+use lazy_static::lazy_static;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use wellen::{FileFormat, Hierarchy, Signal, SignalRef, SignalSource, TimeTable};
+use wellen::viewers::{read_body, BodyResult, ReadBodyContinuation};
+
+use crate::child_pages::ChildPages;
+use crate::decompress::Compression;
+use crate::{fsread, get_chunk_size, outputlog, setchunksize, CompressedFileReader, LoadSettings, ReadbodyStatus, WasmFileReader};
+use crate::error::{loaded, loaded_mut, ErrorKind, LoadError};
+use crate::hierarchy_filter::HierarchyFilter;
+use crate::page_cache::CacheCounters;
+use crate::sidecar::{IndexBuilder, SidecarIndex};
+use crate::signal_cache::{SignalCache, DEFAULT_BUDGET};
+use crate::signal_queue::SignalQueue;
+use crate::vaporview::fileparser::types::TimeWindow;
+use crate::vcd_body::{VcdBodyParser, VcdBodyReader};
+use crate::vcd_index::VcdIndex;
+
+// Bytes read per fsread() call when a file is tailed
+const TAIL_READ_SIZE: u64 = 65536;
+
+pub enum ReadBodyEnum {
+  Static(ReadBodyContinuation<Cursor<Vec<u8>>>),
+  Dynamic(ReadBodyContinuation<BufReader<WasmFileReader>>),
+  Compressed(ReadBodyContinuation<BufReader<CompressedFileReader>>),
+  // VCD files that are loaded through fsread() are parsed in steps
+  Vcd(Box<VcdBodyReader>),
+  None,
+}
+
+/// Everything the filehandler knows about one open waveform file. The host
+/// refers to a document by the handle returned from `createdocument`, so a
+/// single wasm instance can hold several files at once.
+pub struct WaveformDocument {
+  pub file_format: FileFormat,
+  pub compression: Option<Compression>,
+  pub settings: LoadSettings,
+  pub filter: Option<HierarchyFilter>,
+  pub hierarchy: Option<Hierarchy>,
+  pub body: ReadBodyEnum,
+  pub time_table: Option<TimeTable>,
+  pub signal_source: Option<SignalSource>,
+  pub param_table: Option<Vec<(u32, String)>>,
+  pub param_id_list: Option<Vec<SignalRef>>,
+  pub signal_cache: SignalCache,
+  pub signal_queue: SignalQueue,
+  pub child_pages: ChildPages,
+  // Signal data refers to the time table sent by sendtimetable() instead of
+  // holding times
+  pub time_indices: bool,
+  // Set instead of signal_source when the document was opened from a sidecar index
+  pub index: Option<SidecarIndex>,
+
+  // Region loading of VCD files that are too large to be parsed in full.
+  // signal_source and time_table then only hold the loaded region.
+  pub body_start: u64,
+  pub vcd_index: Option<VcdIndex>,
+  pub region: Option<TimeWindow>,
+
+  // Incremental body loading
+  pub file_size: u64,
+  pub load_cancelled: bool,
+
+  // Live tail, fd is None for documents loaded from a buffer. The parser of
+  // the body is kept when live_tail is set, to resume where it stopped.
+  pub fd: Option<u32>,
+  pub live_tail: bool,
+  pub tail: Option<VcdBodyParser>,
+  // The signal source is behind the tail parser, see tail()
+  tail_stale: bool,
+
+  pub cache_counters: Option<Arc<CacheCounters>>,
+
+  // Sidecar index that is being written by buildindexstep()
+  pub index_builder: Option<IndexBuilder>,
+
+  // Data collected by appendbuffer() until loadbuffer() is called
+  pub buffer: Vec<u8>,
+
+  // Chunked data reassembly
+  pub chunks: Vec<Vec<u8>>,
+  pub total_chunks: u32,
+}
+
+impl WaveformDocument {
+  fn new() -> Self {
+    WaveformDocument {
+      file_format: FileFormat::Unknown,
+      compression: None,
+      settings: LoadSettings {
+        time_window: None,
+        include_scopes: Vec::new(),
+        exclude_scopes: Vec::new(),
+        include_var_types: Vec::new(),
+        exclude_var_types: Vec::new(),
+      },
+      filter: None,
+      hierarchy: None,
+      body: ReadBodyEnum::None,
+      time_table: None,
+      signal_source: None,
+      param_table: None,
+      param_id_list: None,
+      signal_cache: SignalCache::new(DEFAULT_BUDGET),
+      signal_queue: SignalQueue::new(),
+      child_pages: ChildPages::new(),
+      time_indices: false,
+      index: None,
+      body_start: 0,
+      vcd_index: None,
+      region: None,
+      file_size: 0,
+      load_cancelled: false,
+      fd: None,
+      live_tail: false,
+      tail: None,
+      tail_stale: false,
+      cache_counters: None,
+      index_builder: None,
+      buffer: Vec::new(),
+      chunks: Vec::new(),
+      total_chunks: 0,
+    }
+  }
+
+  /// Drops all loaded data, but keeps the handle valid and its load settings
+  /// so the document can be reused
+  pub fn clear(&mut self) {
+    let settings = self.settings.clone();
+    let budget = self.signal_cache.budget;
+    let live_tail = self.live_tail;
+    *self = WaveformDocument::new();
+    self.settings = settings;
+    self.signal_cache.budget = budget;
+    self.live_tail = live_tail;
+  }
+
+  /// The time range that signal data is limited to, from the load settings
+  /// and the loaded region
+  pub fn time_window(&self) -> Option<TimeWindow> {
+    match (self.settings.time_window, self.region) {
+      (Some(window), Some(region)) => Some(TimeWindow {
+        start: std::cmp::max(window.start, region.start),
+        end: std::cmp::min(window.end, region.end),
+      }),
+      (window, region) => window.or(region),
+    }
+  }
+
+  /// The part of the time table in the time window
+  pub fn windowed_time_table(&self) -> Result<&[u64], LoadError> {
+    let time_table = loaded(&self.time_table, "time table")?;
+    Ok(match &self.time_window() {
+      Some(window) => {
+        let first = time_table.partition_point(|t| *t < window.start);
+        let last = time_table.partition_point(|t| *t <= window.end);
+        &time_table[first..std::cmp::max(first, last)]
+      },
+      None => &time_table[..],
+    })
+  }
+
+  // Sends the time range of the body to the host, limited to the time window
+  pub fn send_chunk_size(&self, handle: u32) -> Result<(), LoadError> {
+    let time_table = self.windowed_time_table()?;
+    let (chunk_size, time_end_extend) = get_chunk_size(time_table);
+    setchunksize(handle, chunk_size, time_end_extend, time_table.len() as u64);
+    Ok(())
+  }
+
+  /// Does one bounded piece of body loading. A VCD body that is read
+  /// through fsread() is parsed about `budget` bytes per step, so the host
+  /// can serve other calls or cancel in between. Other bodies are parsed in
+  /// one step, they are either in memory already or read lazily.
+  pub fn read_body_step(&mut self, handle: u32, budget: u64) -> Result<ReadbodyStatus, LoadError> {
+    if let ReadBodyEnum::Vcd(body) = &mut self.body {
+      if !body.step(budget)? {
+        let percent = (body.file_position() as f64 / std::cmp::max(self.file_size, 1) as f64 * 100.0) as u32;
+        return Ok(ReadbodyStatus { done: false, percent: std::cmp::min(percent, 99) });
+      }
+    }
+
+    self.finish_body(handle)?;
+    Ok(ReadbodyStatus { done: true, percent: 100 })
+  }
+
+  /// Parses whatever is left of the body in one go
+  pub fn finish_body(&mut self, handle: u32) -> Result<(), LoadError> {
+    //log(&format!("Reading body..."));
+
+    let hierarchy = loaded(&self.hierarchy, "hierarchy")?;
+    let body = std::mem::replace(&mut self.body, ReadBodyEnum::None);
+    let wellen_body = |result: wellen::Result<BodyResult>| {
+      result.map(|body| (body.source, body.time_table)).map_err(LoadError::from_wellen)
+    };
+
+    let (signal_source, time_table) = match body {
+      ReadBodyEnum::Vcd(mut body) => {
+        while !body.step(u64::MAX)? {}
+        if self.live_tail && self.compression.is_none() && self.fd.is_some() {
+          let mut parser = body.into_parser();
+          let result = parser.snapshot()?;
+          parser.track_changes();
+          self.tail = Some(parser);
+          result
+        } else {
+          body.finish()?
+        }
+      },
+      ReadBodyEnum::Dynamic(body) => wellen_body(read_body(body, hierarchy, None))?,
+      ReadBodyEnum::Static(body) => wellen_body(read_body(body, hierarchy, None))?,
+      ReadBodyEnum::Compressed(body) => wellen_body(read_body(body, hierarchy, None))?,
+      ReadBodyEnum::None => {
+        if self.load_cancelled {
+          return Err(LoadError::new(ErrorKind::Cancelled, "Body loading was cancelled"));
+        }
+        return Err(LoadError::new(ErrorKind::NotLoaded, "No body found, call loadfile first"));
+      }
+    };
+
+    //log(&format!("Done reading body"));
+
+    self.time_table = Some(time_table);
+    self.signal_source = Some(signal_source);
+
+    if self.file_format != FileFormat::Fst {
+      self.cache_signals(&[])?;
+    }
+
+    self.send_chunk_size(handle)
+  }
+
+  /// Completes opening a document from a sidecar index, once the hierarchy
+  /// has been sent. The index replaces the body, so nothing is left to read.
+  pub fn finish_index(&mut self, handle: u32) -> Result<(), LoadError> {
+    if self.file_format != FileFormat::Fst {
+      self.cache_signals(&[])?;
+    }
+
+    self.send_chunk_size(handle)
+  }
+
+  /// Abandons a body load that is in progress. The hierarchy stays loaded.
+  pub fn cancel_load(&mut self) {
+    if let ReadBodyEnum::None = self.body {return;}
+    self.body = ReadBodyEnum::None;
+    self.load_cancelled = true;
+  }
+
+  /// Picks up data appended to a VCD file since it was loaded, so that a
+  /// running simulation can be watched. The parser kept by finish_body()
+  /// continues from the last byte it read. The new times are appended to the
+  /// time table, and cached signals that changed are replaced from a snapshot
+  /// of the parser's encoder. A snapshot copies every signal, so it is put
+  /// off until a cached signal changes or another signal is loaded. Signal
+  /// ids stay the same, since a VCD header can't change once it's written.
+  pub fn tail(&mut self, handle: u32, size: u64) -> Result<bool, LoadError> {
+    if self.file_format != FileFormat::Vcd {
+      return Err(LoadError::new(ErrorKind::InvalidArgument, "Live tail is only supported for VCD files"));
+    }
+    let fd = match self.fd {
+      Some(fd) => fd,
+      None => {return Err(LoadError::new(ErrorKind::InvalidArgument, "Live tail needs a file, this document was loaded from a buffer"));}
+    };
+    if self.compression.is_some() {
+      return Err(LoadError::new(ErrorKind::InvalidArgument, "Live tail is not supported for compressed files"));
+    }
+    let parser = match &mut self.tail {
+      Some(parser) => parser,
+      None => {return Err(LoadError::new(ErrorKind::InvalidArgument, "Live tail needs setlivetail() before the body is read, and is not supported for documents loaded from an index or by region"));}
+    };
+    if size < self.file_size {
+      return Err(LoadError::new(ErrorKind::InvalidArgument, format!("File shrank from {} to {} bytes, reload it instead", self.file_size, size)));
+    }
+    // Nothing after the time window is kept
+    if parser.is_done() {return Ok(false);}
+
+    let start = parser.position();
+    while parser.position() < size && !parser.is_done() {
+      let length = std::cmp::min(size - parser.position(), TAIL_READ_SIZE);
+      let data = fsread(fd, parser.position(), length as u32);
+      let length = std::cmp::min(data.len() as u64, length) as usize;
+      if length == 0 {break;}
+      parser.feed(&data[..length])?;
+    }
+    let parsed = parser.position() > start;
+    let changes = parser.take_changes();
+    self.file_size = size;
+    if !parsed {return Ok(false);}
+
+    loaded_mut(&mut self.time_table, "time table")?.extend_from_slice(&changes.times);
+    let changed: Vec<SignalRef> = self.signal_cache.signal_refs().into_iter()
+      .filter(|signal_ref| changes.signals.get(signal_ref.index()).copied().unwrap_or(false))
+      .collect();
+    if changed.is_empty() {
+      self.tail_stale = true;
+    } else {
+      // The cached signals that changed end where the file ended before, they
+      // are loaded again so that the host finds them extended
+      self.refresh_tail()?;
+      for signal_ref in &changed {
+        self.signal_cache.remove(*signal_ref);
+      }
+      self.cache_signals(&changed)?;
+    }
+
+    self.send_chunk_size(handle)?;
+    Ok(true)
+  }
+
+  // Replaces the signals and the time table by a snapshot of the live tail
+  // parser
+  fn refresh_tail(&mut self) -> Result<(), LoadError> {
+    let (signal_source, time_table) = loaded(&self.tail, "live tail")?.snapshot()?;
+    self.signal_source = Some(signal_source);
+    self.time_table = Some(time_table);
+    self.tail_stale = false;
+    Ok(())
+  }
+
+  /// Loads signals from the body, or from the sidecar index if the document
+  /// was opened from one
+  pub fn load_signals(&mut self, signal_id_list: &[SignalRef]) -> Result<Vec<Signal>, LoadError> {
+    if let Some(index) = &mut self.index {
+      return index.load_signals(signal_id_list);
+    }
+    if self.tail_stale {
+      self.refresh_tail()?;
+    }
+    let hierarchy = loaded(&self.hierarchy, "hierarchy")?;
+    let signal_source = loaded_mut(&mut self.signal_source, "waveform body")?;
+    Ok(signal_source.load_signals(signal_id_list, hierarchy, false))
+  }
+
+  /// Makes sure that the signals are in the signal cache, loading the ones
+  /// that aren't. load_signals() is a potentially expensive operation, so
+  /// parameters are loaded in the same batch as the first signal request.
+  pub fn cache_signals(&mut self, signal_id_list: &[SignalRef]) -> Result<(), LoadError> {
+    let missing = self.signal_cache.request(signal_id_list);
+    let load_parameters = self.param_id_list.is_some();
+    if missing.is_empty() && !load_parameters {return Ok(());}
+    if load_parameters {
+      outputlog("Loading parameters and signals");
+    }
+    let param_id_list = self.param_id_list.take().unwrap_or_default();
+
+    let all_signal_ids = [param_id_list.to_vec(), missing.clone()].concat();
+    let signal_data = self.load_signals(&all_signal_ids)?;
+    let mut param_table = Vec::new();
+
+    // Consume signal_data, process params immediately, cache the requested signals
+    for signal in signal_data {
+      let signal_ref = signal.signal_ref();
+      if param_id_list.contains(&signal_ref) {
+        let index = signal.get_first_time_idx();
+        let data_offset = index.and_then(|i| signal.get_offset(i));
+        if let Some(offset) = data_offset {
+          let value = signal.get_value_at(&offset, 0);
+          param_table.push((signal_ref.index() as u32, value.to_string()));
+        }
+      }
+      if missing.contains(&signal_ref) {
+        self.signal_cache.insert(signal);
+      }
+    }
+
+    if load_parameters {
+      self.param_table = Some(param_table);
+    }
+    self.signal_cache.evict();
+    Ok(())
+  }
+}
+
+lazy_static! {
+  static ref _documents: Mutex<HashMap<u32, WaveformDocument>> = Mutex::new(HashMap::new());
+  static ref _next_handle: Mutex<u32> = Mutex::new(0);
+}
+
+// A failed call must not leave the registry unusable, so a poisoned lock is
+// recovered rather than propagated
+fn documents() -> MutexGuard<'static, HashMap<u32, WaveformDocument>> {
+  _documents.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn invalid_handle(handle: u32) -> LoadError {
+  LoadError::new(ErrorKind::InvalidHandle, format!("Invalid document handle: {}", handle))
+}
+
+pub fn create_document() -> u32 {
+  let mut next_handle = _next_handle.lock().unwrap_or_else(PoisonError::into_inner);
+  let handle = *next_handle;
+  *next_handle = next_handle.wrapping_add(1);
+  documents().insert(handle, WaveformDocument::new());
+  handle
+}
+
+pub fn remove_document(handle: u32) -> Result<(), LoadError> {
+  match documents().remove(&handle) {
+    Some(_) => Ok(()),
+    None => Err(invalid_handle(handle)),
+  }
+}
+
+/// Runs `f` on the document for `handle`
+pub fn with_document<T>(handle: u32, f: impl FnOnce(&mut WaveformDocument) -> Result<T, LoadError>) -> Result<T, LoadError> {
+  let mut documents = documents();
+  match documents.get_mut(&handle) {
+    Some(document) => f(document),
+    None => Err(invalid_handle(handle)),
+  }
+}
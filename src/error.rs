@@ -0,0 +1,65 @@
+use std::any::Any;
+use wellen::WellenError;
+
+pub use crate::vaporview::fileparser::types::{ErrorKind, LoadError};
+
+impl LoadError {
+  pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+    LoadError { kind, message: message.into(), offset: None }
+  }
+
+  pub fn at_offset(mut self, offset: Option<u64>) -> Self {
+    self.offset = offset;
+    self
+  }
+
+  pub fn not_loaded(what: &str) -> Self {
+    LoadError::new(ErrorKind::NotLoaded, format!("No {} loaded for this document", what))
+  }
+
+  pub fn from_wellen(error: WellenError) -> Self {
+    match error {
+      WellenError::UnknownFileFormat => LoadError::new(ErrorKind::UnknownFormat, "Unknown file format"),
+      e => LoadError::new(ErrorKind::ParseError, format!("{:?}", e)),
+    }
+  }
+
+  fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+      s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+      s.clone()
+    } else {
+      "unknown panic".to_string()
+    };
+    LoadError::new(ErrorKind::Internal, format!("Internal error: {}", message))
+  }
+}
+
+/// Borrows a piece of document state that a previous call should have loaded
+pub fn loaded<'a, T>(value: &'a Option<T>, what: &str) -> Result<&'a T, LoadError> {
+  value.as_ref().ok_or_else(|| LoadError::not_loaded(what))
+}
+
+pub fn loaded_mut<'a, T>(value: &'a mut Option<T>, what: &str) -> Result<&'a mut T, LoadError> {
+  value.as_mut().ok_or_else(|| LoadError::not_loaded(what))
+}
+
+/// Runs an export body, and writes every failure to the output log, since
+/// that is where users look when something goes wrong. Panics only become
+/// Internal errors where they unwind, as in native tests. The wasm build
+/// aborts on panic, which traps the instance, so export bodies must return
+/// errors for bad files and calls instead of panicking.
+pub fn guard<T>(f: impl FnOnce() -> Result<T, LoadError>) -> Result<T, LoadError> {
+  let result = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+    Ok(result) => result,
+    Err(payload) => Err(LoadError::from_panic(payload)),
+  };
+  if let Err(e) = &result {
+    match e.offset {
+      Some(offset) => crate::outputlog(&format!("{:?}: {} (at byte offset {})", e.kind, e.message, offset)),
+      None => crate::outputlog(&format!("{:?}: {}", e.kind, e.message)),
+    }
+  }
+  result
+}
@@ -1,7 +1,7 @@
 import * as vscode from 'vscode';
 import { type SignalId, type NetlistId, StateChangeType, type QueueEntry, type EnumQueueEntry, type DocumentId, type SavedRowItem, VariableEncoding, type BitRangeSource, type AddVariableSignal, InitMessage, WaveformDumpMetadata, ConfigSettingsMessage, EmitEventMessage, type WebviewStateEvent } from '../common/types';
 import type { GetValuesAtTimeArgs, SignalEvent, ValuesAtTimeResult } from '../../packages/vaporview-api/types';
-import { bitRangeString, logScaleFromUnits, parseParamValue, toStringWithCommas } from '../common/functions';
+import { absoluteTime, bitRangeString, formatTime, logScaleFromUnits, parseParamValue, relativeTime, toStringWithCommas } from '../common/functions';
 import { NetlistLinkProvider } from './terminal_links';
 import * as path from 'path';
 import type { VaporviewDocumentCollection, VaporviewDocumentDelegate } from './viewer_provider';
@@ -50,10 +50,11 @@ export type CustomVariableParseResult = {
 };
 
 // TODO: Combine with WebviewStateEvent in /common/types.ts and WebviewState class
+// Marker times are as in the file, see toAbsoluteTime()
 export type WebviewStateSettings = {
   extensionVersion: string | undefined;
-  markerTime?: number | null;
-  altMarkerTime?: number | null;
+  markerTime?: number | string | null;
+  altMarkerTime?: number | string | null;
   displayTimeUnit?: string;
   selectedSignal?: { name: string; msb: number; lsb: number } | null;
   zoomRatio?: number;
@@ -87,12 +88,18 @@ export interface WaveformFileParser {
   // Methods
   loadNetlist(): Promise<void>;
   loadBody(): Promise<void>;
+  tailFile(): Promise<boolean>;
   unload(): Promise<void>;
   dispose(): void;
   getChildren(element: NetlistItem | undefined): Promise<NetlistItem[]>;
   getSignalData(signalIdList: SignalId[]): Promise<void>;
+  freeSignals(signalIdList: SignalId[]): Promise<void>;
+  postTimeTable(): void;
   getEnumData(enumList: EnumQueueEntry[]): Promise<void>;
-  getValuesAtTime(time: number, instancePaths: string[]): Promise<ValuesAtTimeResult[]>;
+  getValuesAtTime(time: number | string, instancePaths: string[]): Promise<ValuesAtTimeResult[]>;
+  // For handlers that load only part of a file, loads the part that covers
+  // a time range as in the file
+  loadTimeRange?(startTime: bigint, endTime: bigint): Promise<void>;
   searchNetlist(searchString: string, scopeId: number): Promise<NetlistSearchResult>
 
   // Callbacks
@@ -218,6 +225,8 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
       themeValid: colorPalette.themeValid,
       autoReload: this.webviewContext.autoReload,
     } as InitMessage);
+    // Signal data can refer to the time table by index
+    this._handler.postTimeTable();
     this._webviewInitialized = true;
   }
 
@@ -304,8 +313,8 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     return {
       extensionVersion: vscode.extensions.getExtension('Lramseyer.vaporview')?.packageJSON.version,
       fileName: this.uri.fsPath,
-      markerTime: this.webviewContext.markerTime,
-      altMarkerTime: this.webviewContext.altMarkerTime,
+      markerTime: this.toAbsoluteMarkerTime(this.webviewContext.markerTime),
+      altMarkerTime: this.toAbsoluteMarkerTime(this.webviewContext.altMarkerTime),
       displayTimeUnit: this.webviewContext.displayTimeUnit,
       selectedSignal: this.getNameFromNetlistId(this.webviewContext.selectedSignal),
       zoomRatio: this.webviewContext.zoomRatio,
@@ -440,8 +449,8 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     const documentSettings: WebviewStateSettings = {
       extensionVersion: settings.extensionVersion,
       displayedSignals: signalListSettings.signalList,
-      markerTime: settings.markerTime,
-      altMarkerTime: settings.altMarkerTime,
+      markerTime: this.fromAbsoluteMarkerTime(settings.markerTime),
+      altMarkerTime: this.fromAbsoluteMarkerTime(settings.altMarkerTime),
       displayTimeUnit: settings.displayTimeUnit,
       selectedSignal: settings.selectedSignal,
       zoomRatio: settings.zoomRatio,
@@ -463,8 +472,18 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     }
   }
 
+  // The context with marker times as in the file, like getSettings(), as
+  // undo and redo apply it with applySettings()
+  private undoState() {
+    return JSON.stringify({
+      ...this.webviewContext,
+      markerTime: this.toAbsoluteMarkerTime(this.webviewContext.markerTime),
+      altMarkerTime: this.toAbsoluteMarkerTime(this.webviewContext.altMarkerTime),
+    });
+  }
+
   captureStateForUndo() {
-    this.undoStack.push(JSON.stringify(this.webviewContext));
+    this.undoStack.push(this.undoState());
     if (this.undoStack.length > 50) {
       this.undoStack.shift();
     }
@@ -474,14 +493,14 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
   undo() {
     if (this.undoStack.length === 0) {return;}
     const lastState = this.undoStack.pop();
-    this.redoStack.push(JSON.stringify(this.webviewContext));
+    this.redoStack.push(this.undoState());
     this.applySettings(JSON.parse(lastState || '{}'), StateChangeType.Undo, false);
   }
   
   redo() {
     if (this.redoStack.length === 0) {return;}
     const lastState = this.redoStack.pop();
-    this.undoStack.push(JSON.stringify(this.webviewContext));
+    this.undoStack.push(this.undoState());
     this.applySettings(JSON.parse(lastState || '{}'), StateChangeType.Redo, false);
   }
 
@@ -495,9 +514,15 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     const watcher = vscode.workspace.createFileSystemWatcher(pattern);
     this.webviewContext.autoReload = settings.get('defaultAutoReload') || false;
     const scheduleReload = () => {
-      if (this.reloadDebounce) { clearTimeout(this.reloadDebounce); }
-      this.reloadDebounce = setTimeout(() => this.handleUpdateFile(), 500);
       this._fileUpdated = true;
+      // A simulator writes continuously, so in live tail mode the timer is not
+      // restarted, otherwise it would never fire
+      if (this.reloadDebounce && this.liveTail) { return; }
+      if (this.reloadDebounce) { clearTimeout(this.reloadDebounce); }
+      this.reloadDebounce = setTimeout(() => {
+        this.reloadDebounce = undefined;
+        this.handleUpdateFile();
+      }, 500);
     };
 
     watcher.onDidChange(scheduleReload, this, this.disposables);
@@ -505,8 +530,13 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     this.fileWatcher = watcher;
   }
 
-  private handleUpdateFile() {
+  private get liveTail(): boolean {
+    return vscode.workspace.getConfiguration('vaporview').get('liveTailVcd') || false;
+  }
+
+  private async handleUpdateFile() {
     this._providerDelegate.logOutputChannel("File changed: " + this.uri.fsPath);
+    if (this.liveTail && this.metadata.timeTableLoaded && await this.tailFile()) { return; }
     if (this.webviewContext.autoReload && this._fileUpdated) {
       this._reloadPending = true;
       if (this.webviewPanel?.active) {
@@ -562,6 +592,34 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     this.webviewPanel.reveal(vscode.ViewColumn.Active);
   }
 
+  // Times from the webview are relative to metadata.timeOrigin. Outside of
+  // the extension they are as in the file, as decimal strings if a number
+  // can't hold them exactly.
+  public toAbsoluteTime(time: number): number | string {
+    return absoluteTime(time, BigInt(this.metadata.timeOrigin));
+  }
+
+  public fromAbsoluteTime(time: number | string): number {
+    return relativeTime(time, BigInt(this.metadata.timeOrigin));
+  }
+
+  private toAbsoluteMarkerTime(time: number | null) {
+    return time === null ? null : this.toAbsoluteTime(time);
+  }
+
+  private fromAbsoluteMarkerTime(time: number | string | null | undefined) {
+    return time === null || time === undefined ? time : this.fromAbsoluteTime(time);
+  }
+
+  // Formats a marker time, unlike formatTime(), which is for time differences
+  public formatMarkerTime(time: number, unit: string) {
+    if (this.metadata.timeOrigin === "0") {return this.formatTime(time, unit);}
+    const offset  = logScaleFromUnits(this.metadata.timeUnit) - logScaleFromUnits(unit);
+    const strings = formatTime(time, BigInt(this.metadata.timeOrigin), this.metadata.timeScale, offset).split('.');
+    strings[0] = strings[0].replace(/\B(?=(\d{3})+(?!\d))/g, ",");
+    return strings.join('.') + ' ' + unit;
+  }
+
   public formatTime(time: number, unit: string) {
     const timescaleOffset = logScaleFromUnits(this.metadata.timeUnit) - logScaleFromUnits(unit);
     const timeScaleOffsetInverse = logScaleFromUnits(unit) - logScaleFromUnits(this.metadata.timeUnit);
@@ -715,6 +773,14 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     }
   }
 
+  // Extends the loaded waveforms with data appended to the file. The webview
+  // keeps its state and loads the displayed signals again.
+  public async tailFile(): Promise<boolean> {
+    if (!await this._handler.tailFile()) { return false; }
+    this._fileUpdated = false;
+    return true;
+  }
+
   public async reload() {
     this.sortNetlist = vscode.workspace.getConfiguration('vaporview').get('sortNetlist') || false;
     await this.unload();
@@ -739,15 +805,36 @@ export class VaporviewDocument extends vscode.Disposable implements vscode.Custo
     return this._handler.getSignalData(signalIdList);
   }
 
+  // Lets the handler drop cached data of signals removed from the viewer
+  public async freeSignals(netlistIdList: NetlistId[]): Promise<void> {
+    const signalIdList = netlistIdList
+      .map((netlistId) => this._netlistIdTable[netlistId]?.signalId)
+      .filter((signalId): signalId is SignalId => signalId !== undefined);
+    if (signalIdList.length === 0) { return; }
+    return this._handler.freeSignals(signalIdList);
+  }
+
   public async getEnumData(enumNameList: EnumQueueEntry[]): Promise<void> {
     return this._handler.getEnumData(enumNameList);
   }
 
   public async getValuesAtTime(e: GetValuesAtTimeArgs): Promise<ValuesAtTimeResult[]> {
-    const time = e.time ?? this.webviewContext.markerTime ?? 0;
+    const time = e.time ?? this.toAbsoluteMarkerTime(this.webviewContext.markerTime) ?? 0;
     return this._handler.getValuesAtTime(time, e.instancePaths);
   }
 
+  // Loads the part of the file in view, for files that are only loaded in part
+  public async loadTimeRange(startTime: number, endTime: number) {
+    if (!this._handler.loadTimeRange) { return; }
+    const start = BigInt(this.toAbsoluteTime(Math.max(0, Math.floor(startTime))));
+    const end   = BigInt(this.toAbsoluteTime(Math.max(0, Math.ceil(endTime))));
+    try {
+      await this._handler.loadTimeRange(start, end);
+    } catch {
+      this._providerDelegate.logOutputChannel("Failed to load the waveforms from " + start + " to " + end);
+    }
+  }
+
   public searchNetlist(searchQuery: string, scopeId: number | undefined): Promise<NetlistSearchResult> {
     return this._handler.searchNetlist(searchQuery, scopeId ?? 0xFFFFFFFF);
   }
@@ -110,6 +110,7 @@ export class FsdbFormatHandler implements WaveformFileParser {
     signalIdCount: 0,
     timeTableCount: 0,
     timeEnd: 0,
+    timeOrigin: "0",
     minTimeStep: 1,
     timeScale: 1,
     timeUnit: "ns",
@@ -328,7 +329,7 @@ export class FsdbFormatHandler implements WaveformFileParser {
     return;
   }
 
-  async getValuesAtTime(time: number, instancePaths: string[]): Promise<ValuesAtTimeResult[]> {
+  async getValuesAtTime(time: number | string, instancePaths: string[]): Promise<ValuesAtTimeResult[]> {
     const instancePath2signalId: Map<string, number> = new Map();
     const signalId2values: Map<number, string | string[]> = new Map();
     for (const instancePath of instancePaths) {
@@ -353,7 +354,7 @@ export class FsdbFormatHandler implements WaveformFileParser {
       const result = await this.callFsdbWorkerTask({
         command: 'getValuesAtTime',
         signalId: signalId,
-        time: time
+        time: Number(time)
       });
       const message = result;
       signalId2values.set(signalId, (message.result as string | string[]) ?? '');
@@ -373,6 +374,14 @@ export class FsdbFormatHandler implements WaveformFileParser {
     return result;
   }
 
+  async tailFile(): Promise<boolean> {
+    return false;
+  }
+
+  async freeSignals(signalIdList: SignalId[]): Promise<void> {}
+
+  postTimeTable(): void {}
+
   async unload(): Promise<void> {
     await this.callFsdbWorkerTask({ command: 'unload' });
     if (this.fsdbWorker !== undefined) {
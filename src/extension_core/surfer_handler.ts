@@ -1,9 +1,9 @@
 import * as vscode from 'vscode';
-import { Worker } from 'worker_threads';
 import type { EnumQueueEntry, SignalId, ValueChangeDataChunk, CompressedValueChangeDataChunk, EnumDataChunk, WaveformDumpMetadata } from '../common/types';
 
 import type { VaporviewDocumentDelegate } from './viewer_provider';
 import { filehandler } from './filehandler';
+import { type DocumentCallbacks, SharedFilehandler, netlistChildOrder } from './wasm_handler';
 import { type NetlistItem, createScope, createVar } from './tree_view';
 import type { WaveformFileParser, NetlistSearchResult } from './document';
 import type { ValuesAtTimeResult } from '../../packages/vaporview-api/types';
@@ -14,15 +14,19 @@ export class SurferFormatHandler implements WaveformFileParser {
   private uri: vscode.Uri;
   private serverUrl: string;
   private bearerToken?: string;
-  private wasmWorker: Worker;
-  private wasmModule: WebAssembly.Module;
+  private sharedFilehandler: SharedFilehandler;
   private wasmApi: filehandler.Exports.Promisified | undefined;
+  private handle: number = 0;
 
   // Top level netlist items
   private netlistTop: NetlistItem[] = [];
   public netlistSearchable: boolean = false;
   private parametersLoaded: boolean = false;
 
+  // As in WasmFormatHandler
+  private timeEnd: bigint = 0n;
+  private timeOrigin: bigint | undefined = undefined;
+
   public postMessageToWebview = (_message: Record<string, unknown>) => {};
   public metadata: WaveformDumpMetadata = {
     timeTableLoaded: false,
@@ -31,6 +35,7 @@ export class SurferFormatHandler implements WaveformFileParser {
     signalIdCount: 0,
     timeTableCount: 0,
     timeEnd: 0,
+    timeOrigin: "0",
     minTimeStep: 1,
     timeScale: 1,
     timeUnit: "ns",
@@ -40,15 +45,13 @@ export class SurferFormatHandler implements WaveformFileParser {
     providerDelegate: VaporviewDocumentDelegate,
     uri: vscode.Uri,
     serverUrl: string,
-    wasmWorker: Worker,
-    wasmModule: WebAssembly.Module,
+    sharedFilehandler: SharedFilehandler,
     bearerToken?: string,
   ) {
     this.providerDelegate = providerDelegate;
     this.uri = uri;
     this.serverUrl = serverUrl;
-    this.wasmWorker = wasmWorker;
-    this.wasmModule = wasmModule;
+    this.sharedFilehandler = sharedFilehandler;
     this.bearerToken = bearerToken;
   }
 
@@ -60,49 +63,57 @@ export class SurferFormatHandler implements WaveformFileParser {
     wasmModule: WebAssembly.Module,
     bearerToken?: string,
   ): Promise<SurferFormatHandler> {
-    const wasmWorker = new Worker(wasmWorkerFile);
-    const handler = new SurferFormatHandler(providerDelegate, uri, serverUrl, wasmWorker, wasmModule, bearerToken);
+    const outputLog = (message: string) => providerDelegate.logOutputChannel(message);
+    const sharedFilehandler = await SharedFilehandler.acquire(wasmWorkerFile, wasmModule, outputLog);
+    const handler = new SurferFormatHandler(providerDelegate, uri, serverUrl, sharedFilehandler, bearerToken);
     await handler.initWasmApi();
     return handler;
   }
 
   private async initWasmApi() {
-    this.wasmApi = await filehandler._.bind(this.service, this.wasmModule, this.wasmWorker);
+    try {
+      if (!this.sharedFilehandler.features.has('remote')) {
+        throw new Error("The filehandler does not support remote waveforms");
+      }
+      this.wasmApi = this.sharedFilehandler.api;
+      this.handle = await this.wasmApi.createdocument();
+    } catch (error) {
+      this.sharedFilehandler.release();
+      throw error;
+    }
+    this.sharedFilehandler.addDocument(this.handle, this.service);
   }
 
-  // WASM service callbacks
-  private readonly service: filehandler.Imports.Promisified = {
-    log: (msg: string) => { console.log(msg); },
-    outputlog: (msg: string) => { this.providerDelegate.logOutputChannel(msg); },
-    fsread: (fd: number, offset: bigint, length: number): Uint8Array => {
-      // Remote server doesn't use direct file reads, return empty buffer
-      return new Uint8Array(Math.max(0, length));
-    },
-    getsize: (fd: number): bigint => {
-      // Remote server doesn't use direct file access
-      return BigInt(0);
-    },
-    setscopetop: (name: string, id: number, tpe: string) => {
+  // WASM service callbacks of this document. The remote server is not read
+  // through fsread().
+  private readonly service: DocumentCallbacks = {
+    setscopetop: (handle: number, name: string, id: number, tpe: string) => {
       const scope = createScope(name, tpe, [], id, -1, this.uri);
       this.netlistTop.push(scope);
     },
-    setvartop: (name: string, id: number, signalid: number, tpe: string, encoding: string, width: number, msb: number, lsb: number, enumtype: string) => {
+    setvartop: (handle: number, name: string, id: number, signalid: number, tpe: string, encoding: string, width: number, msb: number, lsb: number, enumtype: string) => {
       const varItem = createVar(name, "", tpe, encoding, [], id, signalid, width, msb, lsb, enumtype, false /*isFsdb*/, this.uri);
       this.netlistTop.push(varItem);
     },
-    setmetadata: (scopecount: number, varcount: number, timescale: number, timeunit: string) => {
+    setmetadata: (handle: number, scopecount: number, varcount: number, timescale: number, timeunit: string) => {
       this.metadata.scopeCount = scopecount;
       this.metadata.netlistIdCount = varcount;
       this.metadata.timeScale = timescale;
       this.metadata.timeUnit = timeunit;
     },
-    setchunksize: (chunksize: bigint, timeend: bigint, timetablelength: bigint) => {
-      this.metadata.timeEnd = Number(timeend);
+    setchunksize: (handle: number, chunksize: bigint, timeend: bigint, timetablelength: bigint) => {
+      this.timeEnd = timeend;
       this.metadata.timeTableCount = Number(timetablelength);
       this.metadata.minTimeStep = Number(chunksize);
-      this.metadata.timeTableLoaded = true;
+      if (this.timeOrigin === undefined && timeend <= BigInt(Number.MAX_SAFE_INTEGER)) {
+        this.timeOrigin = 0n;
+      }
+      if (this.timeOrigin !== undefined) {
+        this.metadata.timeEnd = Number(timeend - this.timeOrigin);
+        this.metadata.timeTableLoaded = true;
+      }
     },
-    sendtransitiondatachunk: (signalid: number, totalchunks: number, chunknum: number, min: number, max: number, transitionData: string) => {
+    sendtransitiondatachunk: (handle: number, signalid: number, totalchunks: number, chunknum: number, min: number, max: number, transitionData: string) => {
       this.postMessageToWebview({
         command: 'update-waveform-chunk',
         signalId: signalid,
@@ -113,7 +124,7 @@ export class SurferFormatHandler implements WaveformFileParser {
         max: max
       } as ValueChangeDataChunk);
     },
-    sendenumdata: (name: string, totalchunks: number, chunknum: number, data: string) => {
+    sendenumdata: (handle: number, name: string, totalchunks: number, chunknum: number, data: string) => {
       this.postMessageToWebview({
         command: 'update-enum-chunk',
         enumName: name,
@@ -122,7 +133,12 @@ export class SurferFormatHandler implements WaveformFileParser {
         chunkNum: chunknum,
       } as EnumDataChunk);
     },
-    sendcompressedtransitiondata: (signalid: number, signalwidth: number, totalchunks: number, chunknum: number, min: number, max: number, compresseddata: Uint8Array, originalsize: number) => {
+    setprogress: (handle: number, bytesloaded: bigint, totalbytes: bigint) => {},
+    writeindex: (handle: number, data: Uint8Array) => {},
+    signalloaded: (handle: number, requestid: number, signalid: number) => {},
+    sendtimetablechunk: (handle: number, totalchunks: number, chunknum: number, compresseddata: Uint8Array, timetablelength: bigint) => {},
+    sendhierarchychunk: (handle: number, totalchunks: number, chunknum: number, compresseddata: Uint8Array, itemcount: number) => {},
+    sendcompressedtransitiondata: (handle: number, signalid: number, signalwidth: number, totalchunks: number, chunknum: number, min: number, max: number, compresseddata: Uint8Array, originalsize: number) => {
       this.postMessageToWebview({
         command: 'update-waveform-chunk-compressed',
         signalId: signalid,
@@ -146,8 +162,8 @@ export class SurferFormatHandler implements WaveformFileParser {
       cancellable: false
     }, async () => {
       try {
-        await loadRemoteHierarchy(this.serverUrl, this.wasmApi!, this.bearerToken);
-        await loadRemoteTimeTable(this.serverUrl, this.wasmApi!, this.bearerToken);
+        await loadRemoteHierarchy(this.serverUrl, this.wasmApi!, this.handle, this.bearerToken);
+        await loadRemoteTimeTable(this.serverUrl, this.wasmApi!, this.handle, this.bearerToken);
       } catch (error) {
         this.providerDelegate.logOutputChannel("Failed to connect to remote server: " + error);
         throw error;
@@ -166,7 +182,7 @@ export class SurferFormatHandler implements WaveformFileParser {
       cancellable: false
     }, async () => {
       try {
-        await loadRemoteHierarchy(this.serverUrl, this.wasmApi!, this.bearerToken);
+        await loadRemoteHierarchy(this.serverUrl, this.wasmApi!, this.handle, this.bearerToken);
       } catch (error) {
         this.providerDelegate.logOutputChannel("Failed to connect to remote server: " + error);
         throw error;
@@ -178,7 +194,8 @@ export class SurferFormatHandler implements WaveformFileParser {
   async loadBody(): Promise<void> {
 
     try {
-      await loadRemoteTimeTable(this.serverUrl, this.wasmApi!, this.bearerToken);
+      await loadRemoteTimeTable(this.serverUrl, this.wasmApi!, this.handle, this.bearerToken);
+      await this.updateTimeOrigin();
     } catch (error) {
       this.providerDelegate.logOutputChannel("Failed to connect to remote server: " + error);
       throw error;
@@ -187,6 +204,16 @@ export class SurferFormatHandler implements WaveformFileParser {
     this.loadTopLevelParameters();
   }
 
+  // Makes the first time the time origin, see WasmFormatHandler
+  private async updateTimeOrigin() {
+    if (this.timeOrigin !== undefined) { return; }
+    const origin = (await this.wasmApi!.gettimerange(this.handle)).start;
+    this.timeOrigin = origin;
+    this.metadata.timeOrigin = origin.toString();
+    this.metadata.timeEnd = Number(this.timeEnd - origin);
+    this.metadata.timeTableLoaded = true;
+  }
+
   private getParametersInTreeData(treeData: NetlistItem[]): NetlistItem[] {
     const result: NetlistItem[] = [];
     treeData.forEach((item) => {
@@ -206,12 +233,11 @@ export class SurferFormatHandler implements WaveformFileParser {
 
     const parameterItems = this.getParametersInTreeData(this.netlistTop);
     const signalIdList = parameterItems.map((param) => param.signalId);
-    const params = await this.wasmApi!.getparametervalues(new Uint32Array(signalIdList));
-    const parameterValues = JSON.parse(params);
+    const parameterValues = await this.wasmApi!.getparametervalues(this.handle, new Uint32Array(signalIdList));
     parameterItems.forEach((param) => {
-      const paramValue = parameterValues.find((entry: [number, string]) => entry[0] === param.signalId);
+      const paramValue = parameterValues.find((entry) => entry.signalId === param.signalId);
       if (paramValue) {
-        param.setParamAndTooltip(paramValue[1]);
+        param.setParamAndTooltip(paramValue.value);
       }
     });
     this.parametersLoaded = true;
@@ -225,35 +251,29 @@ export class SurferFormatHandler implements WaveformFileParser {
     //let scopePath = "";
     //if (element.scopePath !== "") { scopePath += element.scopePath + "."; }
     //scopePath += element.name;
-    const scopePath = element.scopePath.concat([element.name]); 
-    let itemsRemaining = Infinity;
-    let startIndex = 0;
+    const scopePath = element.scopePath.concat([element.name]);
+    const order = netlistChildOrder();
+    let token: bigint | undefined = undefined;
     const result: NetlistItem[] = [];
 
-    let callLimit = 255;
     const varTable: Record<string, NetlistItem[]> = {};
-    while (itemsRemaining > 0) {
-      const children = await this.wasmApi!.getchildren(element.netlistId, startIndex);
-      const childItems = JSON.parse(children);
-      itemsRemaining = childItems.remainingItems;
-      startIndex += childItems.totalReturned;
-
-      childItems.scopes.forEach((child: { name: string; type: string; id: number }) => {
-        result.push(createScope(child.name, child.type, scopePath, child.id, -1, this.uri));
+    do {
+      const childItems = await this.wasmApi!.getchildrenpage(this.handle, element.netlistId, order, token);
+      token = childItems.nextToken;
+
+      childItems.scopes.forEach((child) => {
+        result.push(createScope(child.name, child.tpe, scopePath, child.id, -1, this.uri));
       });
-      childItems.vars.forEach((child: { name: string; paramValue: string; type: string; encoding: string; netlistId: number; signalId: number; width: number; msb: number; lsb: number; enumType: string }) => {
+      childItems.vars.forEach((child) => {
         const encoding = child.encoding.split('(')[0];
-        const varItem = createVar(child.name, child.paramValue, child.type, encoding, scopePath, child.netlistId, child.signalId, child.width, child.msb, child.lsb, child.enumType, false /*isFsdb*/, this.uri);
+        const varItem = createVar(child.name, child.paramValue ?? '', child.tpe, encoding, scopePath, child.netlistId, child.signalId, child.width, child.msb, child.lsb, child.enumType, false /*isFsdb*/, this.uri);
         if (varTable[child.name] === undefined) {
           varTable[child.name] = [varItem];
         } else {
           varTable[child.name].push(varItem);
         }
       });
-
-      callLimit--;
-      if (callLimit <= 0) { break; }
-    }
+    } while (token !== undefined);
 
     for (const [_key, value] of Object.entries(varTable)) {
       if (value.length === 1) {
@@ -289,7 +309,7 @@ export class SurferFormatHandler implements WaveformFileParser {
 
   async getSignalData(signalIdList: SignalId[]): Promise<void> {
     try {
-      await loadRemoteSignals(this.serverUrl, this.wasmApi!, this.bearerToken, signalIdList);
+      await loadRemoteSignals(this.serverUrl, this.wasmApi!, this.handle, this.bearerToken, signalIdList);
     } catch (error) {
       this.providerDelegate.logOutputChannel("Failed to get signal data from remote server: " + error);
       // Send empty signal data for failed signals
@@ -309,14 +329,15 @@ export class SurferFormatHandler implements WaveformFileParser {
 
   async getEnumData(enumList: EnumQueueEntry[]): Promise<void> {
     const netlistIdList = enumList.map((entry) => entry.netlistId);
-    this.wasmApi!.getenumdata(new Uint32Array(netlistIdList));
+    this.wasmApi!.getenumdata(this.handle, new Uint32Array(netlistIdList));
   }
 
-  async getValuesAtTime(time: number, instancePaths: string[]): Promise<ValuesAtTimeResult[]> {
+  async getValuesAtTime(time: number | string, instancePaths: string[]): Promise<ValuesAtTimeResult[]> {
     if (!this.wasmApi) { return []; }
     try {
-      const result = await this.wasmApi!.getvaluesattime(BigInt(time), instancePaths.join(" "));
-      return JSON.parse(result);
+      const result = await this.wasmApi!.getvaluesattime(this.handle, BigInt(time), instancePaths.join(" "));
+      // Callers get the values as JSON text, as before the result was typed
+      return result.map((entry) => ({ instancePath: entry.instancePath, value: JSON.stringify(entry.values) }));
     } catch (error) {
       this.providerDelegate.logOutputChannel("Failed to get values at time from remote server: " + error);
       return [];
@@ -324,25 +345,43 @@ export class SurferFormatHandler implements WaveformFileParser {
   }
 
   public async searchNetlist(searchString: string, scopeId: number): Promise<NetlistSearchResult> {
-    const resultJson = await this.wasmApi!.searchnetlist(searchString, scopeId);
-    try {
-      return JSON.parse(resultJson) as NetlistSearchResult;
-    } catch {
-      return { totalResults: 0, searchResults: [] };
-    }
+    const result = await this.wasmApi!.searchnetlist(this.handle, searchString, scopeId);
+    return {
+      totalResults: result.totalResults,
+      searchResults: result.searchResults.map((entry) => {
+        const item = entry.item;
+        if (item.tag === 'scope') {
+          return { instancePath: entry.instancePath, type: item.value.tpe, isVar: false, paramValue: '', msb: -1, lsb: -1 };
+        }
+        return { instancePath: entry.instancePath, type: item.value.tpe, isVar: true, paramValue: item.value.paramValue ?? '', msb: item.value.msb, lsb: item.value.lsb };
+      })
+    };
+  }
+
+  async tailFile(): Promise<boolean> {
+    return false;
   }
 
+  async freeSignals(signalIdList: SignalId[]): Promise<void> {}
+
+  postTimeTable(): void {}
+
   async unload(): Promise<void> {
     if (this.wasmApi) {
-      await this.wasmApi!.unload();
+      await this.wasmApi!.unload(this.handle);
     }
     this.parametersLoaded = false;
     this.netlistTop = [];
+    this.timeOrigin = undefined;
+    this.metadata.timeOrigin = "0";
   }
 
-  dispose(): void {
-    this.unload();
-    this.wasmWorker.terminate();
+  // As in WasmFormatHandler
+  async dispose(): Promise<void> {
+    await this.unload();
+    await this.wasmApi?.closedocument(this.handle).catch(() => {});
+    this.sharedFilehandler.removeDocument(this.handle);
+    this.sharedFilehandler.release();
   }
 }
 
@@ -392,28 +431,28 @@ export async function loadRemoteStatus(server: string, wasmApi: filehandler.Expo
     return ret;
 }
 
-export async function loadRemoteHierarchy(server: string, wasmApi: filehandler.Exports | filehandler.Exports.Promisified, bearerToken?: string): Promise<void> {
+export async function loadRemoteHierarchy(server: string, wasmApi: filehandler.Exports | filehandler.Exports.Promisified, handle: number, bearerToken?: string): Promise<void> {
     const hierarchy = await httpFetch(server, 'get_hierarchy', bearerToken);
     const hierarchyBytes = await hierarchy.arrayBuffer();
     const hierarchyUint8Array = new Uint8Array(hierarchyBytes);
     
     await sendDataInChunks(hierarchyUint8Array, (chunk, chunkIndex, totalChunks) => {
-        wasmApi.loadremotechunk(ChunkType.Hierarchy, chunk, chunkIndex, totalChunks);
+        wasmApi.loadremotechunk(handle, ChunkType.Hierarchy, chunk, chunkIndex, totalChunks);
     });
     
 }
 
-export async function loadRemoteTimeTable(server: string, wasmApi: filehandler.Exports | filehandler.Exports.Promisified, bearerToken?: string): Promise<void> {
+export async function loadRemoteTimeTable(server: string, wasmApi: filehandler.Exports | filehandler.Exports.Promisified, handle: number, bearerToken?: string): Promise<void> {
     const timeTable = await httpFetch(server, 'get_time_table', bearerToken);
     const timeTableBytes = await timeTable.arrayBuffer();
     const timeTableUint8Array = new Uint8Array(timeTableBytes);
     
     await sendDataInChunks(timeTableUint8Array, (chunk, chunkIndex, totalChunks) => {
-        wasmApi.loadremotechunk(ChunkType.TimeTable, chunk, chunkIndex, totalChunks);
+        wasmApi.loadremotechunk(handle, ChunkType.TimeTable, chunk, chunkIndex, totalChunks);
     });
 }
 
-export async function loadRemoteSignals(server: string, wasmApi: filehandler.Exports | filehandler.Exports.Promisified, bearerToken?: string, signalIds?: number[]): Promise<void> {
+export async function loadRemoteSignals(server: string, wasmApi: filehandler.Exports | filehandler.Exports.Promisified, handle: number, bearerToken?: string, signalIds?: number[]): Promise<void> {
     let path = 'get_signals';
     if (signalIds && signalIds.length > 0) {
         path += '/' + signalIds.join('/');
@@ -423,6 +462,6 @@ export async function loadRemoteSignals(server: string, wasmApi: filehandler.Exp
     const signalsUint8Array = new Uint8Array(signalsBytes);
     
     await sendDataInChunks(signalsUint8Array, (chunk, chunkIndex, totalChunks) => {
-        wasmApi.loadremotechunk(ChunkType.Signals, chunk, chunkIndex, totalChunks);
+        wasmApi.loadremotechunk(handle, ChunkType.Signals, chunk, chunkIndex, totalChunks);
     });
 }
\ No newline at end of file
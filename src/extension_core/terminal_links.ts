@@ -67,7 +67,8 @@ export class TimestampLinkProvider implements vscode.TerminalLinkProvider {
 
     switch (link.type) {
       case 'uvm-timestamp': {
-        const time = parseInt([...link.data.matchAll(this.uvmTimestampRegex)][0][1]);
+        // Kept as a string, so times past 2^53 stay exact
+        const time = [...link.data.matchAll(this.uvmTimestampRegex)][0][1];
         this.viewerProvider.log.appendLine('UVM Timestamp link clicked: ' + time);
         this.viewerProvider.setMarkerAtTime(time, 0);
         break;
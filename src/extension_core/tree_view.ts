@@ -417,7 +417,7 @@ export class VaporviewStatusBar {
 
     const timeUnit = event.displayTimeUnit || w.displayTimeUnit;
     if (w.markerTime || w.markerTime === 0) {
-      this.markerTimeStatusBarItem.text = 'Time: ' + document.formatTime(w.markerTime, timeUnit);
+      this.markerTimeStatusBarItem.text = 'Time: ' + document.formatMarkerTime(w.markerTime, timeUnit);
       if (w.altMarkerTime !== null && w.markerTime !== null) {
         const deltaT = w.markerTime - w.altMarkerTime;
         this.deltaTimeStatusBarItem.text = 'Δt: ' + document.formatTime(deltaT, timeUnit);
@@ -360,7 +360,8 @@ export class WaveformViewerProvider implements vscode.CustomEditorProvider<Vapor
       // Create Surfer handler
       handler = await SurferFormatHandler.create(delegate, uri, connectionInfo.serverUrl, this.wasmWorkerFile, this.wasmModule, connectionInfo.bearerToken);
     } else {
-      const fileType = uri.fsPath.split('.').pop()?.toLocaleLowerCase() || '';
+      // Compressed files (.vcd.gz, .vcd.zst, .vcd.xz) are decompressed by the filehandler
+      const fileType = uri.fsPath.replace(/\.(gz|zst|xz)$/i, '').split('.').pop()?.toLocaleLowerCase() || '';
       if (fileType === 'fsdb') {
         handler = new FsdbFormatHandler(delegate, uri, async () => null);
       } else {
@@ -400,6 +401,7 @@ export class WaveformViewerProvider implements vscode.CustomEditorProvider<Vapor
         case 'contextUpdate':       {this.handleUpdateWebviewContext(document, e); break;}
         case 'emitEvent':           {this.emitEvent(e); break;}
         case 'fetchDataFromFile':   {document.fetchData(e.requestList); break;}
+        case 'viewportRange':       {document.loadTimeRange(e.startTime, e.endTime); break;}
         case 'close-webview':       {webviewPanel.dispose(); break;}
         case 'handleDrop':          {this.handleWebviewDrop(e); break;}
         case 'focus':               {webviewPanel.reveal(); break;}
@@ -710,7 +712,7 @@ export class WaveformViewerProvider implements vscode.CustomEditorProvider<Vapor
     }
   }
 
-  setMarkerAtTimeWithUnits(time: number, unit: string, altMarker: number) {
+  setMarkerAtTimeWithUnits(time: number | string, unit: string, altMarker: number) {
 
     if (!this.lastActiveDocument) {return;}
   
@@ -721,16 +723,21 @@ export class WaveformViewerProvider implements vscode.CustomEditorProvider<Vapor
     if (!timeScale || !timeUnit) {return;}
 
     const scaleFactor = scaleFromUnits(unit) / (timeUnit * timeScale);
-
-    this.setMarkerAtTime(Math.round(time * scaleFactor), altMarker);
+    if (scaleFactor === 1) {
+      this.setMarkerAtTime(time, altMarker);
+    } else {
+      this.setMarkerAtTime(Math.round(Number(time) * scaleFactor), altMarker);
+    }
   }
 
-  setMarkerAtTime(time: number, altMarker: number) {
+  // Sets a marker at a time as in the file
+  setMarkerAtTime(absoluteTime: number | string, altMarker: number) {
 
     if (!this.lastActiveWebview) {return;}
     if (!this.lastActiveDocument) {return;}
 
     // Check to see that the time is not out of bounds
+    const time    = this.lastActiveDocument.fromAbsoluteTime(absoluteTime);
     const timeEnd = this.lastActiveDocument.metadata.timeEnd;
     if (time < 0 || time > timeEnd) {return;}
 
@@ -794,7 +801,12 @@ export class WaveformViewerProvider implements vscode.CustomEditorProvider<Vapor
       case 'markerSet':      {WaveformViewerProvider.markerSetEventEmitter.fire(e.eventData as MarkerSetEvent); break;}
       case 'signalSelect':   {WaveformViewerProvider.signalSelectEventEmitter.fire(e.eventData as SignalEvent); break;}
       case 'addVariable':    {WaveformViewerProvider.addVariableEventEmitter.fire(e.eventData as SignalEvent); break;}
-      case 'removeVariable': {WaveformViewerProvider.removeVariableEventEmitter.fire(e.eventData as SignalEvent); break;}
+      case 'removeVariable': {
+        const signalEvent = e.eventData as SignalEvent;
+        WaveformViewerProvider.removeVariableEventEmitter.fire(signalEvent);
+        this.getDocumentFromUri(signalEvent.uri)?.freeSignals(signalEvent.netlistId);
+        break;
+      }
       case 'valueLink':      {WaveformViewerProvider.valueLinkEventEmitter.fire(e.eventData as ValueLinkEvent); break;}
     }
   }
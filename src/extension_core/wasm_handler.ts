@@ -2,8 +2,9 @@ import * as vscode from 'vscode';
 import { promisify } from 'util';
 import { Worker } from 'worker_threads';
 import * as fs from 'fs';
+import * as LZ4 from 'lz4js';
 
-import type { EnumQueueEntry, SignalId, ValueChangeDataChunk, CompressedValueChangeDataChunk, EnumDataChunk, WaveformDumpMetadata } from '../common/types';
+import type { EnumQueueEntry, SignalId, ValueChangeDataChunk, CompressedValueChangeDataChunk, EnumDataChunk, TimeTableChunk, WaveformDumpMetadata } from '../common/types';
 import type { VaporviewDocumentDelegate } from './viewer_provider';
 import { filehandler } from './filehandler';
 import { type NetlistItem, createScope, createVar } from './tree_view';
@@ -23,48 +24,60 @@ interface fsWrapper {
   close: (fd: number) => void;
 }
 
-const nodeFsWrapper: fsWrapper = {
-  type: 'nodeFs',
-  loadStatic: false,
-  fd: 0,
-  fileSize: 0,
-  bufferSize: 60 * 1024,
-  loadFile: async (uri: vscode.Uri, fileType: string) => {
-    const open                 = promisify(fs.open);
-    const stats                = fs.statSync(uri.fsPath);
-    nodeFsWrapper.fd           = await open(uri.fsPath, 'r');
-    nodeFsWrapper.fileSize     = stats.size;
-    const fstMaxStaticLoadSize = vscode.workspace.getConfiguration('vaporview').get('fstMaxStaticLoadSize');
-    const maxStaticSize        = Number(fstMaxStaticLoadSize) * 1048576;
-    nodeFsWrapper.loadStatic   = (stats.size < maxStaticSize);
-
-    // For VCD files, we stream the file, so we want to use a larger buffer size
-    // For FST files, we want to use Rust's default buffer size of 8192 bytes,
-    // but we don't care about buffer size if we statically load the file
-    if (fileType === 'fst' && nodeFsWrapper.loadStatic === false) {
-      nodeFsWrapper.bufferSize = 8192;
-    }
-  },
-  readSlice: fs.readSync,
-  close: promisify(fs.close)
+// Every document gets its own wrapper, since documents share the filehandler
+// and its file reads are routed by fd
+const createNodeFsWrapper = (): fsWrapper => {
+  const wrapper: fsWrapper = {
+    type: 'nodeFs',
+    loadStatic: false,
+    fd: 0,
+    fileSize: 0,
+    bufferSize: 60 * 1024,
+    loadFile: async (uri: vscode.Uri, fileType: string) => {
+      const open                 = promisify(fs.open);
+      const stats                = fs.statSync(uri.fsPath);
+      wrapper.fd                 = await open(uri.fsPath, 'r');
+      wrapper.fileSize           = stats.size;
+      const fstMaxStaticLoadSize = vscode.workspace.getConfiguration('vaporview').get('fstMaxStaticLoadSize');
+      const maxStaticSize        = Number(fstMaxStaticLoadSize) * 1048576;
+      wrapper.loadStatic         = (stats.size < maxStaticSize);
+
+      // For VCD files, we stream the file, so we want to use a larger buffer size
+      // For FST files, we want to use Rust's default buffer size of 8192 bytes,
+      // but we don't care about buffer size if we statically load the file
+      if (fileType === 'fst' && wrapper.loadStatic === false) {
+        wrapper.bufferSize = 8192;
+      }
+    },
+    readSlice: fs.readSync,
+    close: promisify(fs.close)
+  };
+  return wrapper;
 };
 
-const workspaceFsWrapper: fsWrapper = {
-  type: "workspace",
-  loadStatic: true,
-  fd: 0,
-  fileSize: 0,
-  bufferSize: 60 * 1024,
-  loadFile: async (uri: vscode.Uri, fileType: string) => {
-    const stats                 = await vscode.workspace.fs.stat(uri);
-    workspaceFsWrapper.fileData = await vscode.workspace.fs.readFile(uri);
-    workspaceFsWrapper.fileSize = stats.size;
-  },
-  readSlice: (fd: number, buffer: Uint8Array, offset: number, length: number, position: number) => {
-    buffer.set(workspaceFsWrapper.fileData!.subarray(position, position + length), offset);
-    return length;
-  },
-  close: (fd: number) => {}
+// Files read through the workspace API are held in memory and have no file
+// descriptor, so they get numbers well above the ones the OS hands out
+let nextWorkspaceFd = 0x40000000;
+
+const createWorkspaceFsWrapper = (): fsWrapper => {
+  const wrapper: fsWrapper = {
+    type: "workspace",
+    loadStatic: true,
+    fd: nextWorkspaceFd++,
+    fileSize: 0,
+    bufferSize: 60 * 1024,
+    loadFile: async (uri: vscode.Uri, fileType: string) => {
+      const stats      = await vscode.workspace.fs.stat(uri);
+      wrapper.fileData = await vscode.workspace.fs.readFile(uri);
+      wrapper.fileSize = stats.size;
+    },
+    readSlice: (fd: number, buffer: Uint8Array, offset: number, length: number, position: number) => {
+      buffer.set(wrapper.fileData!.subarray(position, position + length), offset);
+      return length;
+    },
+    close: (fd: number) => {}
+  };
+  return wrapper;
 };
 
 // Adapted from the VScode hex editor extension source
@@ -73,28 +86,307 @@ export const getFsWrapper = async (uri: vscode.Uri): Promise<fsWrapper> => {
     try {
       const fileStats = await fs.promises.stat(uri.fsPath);
       if (fileStats.isFile()) {
-        return nodeFsWrapper;
+        return createNodeFsWrapper();
       }
     } catch { /* probably not node.js, or file does not exist */  }
   }
-  return workspaceFsWrapper;
+  return createWorkspaceFsWrapper();
 };
 
+// Bytes of the waveform body read per readbodystep() call
+const readBodyStepSize = 16 * 1048576;
+// Bytes between checkpoints when a VCD file is loaded by region
+const regionCheckpointInterval = 16 * 1048576;
+// Smallest file that gets a sidecar index, smaller ones parse about as fast
+// as the index loads
+const indexMinFileSize = 64 * 1048576;
+// Signals loaded per loadqueuedsignals() call. Requests queued in between
+// calls can be served before the rest of the queue.
+const signalBatchSize = 64;
+
+// Version of the vaporview:fileparser interface in wit/filehandler.wit that
+// this host is written against
+export const filehandlerInterfaceVersion = '2.0.0';
+
+// Checks that the filehandler was built from a compatible version of the
+// interface: the same major version, and at least the minor version of the
+// host. Returns the optional features it supports.
+export async function checkCapabilities(wasmApi: filehandler.Exports.Promisified): Promise<Set<string>> {
+  const capabilities = await wasmApi.getcapabilities();
+  const [major, minor] = capabilities.interfaceVersion.split('.').map(Number);
+  const [hostMajor, hostMinor] = filehandlerInterfaceVersion.split('.').map(Number);
+  if (major !== hostMajor || minor < hostMinor) {
+    throw new Error("Filehandler interface version " + capabilities.interfaceVersion + " does not match version " + filehandlerInterfaceVersion + " of the extension");
+  }
+  return new Set(capabilities.features);
+}
+
+// #region SharedFilehandler
+// Filehandler callbacks that belong to one document, they carry its handle
+export type DocumentCallbacks = Omit<filehandler.Imports.Promisified, 'log' | 'outputlog' | 'fsread' | 'getsize'>;
+
+// A file that the filehandler reads with fsread()
+interface SharedFile {
+  readSlice: fsWrapper['readSlice'];
+  fileSize: number;
+}
+
+// All open files share one filehandler worker and wasm instance, and each file
+// is a document in it. Callbacks are routed to the handler of a document by
+// its handle, and file reads to the file that the fd belongs to. The worker
+// is stopped when the last handler releases it, which frees its memory.
+export class SharedFilehandler {
+  private static instances = new Map<WebAssembly.Module, SharedFilehandler>();
+
+  private worker: Worker;
+  private module: WebAssembly.Module;
+  private ready: Promise<void>;
+  private references: number = 0;
+  private documents = new Map<number, DocumentCallbacks>();
+  private files = new Map<number, SharedFile>();
+  private fileBuffer: Uint8Array = new Uint8Array(65536);
+  private outputLog: (message: string) => void;
+  public api!: filehandler.Exports.Promisified;
+  // Optional features of the filehandler, see checkCapabilities()
+  public features = new Set<string>();
+
+  private constructor(wasmWorkerFile: string, wasmModule: WebAssembly.Module, outputLog: (message: string) => void) {
+    this.worker    = new Worker(wasmWorkerFile);
+    this.module    = wasmModule;
+    this.outputLog = outputLog;
+    this.ready     = this.init();
+  }
+
+  private async init() {
+    this.api      = await filehandler._.bind(this.service, this.module, this.worker);
+    this.features = await checkCapabilities(this.api);
+  }
+
+  // Returns the filehandler for the module, starting it on first use. Every
+  // call must be paired with a release().
+  static async acquire(wasmWorkerFile: string, wasmModule: WebAssembly.Module, outputLog: (message: string) => void): Promise<SharedFilehandler> {
+    let instance = SharedFilehandler.instances.get(wasmModule);
+    if (!instance) {
+      instance = new SharedFilehandler(wasmWorkerFile, wasmModule, outputLog);
+      SharedFilehandler.instances.set(wasmModule, instance);
+    }
+    instance.references++;
+    try {
+      await instance.ready;
+    } catch (error) {
+      instance.release();
+      throw error;
+    }
+    return instance;
+  }
+
+  release() {
+    this.references--;
+    if (this.references > 0) { return; }
+    if (SharedFilehandler.instances.get(this.module) === this) {
+      SharedFilehandler.instances.delete(this.module);
+    }
+    this.worker.terminate();
+  }
+
+  addDocument(handle: number, callbacks: DocumentCallbacks) { this.documents.set(handle, callbacks); }
+  removeDocument(handle: number) { this.documents.delete(handle); }
+  addFile(fd: number, file: SharedFile) { this.files.set(fd, file); }
+  removeFile(fd: number) { this.files.delete(fd); }
+
+  private readonly service: filehandler.Imports.Promisified = {
+    log: (msg: string) => { console.log(msg); },
+    outputlog: (msg: string) => { this.outputLog(msg); },
+    fsread: (fd: number, offset: bigint, length: number): Uint8Array => {
+      const file = this.files.get(fd);
+      if (!file) { return new Uint8Array(0); }
+      // Read-ahead in the filehandler can ask for several pages at once
+      if (length > this.fileBuffer.length) { this.fileBuffer = new Uint8Array(length); }
+      const bytesRead = file.readSlice(fd, this.fileBuffer, 0, length, Number(offset));
+      return this.fileBuffer.subarray(0, bytesRead);
+    },
+    getsize: (fd: number): bigint => {
+      return BigInt(this.files.get(fd)?.fileSize ?? 0);
+    },
+    setscopetop: (handle, ...args) => this.documents.get(handle)?.setscopetop(handle, ...args),
+    setvartop: (handle, ...args) => this.documents.get(handle)?.setvartop(handle, ...args),
+    setmetadata: (handle, ...args) => this.documents.get(handle)?.setmetadata(handle, ...args),
+    setchunksize: (handle, ...args) => this.documents.get(handle)?.setchunksize(handle, ...args),
+    sendtransitiondatachunk: (handle, ...args) => this.documents.get(handle)?.sendtransitiondatachunk(handle, ...args),
+    sendenumdata: (handle, ...args) => this.documents.get(handle)?.sendenumdata(handle, ...args),
+    sendcompressedtransitiondata: (handle, ...args) => this.documents.get(handle)?.sendcompressedtransitiondata(handle, ...args),
+    sendtimetablechunk: (handle, ...args) => this.documents.get(handle)?.sendtimetablechunk(handle, ...args),
+    sendhierarchychunk: (handle, ...args) => this.documents.get(handle)?.sendhierarchychunk(handle, ...args),
+    setprogress: (handle, ...args) => this.documents.get(handle)?.setprogress(handle, ...args),
+    writeindex: (handle, ...args) => this.documents.get(handle)?.writeindex(handle, ...args),
+    signalloaded: (handle, ...args) => this.documents.get(handle)?.signalloaded(handle, ...args),
+  };
+}
+
+// Order of the children of a scope in the netlist view, see getchildrenpage()
+export function netlistChildOrder(): string {
+  return vscode.workspace.getConfiguration('vaporview').get<string>('netlistChildOrder') ?? 'file';
+}
+
+// Item kinds of the hierarchy sent by sendhierarchychunk(), see hierarchy_encoding.rs
+const HIERARCHY_SCOPE = 0;
+
+// Enum values as [bits, name] pairs, by enum type name
+type EnumTables = Map<string, [string, string][]>;
+
+// Decodes the hierarchy sent by sendhierarchychunk() after decompression into
+// the top level netlist items, with the children of every scope filled in,
+// and the enum types of the vars
+function decodeHierarchy(data: Uint8Array, itemCount: number, uri: vscode.Uri): { netlistTop: NetlistItem[], enumTables: EnumTables } {
+  const textDecoder = new TextDecoder();
+  const strings: string[] = [];
+  // Scopes with the scope path of their children, by id
+  const scopes = new Map<number, [NetlistItem, string[]]>();
+  const result: NetlistItem[] = [];
+  let offset = 0;
+
+  const nextVarint = (): number => {
+    let value = 0;
+    let scale = 1;
+    let byte  = 0;
+    do {
+      byte   = data[offset++];
+      value += (byte & 0x7f) * scale;
+      scale *= 128;
+    } while (byte & 0x80);
+    return value;
+  };
+  const nextSignedVarint = (): number => {
+    let value = 0;
+    let scale = 1;
+    let byte  = 0;
+    do {
+      byte   = data[offset++];
+      value += (byte & 0x7f) * scale;
+      scale *= 128;
+    } while (byte & 0x80);
+    return (byte & 0x40) ? value - scale : value;
+  };
+  const nextString = (): string => {
+    const length = nextVarint();
+    const value  = textDecoder.decode(data.subarray(offset, offset + length));
+    offset += length;
+    return value;
+  };
+  const nextTableString = (): string => {
+    const index = nextVarint();
+    if (index === strings.length) { strings.push(nextString()); }
+    return strings[index];
+  };
+
+  for (let i = 0; i < itemCount; i++) {
+    const kind      = data[offset++];
+    const parentId  = nextVarint();
+    const parent    = scopes.get(parentId - 1);
+    const scopePath = parent ? parent[1] : [];
+    let item: NetlistItem;
+    if (kind === HIERARCHY_SCOPE) {
+      const id   = nextVarint();
+      const name = nextString();
+      item = createScope(name, nextTableString(), scopePath, id, -1, uri);
+      scopes.set(id, [item, scopePath.concat([item.name])]);
+    } else {
+      const netlistId = nextVarint();
+      const signalId  = nextVarint();
+      const name      = nextString();
+      const type      = nextTableString();
+      const encoding  = nextTableString();
+      const width     = nextVarint();
+      const msb       = nextSignedVarint();
+      const lsb       = nextSignedVarint();
+      const enumType  = nextTableString();
+      item = createVar(name, "", type, encoding.split('(')[0], scopePath, netlistId, signalId, width, msb, lsb, enumType, false /*isFsdb*/, uri);
+    }
+    if (parent) { parent[0].children.push(item); }
+    else        { result.push(item); }
+  }
+
+  const enumTables: EnumTables = new Map();
+  const enumCount = nextVarint();
+  for (let i = 0; i < enumCount; i++) {
+    const name       = nextTableString();
+    const valueCount = nextVarint();
+    const values: [string, string][] = [];
+    for (let j = 0; j < valueCount; j++) {
+      const bits = nextString();
+      values.push([bits, nextString()]);
+    }
+    enumTables.set(name, values);
+  }
+  return { netlistTop: result, enumTables: enumTables };
+}
+
+// Times in transition JSON are numbers, or decimal strings when they are too
+// large for a number to hold exactly. Both convert to bigint without loss.
+function parseTransitions(json: string): [bigint, string][] {
+  return (JSON.parse(json) as [number | string, string][]).map(([time, value]) => [BigInt(time), value]);
+}
+
+// One bucket of getsignalsummary(). Values are null before the first value
+// change, min and max are only set for real signals.
+export interface SignalSummaryBucket {
+  changes: number;
+  first: string | null;
+  last: string | null;
+  unknown?: boolean;
+  min?: number | null;
+  max?: number | null;
+}
+
 // #region WasmFormatHandler
 export class WasmFormatHandler implements WaveformFileParser {
   private providerDelegate: VaporviewDocumentDelegate;
   private uri: vscode.Uri;
   private fileType: string;
   private fileReader: fsWrapper;
-  private wasmWorker: Worker;
-  private wasmModule: WebAssembly.Module;
+  private sharedFilehandler: SharedFilehandler;
   private wasmApi: filehandler.Exports.Promisified | undefined;
-  private fileBuffer: Uint8Array = new Uint8Array(65536);
+  // Optional features of the filehandler, see checkCapabilities()
+  private features = new Set<string>();
+  private handle: number = 0;
+  private reportProgress: ((percent: number) => void) | undefined;
+
+  // Sidecar index, see useIndexCache
+  private fingerprint: bigint | undefined;
+  private indexFd: number | undefined;
+  private indexWriteFd: number | undefined;
+  private loadedFromIndex: boolean = false;
+
+  // Queued signal requests, by request id
+  private nextRequestId: number = 1;
+  private signalRequests = new Map<number, { remaining: number; start: number }>();
+  private signalQueueActive: boolean = false;
+
+  // Time table sent by sendtimetable(), see useTimeIndices
+  private timeTableChunks: TimeTableChunk[] = [];
+
+  // Hierarchy sent by sendhierarchy(), see loadFullNetlist()
+  private hierarchyChunks: Uint8Array[] = [];
+  private hierarchyItemCount: number = 0;
 
   private parametersLoaded: boolean = false;
 
+  // Last time sent by setchunksize(), and the time origin of the document,
+  // see WaveformDumpMetadata.timeOrigin. The origin is chosen once per load.
+  private timeEnd: bigint = 0n;
+  private timeOrigin: bigint | undefined = undefined;
+
+  // Checkpoint times of a VCD file loaded by region, see loadFirstRegion(),
+  // and the loaded region. Regions are loaded one after another.
+  private regionTimes: bigint[] = [];
+  private loadedRegion: { start: bigint; end: bigint } | undefined = undefined;
+  private regionQueue: Promise<void> = Promise.resolve();
+
   // Top level netlist items
   private netlistTop: NetlistItem[] = [];
+  // Enum types sent with the full netlist, which getEnumData() doesn't have
+  // to ask the filehandler for
+  private enumTables: EnumTables = new Map();
   public netlistSearchable: boolean = false;
 
   public postMessageToWebview = (_message: Record<string, unknown>) => {};
@@ -105,6 +397,7 @@ export class WasmFormatHandler implements WaveformFileParser {
     signalIdCount: 0,
     timeTableCount: 0,
     timeEnd: 0,
+    timeOrigin: "0",
     minTimeStep: 1,
     timeScale: 1,
     timeUnit: "ns",
@@ -115,15 +408,13 @@ export class WasmFormatHandler implements WaveformFileParser {
     uri: vscode.Uri,
     fileType: string,
     fileReader: fsWrapper,
-    wasmWorker: Worker,
-    wasmModule: WebAssembly.Module,
+    sharedFilehandler: SharedFilehandler,
   ) {
     this.providerDelegate = providerDelegate;
     this.uri = uri;
     this.fileType = fileType;
     this.fileReader = fileReader;
-    this.wasmWorker = wasmWorker;
-    this.wasmModule = wasmModule;
+    this.sharedFilehandler = sharedFilehandler;
   }
 
   static async create(
@@ -134,48 +425,59 @@ export class WasmFormatHandler implements WaveformFileParser {
     wasmModule: WebAssembly.Module,
   ): Promise<WasmFormatHandler> {
     const fsWrapper = await getFsWrapper(uri);
-    const wasmWorker = new Worker(wasmWorkerFile);
-    const handler = new WasmFormatHandler(providerDelegate, uri, fileType, fsWrapper, wasmWorker, wasmModule);
+    const outputLog = (message: string) => providerDelegate.logOutputChannel(message);
+    const sharedFilehandler = await SharedFilehandler.acquire(wasmWorkerFile, wasmModule, outputLog);
+    const handler = new WasmFormatHandler(providerDelegate, uri, fileType, fsWrapper, sharedFilehandler);
     await handler.initWasmApi();
     return handler;
   }
 
   private async initWasmApi() {
-    this.wasmApi = await filehandler._.bind(this.service, this.wasmModule, this.wasmWorker);
+    try {
+      this.wasmApi = this.sharedFilehandler.api;
+      this.features = this.sharedFilehandler.features;
+      this.handle = await this.wasmApi.createdocument();
+    } catch (error) {
+      this.sharedFilehandler.release();
+      throw error;
+    }
+    this.sharedFilehandler.addDocument(this.handle, this.service);
   }
 
-  // WASM service callbacks
-  private readonly service: filehandler.Imports.Promisified = {
-    log: (msg: string) => { console.log(msg); },
-    outputlog: (msg: string) => { this.providerDelegate.logOutputChannel(msg); },
-    fsread: (fd: number, offset: bigint, length: number): Uint8Array => {
-      const bytesRead = this.fileReader.readSlice(fd, this.fileBuffer, 0, length, Number(offset));
-      return this.fileBuffer.subarray(0, bytesRead);
-    },
-    getsize: (fd: number): bigint => {
-      return BigInt(this.fileReader.fileSize);
-    },
-    setscopetop: (name: string, id: number, tpe: string) => {
+  // WASM service callbacks of this document
+  private readonly service: DocumentCallbacks = {
+    setscopetop: (handle: number, name: string, id: number, tpe: string) => {
       const scope = createScope(name, tpe, [], id, -1, this.uri);
       this.netlistTop.push(scope);
     },
-    setvartop: (name: string, id: number, signalid: number, tpe: string, encoding: string, width: number, msb: number, lsb: number, enumtype: string) => {
+    setvartop: (handle: number, name: string, id: number, signalid: number, tpe: string, encoding: string, width: number, msb: number, lsb: number, enumtype: string) => {
       const varItem = createVar(name, "", tpe, encoding, [], id, signalid, width, msb, lsb, enumtype, false /*isFsdb*/, this.uri);
       this.netlistTop.push(varItem);
     },
-    setmetadata: (scopecount: number, varcount: number, timescale: number, timeunit: string) => {
+    setmetadata: (handle: number, scopecount: number, varcount: number, timescale: number, timeunit: string) => {
       this.metadata.scopeCount = scopecount;
       this.metadata.netlistIdCount = varcount;
       this.metadata.timeScale = timescale;
       this.metadata.timeUnit = timeunit;
     },
-    setchunksize: (chunksize: bigint, timeend: bigint, timetablelength: bigint) => {
-      this.metadata.timeEnd = Number(timeend);
+    setchunksize: (handle: number, chunksize: bigint, timeend: bigint, timetablelength: bigint) => {
+      // A file loaded by region can be scrolled to its last time
+      const lastTime = this.regionTimes[this.regionTimes.length - 1] ?? 0n;
+      if (lastTime > timeend) { timeend = lastTime; }
+      this.timeEnd = timeend;
       this.metadata.timeTableCount = Number(timetablelength);
       this.metadata.minTimeStep = Number(chunksize);
-      this.metadata.timeTableLoaded = true;
+      // Files whose times all fit in a number don't need an origin. Others
+      // wait for updateTimeOrigin(), which can't be called from a callback.
+      if (this.timeOrigin === undefined && timeend <= BigInt(Number.MAX_SAFE_INTEGER)) {
+        this.timeOrigin = 0n;
+      }
+      if (this.timeOrigin !== undefined) {
+        this.metadata.timeEnd = Number(timeend - this.timeOrigin);
+        this.metadata.timeTableLoaded = true;
+      }
     },
-    sendtransitiondatachunk: (signalid: number, totalchunks: number, chunknum: number, min: number, max: number, transitionData: string) => {
+    sendtransitiondatachunk: (handle: number, signalid: number, totalchunks: number, chunknum: number, min: number, max: number, transitionData: string) => {
       this.postMessageToWebview({
         command: 'update-waveform-chunk',
         signalId: signalid,
@@ -186,7 +488,7 @@ export class WasmFormatHandler implements WaveformFileParser {
         max: max
       } as ValueChangeDataChunk);
     },
-    sendenumdata: (name: string, totalchunks: number, chunknum: number, data: string) => {
+    sendenumdata: (handle: number, name: string, totalchunks: number, chunknum: number, data: string) => {
       this.postMessageToWebview({
         command: 'update-enum-chunk',
         enumName: name,
@@ -195,7 +497,38 @@ export class WasmFormatHandler implements WaveformFileParser {
         chunkNum: chunknum,
       } as EnumDataChunk);
     },
-    sendcompressedtransitiondata: (signalid: number, signalwidth: number, totalchunks: number, chunknum: number, min: number, max: number, compresseddata: Uint8Array, originalsize: number) => {
+    setprogress: (handle: number, bytesloaded: bigint, totalbytes: bigint) => {
+      if (totalbytes === 0n) { return; }
+      this.reportProgress?.(Number((bytesloaded * 100n) / totalbytes));
+    },
+    signalloaded: (handle: number, requestid: number, signalid: number) => {
+      const request = this.signalRequests.get(requestid);
+      if (!request) { return; }
+      request.remaining--;
+      if (request.remaining > 0) { return; }
+      this.signalRequests.delete(requestid);
+      console.log("Signal request " + requestid + " loaded in " + (Date.now() - request.start) / 1000 + " seconds");
+    },
+    sendtimetablechunk: (handle: number, totalchunks: number, chunknum: number, compresseddata: Uint8Array, timetablelength: bigint) => {
+      if (chunknum === 0) { this.timeTableChunks = []; }
+      this.timeTableChunks.push({
+        command: 'update-time-table-chunk',
+        compressedDataChunk: Array.from(compresseddata),
+        totalChunks: totalchunks,
+        chunkNum: chunknum,
+        timeTableLength: Number(timetablelength),
+      });
+    },
+    sendhierarchychunk: (handle: number, totalchunks: number, chunknum: number, compresseddata: Uint8Array, itemcount: number) => {
+      if (chunknum === 0) { this.hierarchyChunks = []; }
+      this.hierarchyChunks.push(compresseddata.slice());
+      if (totalchunks > 0) { this.hierarchyItemCount = itemcount; }
+    },
+    writeindex: (handle: number, data: Uint8Array) => {
+      if (this.indexWriteFd === undefined) { return; }
+      fs.writeSync(this.indexWriteFd, data);
+    },
+    sendcompressedtransitiondata: (handle: number, signalid: number, signalwidth: number, totalchunks: number, chunknum: number, min: number, max: number, compresseddata: Uint8Array, originalsize: number) => {
       this.postMessageToWebview({
         command: 'update-waveform-chunk-compressed',
         signalId: signalid,
@@ -213,40 +546,304 @@ export class WasmFormatHandler implements WaveformFileParser {
   async loadNetlist(): Promise<void> {
     this.providerDelegate.logOutputChannel("Using " + this.fileReader.type + " - Loading " + this.fileType + " file: " + this.uri.fsPath);
     await this.fileReader.loadFile(this.uri, this.fileType);
+    this.sharedFilehandler.addFile(this.fileReader.fd, this.fileReader);
 
+    const fstMaxStaticLoadSize = vscode.workspace.getConfiguration('vaporview').get('fstMaxStaticLoadSize');
+    const maxStaticSize = BigInt(Number(fstMaxStaticLoadSize) * 1048576);
     if (this.fileType === 'fst' && this.fileReader.loadStatic === false) {
-      const fstMaxStaticLoadSize = vscode.workspace.getConfiguration('vaporview').get('fstMaxStaticLoadSize');
       this.providerDelegate.logOutputChannel(
         this.uri.fsPath + ' is larger than the max static load size of ' + fstMaxStaticLoadSize +
         ' MB. File will be loaded dynamically. Configure max load size in the settings menu');
     }
 
-    await vscode.window.withProgress({
-      location: vscode.ProgressLocation.Notification,
-      title: "Parsing Netlist for " + this.uri.fsPath,
-      cancellable: false
-    }, async () => {
-      await this.wasmApi!.loadfile(BigInt(this.fileReader.fileSize), this.fileReader.fd, this.fileReader.loadStatic, this.fileReader.bufferSize);
-    });
-    this.netlistSearchable = true;
-  }
+    const signalCacheSize = vscode.workspace.getConfiguration('vaporview').get('signalCacheSize');
+    await this.wasmApi!.setsignalcachebudget(this.handle, BigInt(Number(signalCacheSize) * 1048576));
 
-  async loadBody() {
-    if (this.fileType === 'vcd') {
+    // Keeps the VCD parser after the body is read, so that data appended to
+    // the file is parsed on its own
+    if (this.fileType === 'vcd' && this.fileReader.type === 'nodeFs' && this.features.has('tail')) {
+      await this.wasmApi!.setlivetail(this.handle, vscode.workspace.getConfiguration('vaporview').get('liveTailVcd') === true);
+    }
+
+    if (await this.loadIndex()) {
+      await this.loadFullNetlist();
+      this.netlistSearchable = true;
+      return;
+    }
+
+    try {
       await vscode.window.withProgress({
         location: vscode.ProgressLocation.Notification,
-        title: "Parsing Waveforms for " + this.uri.fsPath,
+        title: "Parsing Netlist for " + this.uri.fsPath,
         cancellable: false
       }, async () => {
-        await this.wasmApi!.readbody();
+        await this.wasmApi!.loadfile(this.handle, BigInt(this.fileReader.fileSize), this.fileReader.fd, this.fileReader.loadStatic, this.fileReader.bufferSize, maxStaticSize);
       });
-    } else {
-      await this.wasmApi!.readbody();
+    } catch {
+      // Details of the load-error are written to the output log by the filehandler
+      vscode.window.showErrorMessage("Failed to parse netlist for " + this.uri.fsPath);
+      return;
+    }
+    await this.loadFullNetlist();
+    this.netlistSearchable = true;
+  }
+
+  // Replaces the top level items set by loadfile() with the whole netlist,
+  // sent at once, so that scopes don't have to be loaded when they are
+  // expanded. Large designs take more memory this way.
+  private async loadFullNetlist() {
+    if (!this.features.has('full-hierarchy')) { return; }
+    if (vscode.workspace.getConfiguration('vaporview').get('loadFullNetlist') !== true) { return; }
+    try {
+      await this.wasmApi!.sendhierarchy(this.handle);
+      const compressedData = new Uint8Array(this.hierarchyChunks.reduce((length, chunk) => length + chunk.length, 0));
+      let offset = 0;
+      this.hierarchyChunks.forEach((chunk) => {
+        compressedData.set(chunk, offset);
+        offset += chunk.length;
+      });
+      const hierarchy = decodeHierarchy(LZ4.decompress(compressedData), this.hierarchyItemCount, this.uri);
+      this.netlistTop = hierarchy.netlistTop;
+      this.enumTables = hierarchy.enumTables;
+    } catch {
+      this.providerDelegate.logOutputChannel("Failed to load the full netlist, scopes will be loaded when they are expanded");
+    } finally {
+      this.hierarchyChunks = [];
+    }
+  }
+
+  async loadBody() {
+    if (this.loadedFromIndex) {
+      if (this.fileType !== 'fst') {
+        this.loadTopLevelParameters();
+      }
+      await this.loadTimeTable();
+      return;
+    }
+
+    try {
+      if (this.regionLoadSize > 0 && this.fileReader.fileSize > this.regionLoadSize) {
+        await this.loadFirstRegion();
+      } else if (this.fileType === 'vcd') {
+        await vscode.window.withProgress({
+          location: vscode.ProgressLocation.Notification,
+          title: "Parsing Waveforms for " + this.uri.fsPath,
+          cancellable: true
+        }, async (progress, token) => {
+          let lastPercent = 0;
+          this.reportProgress = (percent: number) => {
+            if (percent <= lastPercent) { return; }
+            progress.report({ increment: percent - lastPercent });
+            lastPercent = percent;
+          };
+          try {
+            // Load the body in steps so that the user can cancel between them
+            while (!token.isCancellationRequested) {
+              const status = await this.wasmApi!.readbodystep(this.handle, BigInt(readBodyStepSize));
+              this.reportProgress(status.percent);
+              if (status.done) { return; }
+            }
+            await this.wasmApi!.cancelload(this.handle);
+            throw new vscode.CancellationError();
+          } finally {
+            this.reportProgress = undefined;
+          }
+        });
+      } else {
+        await this.wasmApi!.readbody(this.handle);
+      }
+    } catch (error) {
+      if (error instanceof vscode.CancellationError) {
+        this.providerDelegate.logOutputChannel("Cancelled loading waveforms for " + this.uri.fsPath);
+      } else {
+        vscode.window.showErrorMessage("Failed to parse waveforms for " + this.uri.fsPath);
+      }
+      return;
     }
 
     if (this.fileType !== 'fst') {
       this.loadTopLevelParameters();
     }
+    this.logReaderStats();
+    await this.loadTimeTable();
+    this.buildIndex();
+  }
+
+  // Signal data refers to the time table by index instead of holding times,
+  // which makes it smaller. The time table is sent to the webview once,
+  // when it is initialized.
+  private get useTimeIndices(): boolean {
+    return this.features.has('time-indices') && vscode.workspace.getConfiguration('vaporview').get('transitionTimeIndices') === true;
+  }
+
+  // Makes the first time the time origin, for files with times too large for
+  // a number to hold exactly
+  private async updateTimeOrigin() {
+    if (this.timeOrigin !== undefined) { return; }
+    let origin = 0n;
+    if (this.features.has('time-range')) {
+      try {
+        origin = (await this.wasmApi!.gettimerange(this.handle)).start;
+      } catch {
+        this.providerDelegate.logOutputChannel("Failed to get the time range, times past 2^53 lose precision");
+      }
+    }
+    this.timeOrigin = origin;
+    this.metadata.timeOrigin = origin.toString();
+    this.metadata.timeEnd = Number(this.timeEnd - origin);
+    this.metadata.timeTableLoaded = true;
+  }
+
+  private async loadTimeTable() {
+    await this.updateTimeOrigin();
+    this.timeTableChunks = [];
+    if (!this.useTimeIndices) { return; }
+    try {
+      await this.wasmApi!.sendtimetable(this.handle);
+    } catch {
+      this.timeTableChunks = [];
+      this.providerDelegate.logOutputChannel("Failed to send the time table, signal data will hold times");
+    }
+  }
+
+  postTimeTable(): void {
+    this.timeTableChunks.forEach((chunk) => this.postMessageToWebview(chunk));
+  }
+
+  // The sidecar index is only used for files on disk, and is written next to
+  // the waveform file so that it is found again on the next open
+  private get useIndexCache(): boolean {
+    if (this.fileReader.type !== 'nodeFs') { return false; }
+    return vscode.workspace.getConfiguration('vaporview').get('useIndexCache') === true;
+  }
+
+  private get indexPath(): string {
+    return this.uri.fsPath + '.vvidx';
+  }
+
+  private closeIndex() {
+    if (this.indexFd !== undefined) {
+      this.sharedFilehandler.removeFile(this.indexFd);
+      fs.closeSync(this.indexFd);
+    }
+    this.indexFd = undefined;
+    this.loadedFromIndex = false;
+  }
+
+  private async loadIndex(): Promise<boolean> {
+    this.closeIndex();
+    this.fingerprint = undefined;
+    if (!this.useIndexCache) { return false; }
+
+    try {
+      // Only parts of the file are hashed, its modification time and inode
+      // catch edits between them
+      const stats = fs.fstatSync(this.fileReader.fd, { bigint: true });
+      const stamp = BigInt.asUintN(64, stats.mtimeNs ^ (stats.ino << 32n));
+      this.fingerprint = await this.wasmApi!.fingerprint(this.fileReader.fd, BigInt(this.fileReader.fileSize), stamp);
+      if (!fs.existsSync(this.indexPath)) { return false; }
+      // The index file stays open, signals are read from it on demand
+      this.indexFd = fs.openSync(this.indexPath, 'r');
+      const size = fs.fstatSync(this.indexFd).size;
+      this.sharedFilehandler.addFile(this.indexFd, { readSlice: fs.readSync, fileSize: size });
+      this.loadedFromIndex = await this.wasmApi!.loadindex(this.handle, this.indexFd, BigInt(size), this.fingerprint);
+    } catch {
+      this.providerDelegate.logOutputChannel("Failed to load index file " + this.indexPath);
+      this.loadedFromIndex = false;
+    }
+
+    if (!this.loadedFromIndex) {
+      this.closeIndex();
+      return false;
+    }
+    this.providerDelegate.logOutputChannel("Loaded " + this.uri.fsPath + " from index file " + this.indexPath);
+    return true;
+  }
+
+  // Builds the index a batch of signals at a time, so that the webview can
+  // load signals in between. Files that parse quickly don't get one.
+  private async buildIndex() {
+    if (!this.useIndexCache || this.fingerprint === undefined) { return; }
+    if (this.fileReader.fileSize < indexMinFileSize) { return; }
+
+    // Write to a temporary file, so that an interrupted write never leaves a
+    // partial index behind
+    const tempPath = this.indexPath + '.tmp';
+    try {
+      this.indexWriteFd = fs.openSync(tempPath, 'w');
+      await this.wasmApi!.buildindex(this.handle, this.fingerprint);
+      while (!await this.wasmApi!.buildindexstep(this.handle)) { /* next batch */ }
+      fs.closeSync(this.indexWriteFd);
+      this.indexWriteFd = undefined;
+      fs.renameSync(tempPath, this.indexPath);
+      this.providerDelegate.logOutputChannel("Wrote index file " + this.indexPath);
+    } catch {
+      if (this.indexWriteFd !== undefined) { fs.closeSync(this.indexWriteFd); }
+      this.indexWriteFd = undefined;
+      fs.rmSync(tempPath, { force: true });
+      this.providerDelegate.logOutputChannel("Failed to write index file " + this.indexPath);
+    }
+  }
+
+  // VCD files larger than this are indexed, and only their first region of
+  // about this size is parsed. 0 if region loading is disabled.
+  private get regionLoadSize(): number {
+    if (this.fileType !== 'vcd' || this.fileReader.type !== 'nodeFs') { return 0; }
+    const vcdRegionLoadSize = vscode.workspace.getConfiguration('vaporview').get('vcdRegionLoadSize');
+    return Number(vcdRegionLoadSize) * 1048576;
+  }
+
+  private async loadFirstRegion() {
+    await vscode.window.withProgress({
+      location: vscode.ProgressLocation.Notification,
+      title: "Indexing Waveforms for " + this.uri.fsPath,
+      cancellable: false
+    }, async () => {
+      this.regionTimes = Array.from(await this.wasmApi!.indexvcd(this.handle, BigInt(regionCheckpointInterval)));
+      await this.loadRegion(0);
+    });
+  }
+
+  // Loads the region from a checkpoint that is about the region load size
+  private async loadRegion(first: number) {
+    const times = this.regionTimes;
+    const last  = Math.min(first + Math.max(1, Math.floor(this.regionLoadSize / regionCheckpointInterval)), times.length - 1);
+    const region = { start: times[first], end: times[last] };
+    await this.wasmApi!.loadregion(this.handle, region);
+    this.loadedRegion = region;
+    this.providerDelegate.logOutputChannel(this.uri.fsPath + " is larger than the region load size, loaded times " +
+      region.start + " to " + region.end + " of " + times[times.length - 1]);
+  }
+
+  // Loads the region that starts at the checkpoint before startTime, unless
+  // the loaded region covers the range, and has the webview load its
+  // signals again. A range larger than a region gets the region at its start.
+  public loadTimeRange(startTime: bigint, endTime: bigint): Promise<void> {
+    const load = this.regionQueue.then(async () => {
+      const region = this.loadedRegion;
+      if (region === undefined) { return; }
+      if (startTime >= region.start && endTime <= region.end) { return; }
+      let first = 0;
+      while (first + 1 < this.regionTimes.length && this.regionTimes[first + 1] <= startTime) { first++; }
+      if (this.regionTimes[first] === region.start) { return; }
+
+      await this.loadRegion(first);
+      await this.loadTimeTable();
+      this.postTimeTable();
+      this.postMessageToWebview({ command: 'reloadSignalData', metadata: this.metadata });
+    });
+    this.regionQueue = load.catch(() => {});
+    return load;
+  }
+
+  private async logReaderStats() {
+    try {
+      const stats = await this.wasmApi!.getreaderstats(this.handle);
+      this.providerDelegate.logOutputChannel("File reader cache hits: " + stats.cacheHits + ", misses: " + stats.cacheMisses +
+        ", host reads: " + stats.hostReads + ", bytes read: " + stats.bytesRead);
+    } catch {
+      // Stats are only informational
+    }
   }
 
   private getParametersInTreeData(treeData: NetlistItem[]): NetlistItem[] {
@@ -268,12 +865,11 @@ export class WasmFormatHandler implements WaveformFileParser {
 
     const parameterItems = this.getParametersInTreeData(this.netlistTop);
     const signalIdList = parameterItems.map((param) => param.signalId);
-    const params = await this.wasmApi!.getparametervalues(new Uint32Array(signalIdList));
-    const parameterValues = JSON.parse(params);
+    const parameterValues = await this.wasmApi!.getparametervalues(this.handle, new Uint32Array(signalIdList));
     parameterItems.forEach((param) => {
-      const paramValue = parameterValues.find((entry: [number, string]) => entry[0] === param.signalId);
+      const paramValue = parameterValues.find((entry) => entry.signalId === param.signalId);
       if (paramValue) {
-        param.setParamAndTooltip(paramValue[1]);
+        param.setParamAndTooltip(paramValue.value);
       }
     });
     this.providerDelegate.updateViews(this.uri);
@@ -285,70 +881,176 @@ export class WasmFormatHandler implements WaveformFileParser {
     if (!this.wasmApi) { return []; }
     if (element.children.length > 0) { return element.children; }
 
-    const scopePath    = element.scopePath.concat([element.name]); 
-    let itemsRemaining = Infinity;
-    let startIndex     = 0;
-    let callLimit      = 255;
+    const scopePath = element.scopePath.concat([element.name]);
+    const order     = netlistChildOrder();
+    let token: bigint | undefined = undefined;
     const result: NetlistItem[] = [];
 
-    while (itemsRemaining > 0) {
-      const children   = await this.wasmApi!.getchildren(element.netlistId, startIndex);
-      const childItems = JSON.parse(children);
-      itemsRemaining   = childItems.remainingItems;
-      startIndex      += childItems.totalReturned;
+    do {
+      const childItems = await this.wasmApi!.getchildrenpage(this.handle, element.netlistId, order, token);
+      token = childItems.nextToken;
 
-      const scopes: NetlistItem[] = childItems.scopes?.map((child: { name: string; type: string; id: number }) => {
-        return createScope(child.name, child.type, scopePath, child.id, -1, this.uri);
-      }) || [];
-      const vars: NetlistItem[] = childItems.vars?.map((child: { name: string; paramValue: string; type: string; encoding: string; netlistId: number; signalId: number; width: number; msb: number; lsb: number; enumType: string }) => {
-        return createVar(child.name, child.paramValue, child.type, child.encoding.split('(')[0], scopePath, child.netlistId, child.signalId, child.width, child.msb, child.lsb, child.enumType, false /*isFsdb*/, this.uri);
-      }) || [];
+      const scopes: NetlistItem[] = childItems.scopes.map((child) => {
+        return createScope(child.name, child.tpe, scopePath, child.id, -1, this.uri);
+      });
+      const vars: NetlistItem[] = childItems.vars.map((child) => {
+        return createVar(child.name, child.paramValue ?? '', child.tpe, child.encoding.split('(')[0], scopePath, child.netlistId, child.signalId, child.width, child.msb, child.lsb, child.enumType, false /*isFsdb*/, this.uri);
+      });
 
       result.push(...scopes);
       result.push(...vars);
-
-      callLimit--;
-      if (callLimit <= 0) {break;}
-    }
+    } while (token !== undefined);
 
     return result;
   }
 
-  async getSignalData(signalIdList: SignalId[]): Promise<void> {
-    this.wasmApi!.getsignaldata(new Uint32Array(signalIdList));
+  async getSignalData(signalIdList: SignalId[], priority: number = 0): Promise<void> {
+    await this.queueSignals(signalIdList, priority);
     this.loadTopLevelParameters();
   }
 
+  // Returns the request id, which can be passed to cancelSignalRequest()
+  public async queueSignals(signalIdList: SignalId[], priority: number): Promise<number> {
+    const requestId = this.nextRequestId++;
+    this.signalRequests.set(requestId, { remaining: signalIdList.length, start: Date.now() });
+    await this.wasmApi!.queuesignals(this.handle, requestId, new Uint32Array(signalIdList), priority);
+    this.processSignalQueue();
+    return requestId;
+  }
+
+  public async cancelSignalRequest(requestId: number): Promise<void> {
+    this.signalRequests.delete(requestId);
+    await this.wasmApi?.cancelsignalrequest(this.handle, requestId);
+  }
+
+  // Loads the queue in batches. Calls to the filehandler are served in
+  // order, so requests queued while a batch loads are sorted in before the
+  // next one.
+  private async processSignalQueue() {
+    if (this.signalQueueActive) { return; }
+    this.signalQueueActive = true;
+    try {
+      let remaining = 1;
+      while (remaining > 0 && this.wasmApi) {
+        remaining = await this.wasmApi.loadqueuedsignals(this.handle, signalBatchSize);
+      }
+    } catch {
+      // Details of the load-error are written to the output log by the filehandler
+      this.signalRequests.clear();
+    } finally {
+      this.signalQueueActive = false;
+    }
+  }
+
+  async freeSignals(signalIdList: SignalId[]): Promise<void> {
+    await this.wasmApi?.freesignals(this.handle, new Uint32Array(signalIdList));
+  }
+
   async getEnumData(enumList: EnumQueueEntry[]): Promise<void> {
-    const netlistIdList = enumList.map((entry) => entry.netlistId);
-    this.wasmApi!.getenumdata(new Uint32Array(netlistIdList));
+    const missing = enumList.filter((entry) => {
+      const values = this.enumTables.get(entry.name);
+      if (values === undefined) { return true; }
+      this.postMessageToWebview({
+        command: 'update-enum-chunk',
+        enumName: entry.name,
+        enumDataChunk: JSON.stringify(values),
+        totalChunks: 1,
+        chunkNum: 0,
+      } as EnumDataChunk);
+      return false;
+    });
+    if (missing.length === 0) { return; }
+    const netlistIdList = missing.map((entry) => entry.netlistId);
+    this.wasmApi!.getenumdata(this.handle, new Uint32Array(netlistIdList));
   }
 
-  async getValuesAtTime(time: number, instancePaths: string[]): Promise<ValuesAtTimeResult[]> {
-    const result = await this.wasmApi!.getvaluesattime(BigInt(time), instancePaths.join(" "));
+  async getValuesAtTime(time: number | string, instancePaths: string[]): Promise<ValuesAtTimeResult[]> {
+    await this.loadTimeRange(BigInt(time), BigInt(time));
+    const result = await this.wasmApi!.getvaluesattime(this.handle, BigInt(time), instancePaths.join(" "));
+    // Callers get the values as JSON text, as before the result was typed
+    return result.map((entry) => ({ instancePath: entry.instancePath, value: JSON.stringify(entry.values) }));
+  }
+
+  // Value changes of a signal in [startTime, endTime], starting with the value in effect at startTime
+  public async getTransitions(signalId: number, startTime: number | bigint, endTime: number | bigint): Promise<[bigint, string][]> {
+    await this.loadTimeRange(BigInt(startTime), BigInt(endTime));
+    const result = await this.wasmApi!.gettransitions(this.handle, signalId, BigInt(startTime), BigInt(endTime));
+    return parseTransitions(result);
+  }
+
+  // The next count value changes after time. Pass the time of the last change to get the next page.
+  public async getNextTransitions(signalId: number, time: number | bigint, count: number): Promise<[bigint, string][]> {
+    const result = await this.wasmApi!.getnexttransitions(this.handle, signalId, BigInt(time), count);
+    return parseTransitions(result);
+  }
+
+  // Per-bucket summaries of a signal between startTime and endTime, for drawing it zoomed out.
+  // Fewer buckets come back than asked for if the range is clipped or narrower than that.
+  public async getSignalSummary(signalId: number, startTime: number, endTime: number, buckets: number): Promise<SignalSummaryBucket[]> {
+    await this.loadTimeRange(BigInt(startTime), BigInt(endTime));
+    const result = await this.wasmApi!.getsignalsummary(this.handle, signalId, BigInt(startTime), BigInt(endTime), buckets);
     return JSON.parse(result);
   }
 
   public async searchNetlist(searchString: string, scopeId: number): Promise<NetlistSearchResult> {
-    const resultJson = await this.wasmApi!.searchnetlist(searchString, scopeId);
+    const result = await this.wasmApi!.searchnetlist(this.handle, searchString, scopeId);
+    return {
+      totalResults: result.totalResults,
+      searchResults: result.searchResults.map((entry) => {
+        const item = entry.item;
+        if (item.tag === 'scope') {
+          return { instancePath: entry.instancePath, type: item.value.tpe, isVar: false, paramValue: '', msb: -1, lsb: -1 };
+        }
+        return { instancePath: entry.instancePath, type: item.value.tpe, isVar: true, paramValue: item.value.paramValue ?? '', msb: item.value.msb, lsb: item.value.lsb };
+      })
+    };
+  }
+
+  // Parses data that a running simulation has appended to a VCD file, and
+  // has the webview load its signals again with the new end time
+  async tailFile(): Promise<boolean> {
+    if (!this.wasmApi) { return false; }
+    if (this.fileType !== 'vcd' || this.fileReader.type !== 'nodeFs') { return false; }
+
+    const stats = await fs.promises.stat(this.uri.fsPath);
+    if (stats.size === this.fileReader.fileSize) { return false; }
     try {
-      return JSON.parse(resultJson) as NetlistSearchResult;
+      const grown = await this.wasmApi.tailfile(this.handle, BigInt(stats.size));
+      this.fileReader.fileSize = stats.size;
+      if (!grown) { return false; }
+      await this.loadTimeTable();
+      this.postTimeTable();
+      this.postMessageToWebview({ command: 'reloadSignalData', metadata: this.metadata });
+      return true;
     } catch {
-      return { totalResults: 0, searchResults: [] };
+      return false;
     }
   }
 
   async unload(): Promise<void> {
+    this.sharedFilehandler.removeFile(this.fileReader.fd);
     this.fileReader.close(this.fileReader.fd);
+    this.closeIndex();
     if (this.wasmApi) {
-      await this.wasmApi.unload();
+      await this.wasmApi.unload(this.handle);
     }
     this.parametersLoaded = false;
     this.netlistTop = [];
+    this.enumTables = new Map();
+    this.signalRequests.clear();
+    this.timeTableChunks = [];
+    this.timeOrigin = undefined;
+    this.metadata.timeOrigin = "0";
+    this.regionTimes = [];
+    this.loadedRegion = undefined;
   }
 
-  dispose(): void {
-    this.unload();
-    this.wasmWorker.terminate();
+  // Closes the document and lets go of the shared filehandler, which stops
+  // once no document uses it anymore
+  async dispose(): Promise<void> {
+    await this.unload();
+    await this.wasmApi?.closedocument(this.handle).catch(() => {});
+    this.sharedFilehandler.removeDocument(this.handle);
+    this.sharedFilehandler.release();
   }
 }
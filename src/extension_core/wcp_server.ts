@@ -835,6 +835,7 @@ export class WCPServer {
     }
 
     // Check time bounds (same logic as setMarkerAtTime)
+    time = document.fromAbsoluteTime(time);
     const timeEnd = document.metadata.timeEnd;
     if (time < 0 || time > timeEnd) {
       throw new Error(`Time ${time} is out of bounds (0 to ${timeEnd})`);
@@ -885,6 +886,8 @@ export class WCPServer {
     }
 
     // Check time bounds
+    startTime = document.fromAbsoluteTime(startTime);
+    endTime   = document.fromAbsoluteTime(endTime);
     const timeEnd = document.metadata.timeEnd;
     if (startTime < 0 || endTime > timeEnd || startTime >= endTime) {
       throw new Error(`Invalid time range: start_time ${startTime} to end_time ${endTime} (valid range: 0 to ${timeEnd})`);
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use wellen::{Hierarchy, ScopeRef, VarRef};
+
+use crate::hierarchy_filter::{scope_visible, var_visible, HierarchyFilter};
+use crate::{get_scope_data, get_var_data};
+
+/*
+  Binary encoding of the whole hierarchy, which is sent as one LZ4 frame with
+  sendhierarchychunk(), so the host can build its netlist without a call per
+  scope. It is sent in chunks like the signal data in transition_encoding.rs.
+  After decompression it is, for every visible scope and var, with every
+  scope before its children:
+
+    kind (u8), SCOPE or VAR
+    id of the parent scope + 1, or 0 at the top level (LEB128)
+    SCOPE: id (LEB128), name, type
+    VAR:   netlist id, signal id (LEB128), name, type, encoding, width
+           (LEB128), msb and lsb (signed LEB128, -1 without a bit range),
+           enum type name ("" if none)
+
+  Names are a length (LEB128) + UTF-8. Types, encodings and enum type names
+  repeat a lot, so they are an index into a table of strings that is filled
+  while the data is read: an index equal to the number of strings read so
+  far is followed by a new string, as a length + UTF-8.
+
+  The items are followed by the enum types of the vars, so the host doesn't
+  have to ask for them with getenumdata():
+
+    number of enum types (LEB128)
+    for each: name, number of values (LEB128), and every value as its bits
+    and its name
+
+  Enum type names are table strings, bits and value names are plain strings.
+  The number of items is sent along with the data.
+ */
+
+pub const SCOPE: u8 = 0;
+pub const VAR: u8 = 1;
+
+struct HierarchyEncoder<'a> {
+  hierarchy: &'a Hierarchy,
+  filter: &'a Option<HierarchyFilter>,
+  param_table: &'a Option<Vec<(u32, String)>>,
+  out: &'a mut dyn Write,
+  strings: HashMap<String, u64>,
+  items: u32,
+  // Enum types of the written vars, in the order they were first seen
+  enum_types: Vec<(&'a str, Vec<(&'a str, &'a str)>)>,
+}
+
+impl HierarchyEncoder<'_> {
+  fn write_varint(&mut self, value: u64) -> io::Result<()> {
+    leb128::write::unsigned(&mut *self.out, value).map(|_| ())
+  }
+
+  fn write_signed_varint(&mut self, value: i64) -> io::Result<()> {
+    leb128::write::signed(&mut *self.out, value).map(|_| ())
+  }
+
+  fn write_string(&mut self, s: &str) -> io::Result<()> {
+    self.write_varint(s.len() as u64)?;
+    self.out.write_all(s.as_bytes())
+  }
+
+  fn write_table_string(&mut self, s: &str) -> io::Result<()> {
+    match self.strings.get(s) {
+      Some(index) => self.write_varint(*index),
+      None => {
+        let index = self.strings.len() as u64;
+        self.strings.insert(s.to_string(), index);
+        self.write_varint(index)?;
+        self.write_string(s)
+      },
+    }
+  }
+
+  fn write_scope(&mut self, s: ScopeRef, parent: u64) -> io::Result<()> {
+    let scope_data = get_scope_data(self.hierarchy, s);
+    self.out.write_all(&[SCOPE])?;
+    self.write_varint(parent)?;
+    self.write_varint(scope_data.id as u64)?;
+    self.write_string(&scope_data.name)?;
+    self.write_table_string(&scope_data.tpe)?;
+    self.items += 1;
+
+    let hierarchy = self.hierarchy;
+    let filter = self.filter;
+    let scope = &hierarchy[s];
+    for child in scope.scopes(hierarchy).filter(|child| scope_visible(filter, *child)) {
+      self.write_scope(child, scope_data.id as u64 + 1)?;
+    }
+    for var in scope.vars(hierarchy).filter(|var| var_visible(filter, *var)) {
+      self.write_var(var, scope_data.id as u64 + 1)?;
+    }
+    Ok(())
+  }
+
+  fn write_var(&mut self, v: VarRef, parent: u64) -> io::Result<()> {
+    let var_data = get_var_data(self.hierarchy, v, self.param_table);
+    self.out.write_all(&[VAR])?;
+    self.write_varint(parent)?;
+    self.write_varint(var_data.netlist_id as u64)?;
+    self.write_varint(var_data.signal_id as u64)?;
+    self.write_string(&var_data.name)?;
+    self.write_table_string(&var_data.tpe)?;
+    self.write_table_string(&var_data.encoding)?;
+    self.write_varint(var_data.width as u64)?;
+    self.write_signed_varint(var_data.msb as i64)?;
+    self.write_signed_varint(var_data.lsb as i64)?;
+    self.write_table_string(&var_data.enum_type)?;
+    self.items += 1;
+
+    let hierarchy = self.hierarchy;
+    if let Some((name, values)) = hierarchy[v].enum_type(hierarchy) {
+      if !self.enum_types.iter().any(|(known, _)| *known == name) {
+        self.enum_types.push((name, values));
+      }
+    }
+    Ok(())
+  }
+
+  fn write_enum_types(&mut self) -> io::Result<()> {
+    let enum_types = std::mem::take(&mut self.enum_types);
+    self.write_varint(enum_types.len() as u64)?;
+    for (name, values) in enum_types {
+      self.write_table_string(name)?;
+      self.write_varint(values.len() as u64)?;
+      for (bits, value_name) in values {
+        self.write_string(bits)?;
+        self.write_string(value_name)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Encodes every visible scope and var, and returns how many there are
+pub fn encode_hierarchy(hierarchy: &Hierarchy, filter: &Option<HierarchyFilter>, param_table: &Option<Vec<(u32, String)>>, out: &mut dyn Write) -> io::Result<u32> {
+  let mut encoder = HierarchyEncoder { hierarchy, filter, param_table, out, strings: HashMap::new(), items: 0, enum_types: Vec::new() };
+  for s in hierarchy.scopes().filter(|s| scope_visible(filter, *s)) {
+    encoder.write_scope(s, 0)?;
+  }
+  for v in hierarchy.vars().filter(|v| var_visible(filter, *v)) {
+    encoder.write_var(v, 0)?;
+  }
+  encoder.write_enum_types()?;
+  Ok(encoder.items)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use wellen::{HierarchyBuilder, ScopeType, SignalEncoding, Timescale, TimescaleUnit, VarDirection, VarType};
+
+  // Reads the encoding back the way the host does
+  struct Decoder<'d> {
+    data: &'d [u8],
+    strings: Vec<String>,
+  }
+
+  impl Decoder<'_> {
+    fn varint(&mut self) -> u64 {
+      leb128::read::unsigned(&mut self.data).unwrap()
+    }
+
+    fn signed_varint(&mut self) -> i64 {
+      leb128::read::signed(&mut self.data).unwrap()
+    }
+
+    fn string(&mut self) -> String {
+      let length = self.varint() as usize;
+      let (s, rest) = self.data.split_at(length);
+      self.data = rest;
+      String::from_utf8(s.to_vec()).unwrap()
+    }
+
+    fn table_string(&mut self) -> String {
+      let index = self.varint() as usize;
+      if index == self.strings.len() {
+        let s = self.string();
+        self.strings.push(s);
+      }
+      self.strings[index].clone()
+    }
+
+    // Returns (parent, id, name, type or enum type name) of an item, with the
+    // netlist id of a var
+    fn item(&mut self) -> (u64, u64, String, String) {
+      let kind = self.data[0];
+      self.data = &self.data[1..];
+      let parent = self.varint();
+      let id = self.varint();
+      if kind == SCOPE {
+        let name = self.string();
+        (parent, id, name, self.table_string())
+      } else {
+        self.varint();
+        let name = self.string();
+        self.table_string();
+        self.table_string();
+        self.varint();
+        self.signed_varint();
+        self.signed_varint();
+        (parent, id, name, self.table_string())
+      }
+    }
+  }
+
+  fn fsm_hierarchy() -> Hierarchy {
+    let mut builder = HierarchyBuilder::new(Timescale::new(1, TimescaleUnit::NanoSeconds), None, None);
+    let fsm = builder.declare_enum("fsm", [("00", "IDLE"), ("01", "RUN"), ("10", "DONE")].into_iter());
+    builder.push_scope("top", ScopeType::Module, None);
+    for name in ["state", "next"] {
+      let signal = builder.new_signal(SignalEncoding::BitVector(2));
+      builder.add_var(name, VarType::Wire, VarDirection::Implicit, None, None, Some(fsm), signal);
+    }
+    let signal = builder.new_signal(SignalEncoding::BitVector(1));
+    builder.add_var("clk", VarType::Wire, VarDirection::Implicit, None, None, None, signal);
+    builder.pop_scope();
+    builder.finish()
+  }
+
+  #[test]
+  fn writes_items_then_enum_types_once() {
+    let hierarchy = fsm_hierarchy();
+    let mut out = Vec::new();
+    let items = encode_hierarchy(&hierarchy, &None, &None, &mut out).unwrap();
+    assert_eq!(items, 4);
+
+    let mut decoder = Decoder { data: &out, strings: Vec::new() };
+    let decoded: Vec<(u64, u64, String, String)> = (0..items).map(|_| decoder.item()).collect();
+    let (top_parent, top_id, top_name, _) = &decoded[0];
+    assert_eq!((*top_parent, top_name.as_str()), (0, "top"));
+    let vars: Vec<(&str, &str)> = decoded[1..].iter().map(|(_, _, name, enum_type)| (name.as_str(), enum_type.as_str())).collect();
+    assert_eq!(vars, [("state", "fsm"), ("next", "fsm"), ("clk", "")]);
+    assert!(decoded[1..].iter().all(|(parent, _, _, _)| *parent == top_id + 1));
+
+    assert_eq!(decoder.varint(), 1);
+    assert_eq!(decoder.table_string(), "fsm");
+    let count = decoder.varint();
+    let values: Vec<(String, String)> = (0..count).map(|_| (decoder.string(), decoder.string())).collect();
+    assert_eq!(values, [("00".into(), "IDLE".into()), ("01".into(), "RUN".into()), ("10".into(), "DONE".into())]);
+    assert!(decoder.data.is_empty());
+  }
+}
@@ -0,0 +1,227 @@
+use wellen::{Hierarchy, ScopeRef, VarRef};
+
+use crate::LoadSettings;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ScopeState {
+  Hidden,
+  // Not included itself, but on the way to an included scope
+  OnPath,
+  Included,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum PathMatch {
+  None,
+  // The pattern could match a scope below this one
+  Prefix,
+  Full,
+}
+
+// Scope patterns of the load settings, split at '.'
+struct ScopePatterns<'a> {
+  include: Vec<Vec<&'a str>>,
+  exclude: Vec<Vec<&'a str>>,
+}
+
+/// Which scopes and vars are left after applying the scope and var type
+/// filters of the load settings. wellen parses the whole hierarchy, so items
+/// are filtered out where they are handed to the host and when signals are
+/// loaded automatically. Value changes of filtered signals are only dropped
+/// by the VCD parser that reads files in steps, other bodies are still
+/// parsed in full.
+pub struct HierarchyFilter {
+  scopes: Vec<bool>,
+  vars: Vec<bool>,
+}
+
+impl HierarchyFilter {
+  /// Returns None if the settings don't filter anything
+  pub fn new(hierarchy: &Hierarchy, settings: &LoadSettings) -> Option<Self> {
+    if settings.include_scopes.is_empty() && settings.exclude_scopes.is_empty() &&
+       settings.include_var_types.is_empty() && settings.exclude_var_types.is_empty() {
+      return None;
+    }
+
+    let patterns = ScopePatterns {
+      include: settings.include_scopes.iter().map(|p| p.split('.').collect()).collect(),
+      exclude: settings.exclude_scopes.iter().map(|p| p.split('.').collect()).collect(),
+    };
+    let mut filter = HierarchyFilter { scopes: Vec::new(), vars: Vec::new() };
+    let root_state = if patterns.include.is_empty() {ScopeState::Included} else {ScopeState::OnPath};
+
+    for v in hierarchy.vars() {
+      filter.set_var(hierarchy, v, root_state, settings);
+    }
+    let mut path = Vec::new();
+    for s in hierarchy.scopes() {
+      filter.visit_scope(hierarchy, s, root_state, &mut path, &patterns, settings);
+    }
+    Some(filter)
+  }
+
+  fn visit_scope<'h>(&mut self, hierarchy: &'h Hierarchy, s: ScopeRef, parent_state: ScopeState, path: &mut Vec<&'h str>,
+    patterns: &ScopePatterns, settings: &LoadSettings) {
+    let scope = &hierarchy[s];
+    path.push(scope.name(hierarchy));
+
+    let state = if parent_state == ScopeState::Hidden || patterns.exclude.iter().any(|p| match_path(p, path) == PathMatch::Full) {
+      ScopeState::Hidden
+    } else if parent_state == ScopeState::Included {
+      ScopeState::Included
+    } else {
+      match patterns.include.iter().map(|p| match_path(p, path)).fold(PathMatch::None, |a, b| if b > a {b} else {a}) {
+        PathMatch::Full => ScopeState::Included,
+        PathMatch::Prefix => ScopeState::OnPath,
+        PathMatch::None => ScopeState::Hidden,
+      }
+    };
+    set_flag(&mut self.scopes, s.index(), state != ScopeState::Hidden);
+
+    for v in scope.vars(hierarchy) {
+      self.set_var(hierarchy, v, state, settings);
+    }
+    for child in scope.scopes(hierarchy) {
+      self.visit_scope(hierarchy, child, state, path, patterns, settings);
+    }
+    path.pop();
+  }
+
+  fn set_var(&mut self, hierarchy: &Hierarchy, v: VarRef, scope_state: ScopeState, settings: &LoadSettings) {
+    let var_type = format!("{:?}", hierarchy[v].var_type());
+    let type_included = settings.include_var_types.is_empty() ||
+      settings.include_var_types.iter().any(|t| t.eq_ignore_ascii_case(&var_type));
+    let type_excluded = settings.exclude_var_types.iter().any(|t| t.eq_ignore_ascii_case(&var_type));
+    set_flag(&mut self.vars, v.index(), scope_state == ScopeState::Included && type_included && !type_excluded);
+  }
+
+  /// Flags by signal index for the signals of visible vars, the value
+  /// changes of the others don't have to be kept
+  pub fn kept_signals(&self, hierarchy: &Hierarchy) -> Vec<bool> {
+    let mut kept = Vec::new();
+    for v in hierarchy.all_vars().filter(|v| self.var_visible(*v)) {
+      set_flag(&mut kept, hierarchy[v].signal_ref().index(), true);
+    }
+    kept
+  }
+
+  pub fn scope_visible(&self, s: ScopeRef) -> bool {
+    self.scopes.get(s.index()).copied().unwrap_or(false)
+  }
+
+  pub fn var_visible(&self, v: VarRef) -> bool {
+    self.vars.get(v.index()).copied().unwrap_or(false)
+  }
+}
+
+/// Checks a scope against an optional filter, for documents loaded without one
+pub fn scope_visible(filter: &Option<HierarchyFilter>, s: ScopeRef) -> bool {
+  filter.as_ref().is_none_or(|f| f.scope_visible(s))
+}
+
+pub fn var_visible(filter: &Option<HierarchyFilter>, v: VarRef) -> bool {
+  filter.as_ref().is_none_or(|f| f.var_visible(v))
+}
+
+fn set_flag(flags: &mut Vec<bool>, index: usize, value: bool) {
+  if flags.len() <= index {flags.resize(index + 1, false);}
+  flags[index] = value;
+}
+
+// Matches a scope path against a pattern, both split at '.'. "**" matches any
+// number of scopes, "*" and "?" match within one scope name. Results are
+// memoized by position, as "**" would otherwise try every split of the path.
+fn match_path(pattern: &[&str], path: &[&str]) -> PathMatch {
+  let mut memo = vec![None; (pattern.len() + 1) * (path.len() + 1)];
+  match_path_from(pattern, path, 0, 0, &mut memo)
+}
+
+fn match_path_from(pattern: &[&str], path: &[&str], p: usize, s: usize, memo: &mut [Option<PathMatch>]) -> PathMatch {
+  let key = p * (path.len() + 1) + s;
+  if let Some(result) = memo[key] {return result;}
+  let result = if s == path.len() {
+    if pattern[p..].iter().all(|p| *p == "**") {PathMatch::Full} else {PathMatch::Prefix}
+  } else if p == pattern.len() {
+    PathMatch::None
+  } else if pattern[p] == "**" {
+    let skip = match_path_from(pattern, path, p + 1, s, memo);
+    let consume = match_path_from(pattern, path, p, s + 1, memo);
+    if consume > skip {consume} else {skip}
+  } else if glob(pattern[p].as_bytes(), path[s].as_bytes()) {
+    match_path_from(pattern, path, p + 1, s + 1, memo)
+  } else {
+    PathMatch::None
+  };
+  memo[key] = Some(result);
+  result
+}
+
+// Backtracks to the last '*' only, which is enough since a later '*' can
+// match anything an earlier one could
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+  let (mut p, mut t) = (0, 0);
+  // Position after the last '*', and the text position it matched up to
+  let mut star = None;
+  while t < text.len() {
+    if p < pattern.len() && pattern[p] == b'*' {
+      p += 1;
+      star = Some((p, t));
+    } else if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+      p += 1;
+      t += 1;
+    } else if let Some((star_p, star_t)) = star {
+      p = star_p;
+      t = star_t + 1;
+      star = Some((star_p, t));
+    } else {
+      return false;
+    }
+  }
+  pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn path_match(pattern: &str, path: &str) -> PathMatch {
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let path: Vec<&str> = path.split('.').collect();
+    match_path(&pattern, &path)
+  }
+
+  #[test]
+  fn globs_within_a_name() {
+    assert!(glob(b"*", b""));
+    assert!(glob(b"*", b"clk"));
+    assert!(glob(b"a?c", b"abc"));
+    assert!(!glob(b"a?c", b"ac"));
+    assert!(glob(b"*_reg", b"data_reg"));
+    assert!(!glob(b"*_reg", b"data_reg2"));
+    assert!(glob(b"a*b*c", b"aXbYbZc"));
+    assert!(!glob(b"a*b*c", b"aXbYbZ"));
+    assert!(glob(&b"a*".repeat(20), &[b'a'; 40]));
+    assert!(!glob(&[&b"a*".repeat(20)[..], b"b"].concat(), &[b'a'; 100]));
+  }
+
+  #[test]
+  fn matches_scope_paths() {
+    assert_eq!(path_match("top.cpu", "top"), PathMatch::Prefix);
+    assert_eq!(path_match("top.cpu", "top.cpu"), PathMatch::Full);
+    assert_eq!(path_match("top.cpu", "top.mem"), PathMatch::None);
+    assert_eq!(path_match("top.cpu", "top.cpu.alu"), PathMatch::None);
+    assert_eq!(path_match("top.c*", "top.cpu"), PathMatch::Full);
+    assert_eq!(path_match("top.**", "top"), PathMatch::Full);
+    assert_eq!(path_match("**.alu", "top"), PathMatch::Prefix);
+    assert_eq!(path_match("**.alu", "top.cpu.alu"), PathMatch::Full);
+    assert_eq!(path_match("**.alu", "top.cpu.fpu"), PathMatch::Prefix);
+  }
+
+  #[test]
+  fn matches_many_double_stars_on_a_deep_path() {
+    let pattern = vec!["**"; 30].join(".") + ".x";
+    let path = vec!["a"; 40].join(".");
+    assert_eq!(path_match(&pattern, &path), PathMatch::Prefix);
+    assert_eq!(path_match(&pattern, &(path + ".x")), PathMatch::Full);
+  }
+}
@@ -1,3 +1,7 @@
+// The generated bindings of some imports take more arguments than clippy
+// likes, and a lint attribute on the macro call doesn't reach them
+#![allow(clippy::too_many_arguments)]
+
 // Use a procedural macro to generate bindings for the world we specified in
 // `host.wit`
 
@@ -6,20 +10,52 @@ wit_bindgen::generate!({
   world: "filehandler",
 });
 
-use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
 //use std::result;
 use lazy_static::lazy_static;
-use std::sync::Mutex;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::cmp::max;
-use wellen::{FileFormat, Hierarchy, Item, ScopeRef, Signal, SignalRef, SignalSource, TimeTable, TimescaleUnit, WellenError, VarRef, Var, Scope};
-use wellen::viewers::{read_body, read_header, ReadBodyContinuation, HeaderResult};
+use wellen::{FileFormat, Hierarchy, ItemRef, ScopeRef, SignalRef, TimescaleUnit, VarRef, Scope};
+use wellen::viewers::{read_body, read_header, HeaderResult};
 use wellen::LoadOptions;
 use core::ops::Index;
-use lz4_flex::frame::FrameEncoder;
 use serde::Deserialize;
 
+mod child_pages;
+mod decompress;
+mod document;
+mod error;
+mod hierarchy_encoding;
+mod hierarchy_filter;
 mod libsurfer;
+mod lod;
+mod page_cache;
+mod signal_cache;
+mod signal_queue;
+mod sidecar;
+mod time_window;
+mod transition_encoding;
+mod transitions;
+mod vcd_body;
+mod vcd_index;
+#[cfg(test)]
+mod test_util;
+
+use child_pages::ChildOrder;
+use decompress::{decompress_bounded, Compression, DecompressReader};
+use document::{ReadBodyEnum, WaveformDocument, with_document};
+use error::{guard, loaded, loaded_mut, ErrorKind};
+use hierarchy_encoding::encode_hierarchy;
+use hierarchy_filter::{scope_visible, var_visible, HierarchyFilter};
+use page_cache::{PageCache, PAGE_SIZE};
+use sidecar::IndexBuilder;
+use time_window::truncate_vcd;
+use transition_encoding::{encode_time_table, encode_transitions, send_compressed};
+use transitions::{transitions_after, transitions_in_range};
+use vcd_body::{VcdBodyParser, VcdBodyReader};
+use vcd_index::VcdIndex;
+use vaporview::fileparser::types::{NetlistItem, ScopeItem, SearchEntry, SignalMemory, VarItem};
 
 
 #[derive(Deserialize, Debug)]
@@ -32,78 +68,71 @@ pub struct SurferStatus {
     file_format: String,
 }
 
-enum ReadBodyEnum {
-  Static(ReadBodyContinuation<Cursor<Vec<u8>>>),
-  Dynamic(ReadBodyContinuation<BufReader<WasmFileReader>>),
-  None,
-}
+pub type CompressedFileReader = DecompressReader<BufReader<WasmFileReader>>;
 
 enum HeaderResultType {
   Static(HeaderResult<Cursor<Vec<u8>>>),
   Dynamic(HeaderResult<BufReader<WasmFileReader>>),
-  Err(WellenError),
+  Compressed(HeaderResult<BufReader<CompressedFileReader>>),
 }
 
 lazy_static! {
-  //static ref _file: Mutex<Option<WasmFileReader>> = Mutex::new(None);
   pub static ref BINCODE_OPTIONS: bincode::DefaultOptions = bincode::DefaultOptions::new();
-  static ref _file_format : Mutex<FileFormat> = Mutex::new(FileFormat::Unknown);
-  static ref _hierarchy: Mutex<Option<Hierarchy>> = Mutex::new(None);
-  static ref _body: Mutex<ReadBodyEnum> = Mutex::new(ReadBodyEnum::None);
-  static ref _time_table: Mutex<Option<TimeTable>> = Mutex::new(None);
-  static ref _signal_source: Mutex<Option<SignalSource>> = Mutex::new(None);
-  static ref _param_table: Mutex<Option<Vec<(u32, String)>>> = Mutex::new(None);
-  static ref _param_id_list: Mutex<Option<Vec<SignalRef>>> = Mutex::new(None);
-  
-  // Chunked data reassembly
-  static ref _chunks: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
-  static ref _total_chunks: Mutex<u32> = Mutex::new(0);
-}
-
-struct WasmFileReader {
+}
+
+pub struct WasmFileReader {
   fd: u32,
   file_size: u64,
   cursor: u64,
   read_callback: Arc<dyn Fn(u32, u64, u32) -> Vec<u8> + Send + Sync>,
+  // Shared by the readers of a document, so that they keep one page budget
+  cache: Arc<Mutex<PageCache>>,
+  // Shared copy of the cursor, so the loader can still report where in the
+  // file an error happened after the reader has been handed to wellen
+  read_position: Arc<AtomicU64>,
+  // Document handle to report read progress for with setprogress()
+  progress_handle: Option<u32>,
+  next_progress_report: u64,
 }
 
 impl WasmFileReader {
   fn new(fd: u32, file_size: u64) -> Self {
     //let file_size = getsize(fd);
     let read_callback = Arc::new(|fd, cursor, size| {fsread(fd, cursor, size)});
-    let reader = WasmFileReader { fd, file_size, cursor: 0, read_callback };
-    reader
+    let read_position = Arc::new(AtomicU64::new(0));
+    let cache = Arc::new(Mutex::new(PageCache::new()));
+    WasmFileReader { fd, file_size, cursor: 0, read_callback, cache, read_position, progress_handle: None, next_progress_report: 0 }
   }
-}
 
-#[derive(Deserialize, Debug)]
-pub struct VarData {
-  name: String,
-  id: u32,
-  signal_id: u32,
-  var_type: String,
-  encoding: String,
-  width: u32,
-  msb: i32,
-  lsb: i32,
-  enum_name: String,
-  param_value: Option<String>,
-}
+  fn with_progress(mut self, handle: u32) -> Self {
+    self.progress_handle = Some(handle);
+    self
+  }
 
-#[derive(Deserialize, Debug)]
-pub struct ScopeData {
-  name: String,
-  id: u32,
-  tpe: String,
+  fn with_cache(mut self, cache: Arc<Mutex<PageCache>>) -> Self {
+    self.cache = cache;
+    self
+  }
+
+  // Reports progress roughly every 1% of the file, so that the host isn't
+  // flooded with calls on small reads
+  fn report_progress(&mut self) {
+    if let Some(handle) = self.progress_handle {
+      if self.cursor >= self.next_progress_report {
+        setprogress(handle, self.cursor, self.file_size);
+        self.next_progress_report = self.cursor + max(self.file_size / 100, 1);
+      }
+    }
+  }
 }
 
-pub fn get_var_data(hierarchy: &Hierarchy, v: VarRef) -> VarData {
+pub fn get_var_data(hierarchy: &Hierarchy, v: VarRef, param_table: &Option<Vec<(u32, String)>>) -> VarItem {
 
   let variable = hierarchy.index(v);
-  let name = variable.name(&hierarchy).to_string();
-  let id = v.index() as u32;
-  let tpe = variable.var_type();
-  let var_type = format!("{:?}", tpe);
+  let name = variable.name(hierarchy).to_string();
+  let netlist_id = v.index() as u32;
+  let var_type = variable.var_type();
+  let tpe = format!("{:?}", var_type);
   let encoding = format!("{:?}", variable.signal_encoding(hierarchy));
   let width = variable.length(hierarchy).unwrap_or(0);
   let signal_ref = variable.signal_ref();
@@ -111,84 +140,81 @@ pub fn get_var_data(hierarchy: &Hierarchy, v: VarRef) -> VarData {
   let mut msb: i32 = -1;
   let mut lsb: i32 = -1;
   let bits = variable.index();
-  let enum_type = variable.enum_type(&hierarchy);
-  match bits {
-    Some(b) => {msb = b.msb() as i32; lsb = b.lsb() as i32;},
-    None => {}
-  }
+  let enum_type = variable.enum_type(hierarchy);
+  if let Some(b) = bits {msb = b.msb() as i32; lsb = b.lsb() as i32;}
 
-  let enum_name = match enum_type {
+  let enum_type = match enum_type {
     Some(e) => e.0.to_string(),
     None => "".to_string(),
   };
 
   let mut param_value: Option<String> = None;
-  if tpe == wellen::VarType::Parameter {
-    param_value = get_parameter_value(signal_id);
+  if var_type == wellen::VarType::Parameter {
+    param_value = get_parameter_value(param_table, signal_id);
     //log(&format!("Parameter {} value: {:?}", name, param_value));
   }
-  VarData { name, id, signal_id, var_type, encoding, width, msb, lsb, enum_name, param_value }
+  VarItem { name, netlist_id, signal_id, tpe, encoding, width, msb, lsb, enum_type, param_value }
 }
 
-pub fn get_scope_data(hierarchy: &Hierarchy, s: ScopeRef) -> ScopeData {
+pub fn get_scope_data(hierarchy: &Hierarchy, s: ScopeRef) -> ScopeItem {
   let scope = hierarchy.index(s);
-  let name = scope.name(&hierarchy).to_string();
+  let name = scope.name(hierarchy).to_string();
   let id = s.index() as u32;
   let tpe = format!("{:?}", scope.scope_type());
-  ScopeData { name, id, tpe }
-}
-
-fn load_parameters_and_signals(signal_id_list: Vec<SignalRef>, hierarchy: &Hierarchy, signal_source: &mut SignalSource) -> Vec<Signal> {
-  outputlog(&format!("Loading parameters and signals"));
-  let mut global_param_id_list = _param_id_list.lock().unwrap();
-  let param_id_list = global_param_id_list.as_ref().unwrap();
-
-  let all_signal_ids = [param_id_list.to_vec(), signal_id_list.clone()].concat();
-  let signal_data = signal_source.load_signals(&all_signal_ids, hierarchy, false);
-  let mut return_signals = Vec::new();
-  let mut param_table = Vec::new();
-
-  // Consume signal_data, process params immediately, keep return signals
-  for signal in signal_data {
-    let signal_ref = signal.signal_ref();
-    if param_id_list.contains(&signal_ref) {
-      let index = signal.get_first_time_idx();
-      let data_offset = index.and_then(|i| signal.get_offset(i));
-      if let Some(offset) = data_offset {
-        let value = signal.get_value_at(&offset, 0);
-        param_table.push((signal_ref.index() as u32, value.to_string()));
-      }
-    }
-    if signal_id_list.contains(&signal_ref) {
-      return_signals.push(signal);
-    }
-  }
+  ScopeItem { name, id, tpe }
+}
 
-  let mut global_param_table = _param_table.lock().unwrap();
-  *global_param_table = Some(param_table);
-  *global_param_id_list = None;
+// Rough number of bytes an item takes in a getchildren() result
+fn scope_item_size(scope: &ScopeItem) -> u32 {
+  (scope.name.len() + scope.tpe.len() + 16) as u32
+}
+
+fn var_item_size(var: &VarItem) -> u32 {
+  let param_length = var.param_value.as_ref().map_or(0, |value| value.len());
+  (var.name.len() + var.tpe.len() + var.encoding.len() + var.enum_type.len() + param_length + 48) as u32
+}
 
-  return_signals
+fn get_parameter_value(param_table: &Option<Vec<(u32, String)>>, signalid: u32) -> Option<String> {
+  param_table.as_ref()?.iter().find(|(id, _)| *id == signalid).map(|(_, value)| value.clone())
 }
 
-fn get_parameter_value(signalid: u32) -> Option<String> {
-  let global_param_table = _param_table.lock().unwrap();
-  let param_table = global_param_table.as_ref();
-  if param_table.is_none() {
-    return None;
+
+// Returns the chunk size and the end time (padded by one average time step)
+// that get sent to the host with setchunksize()
+pub fn get_chunk_size(time_table: &[u64]) -> (u64, u64) {
+  let event_count = time_table.len();
+  if event_count == 0 {return (1, 1);}
+
+  let time_end = time_table.last().copied().unwrap_or(0);
+  let time_extend = max(time_end.div_ceil(event_count as u64), 1);
+  let time_end_extend = time_end.saturating_add(time_extend);
+  //log(&format!("Event count: {:?}", event_count));
+  let min_timestamp = if event_count <= 128 {
+    time_end
   } else {
-    let param_table = param_table.unwrap();
-    for (id, value) in param_table.iter() {
-      if *id == signalid {
-        return Some(value.clone());
-      }
-    }
-  }
-  None
+    time_table.windows(129).map(|w| w[128].saturating_sub(w[0])).fold(9999999, std::cmp::min)
+  };
+  (min_timestamp / 128, time_end_extend)
+}
+
+/// The time of a time table index. wellen only hands out indices into the
+/// time table it builds, but a missing one counts as after every time
+/// instead of panicking, since a panic traps the whole instance.
+pub fn table_time(time_table: &[u64], index: u32) -> u64 {
+  time_table.get(index as usize).copied().unwrap_or(u64::MAX)
 }
 
+// Largest integer a JavaScript number holds exactly
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
 
-fn send_enum_data(name: &str, values: &str) {
+/// A time for JSON sent to the host. Times that a JavaScript number can't
+/// hold exactly are sent as decimal strings, so they don't lose precision
+/// when the host parses them.
+pub fn json_time(time: u64) -> String {
+  if time <= MAX_SAFE_INTEGER {time.to_string()} else {format!("\"{}\"", time)}
+}
+
+fn send_enum_data(handle: u32, name: &str, values: &str) {
   let max_return_length = 65000;
   let result_length = values.len();
   let chunk_count = (result_length as f32 / max_return_length as f32).ceil() as u32;
@@ -196,110 +222,7 @@ fn send_enum_data(name: &str, values: &str) {
     let start = i * max_return_length;
     let end = std::cmp::min((i + 1) * max_return_length, result_length as u32);
     let chunk = &values[start as usize..end as usize];
-    sendenumdata(name, chunk_count, i, chunk);
-  }
-}
-
-fn parse_value_change_data_json(signal: &Signal, time_index: &[u32], signalid: u32) {
-  let global_time_table = _time_table.lock().unwrap();
-  let time_table = global_time_table.as_ref().unwrap();
-  let transitions = signal.iter_changes();
-
-  let mut i: usize = 0;
-  let mut min: f64 = 0.0;
-  let mut max: f64 = 0.0;
-  let mut result = String::new();
-  result.push_str("[");
-  for (_, value) in transitions {
-    let v = value.to_string();
-    let time = time_table[time_index[i] as usize];
-    match value {
-      wellen::SignalValueRef::Real(v) => {
-        min = f64::min(min, v);
-        max = f64::max(max, v);
-      },
-      _ => {}
-    }
-    result.push_str(&format!("[{:?},{:?}],", time, v));
-    i += 1;
-  }
-  //log(&format!("Signal Data Orgainzed!"));
-  // set last character to "]" to close the array
-  if result.len() > 1 {result.pop();}
-  result.push_str("]");
-
-  // Fallback to uncompressed chunks
-  let max_return_length = 65000;
-  let result_length = result.len();
-  let chunk_count = (result_length as f32 / max_return_length as f32).ceil() as u32;
-  for i in 0..chunk_count {
-    let start = i * max_return_length;
-    let end = std::cmp::min((i + 1) * max_return_length, result_length as u32);
-    let chunk = &result[start as usize..end as usize];
-    sendtransitiondatachunk(signalid, chunk_count, i as u32, min, max, chunk);
-  }
-}
-
-fn parse_value_change_data_lz4(signal: &Signal, time_index: &[u32], signalid: u32) {
-  let global_time_table = _time_table.lock().unwrap();
-  let time_table = global_time_table.as_ref().unwrap();
-
-  let transitions = signal.iter_changes();
-
-  let mut i: usize = 0;
-  let mut min: f64 = 0.0;
-  let mut max: f64 = 0.0;
-  let mut result = Vec::<u8>::new();
-  let mut prev_time = 0;
-  let mut v: String = String::new();
-
-  for (_, value) in transitions {
-    v = value.to_string();
-    let time = time_table[time_index[i] as usize];
-    let delta_time = time - prev_time;
-    match value {
-      wellen::SignalValueRef::Real(v) => {
-        min = f64::min(min, v);
-        max = f64::max(max, v);
-      },
-      _ => {}
-    }
-    // store u64 as raw bytes (8 bytes in little-endian format)
-    let delta_time_bytes = delta_time.to_le_bytes();
-    result.extend_from_slice(&delta_time_bytes);
-    result.extend_from_slice(v.as_bytes());
-
-    prev_time = time;
-    i += 1;
-  }
-  let width = v.len() as u32;
-
-  // Try LZ4 compression first, fallback to uncompressed if compression doesn't help or fails
-  let original_size = result.len();
-
-  match std::panic::catch_unwind(|| {
-    let mut encoder = FrameEncoder::new(Vec::new());
-    encoder.write_all(&result).unwrap();
-    encoder.finish().unwrap()
-  }) {
-    Ok(compressed_data) => {
-
-      let max_chunk_size = 65000;
-      let compressed_length = compressed_data.len();
-      let chunk_count_float = (compressed_length as f32) / (max_chunk_size as f32);
-      let chunk_count = chunk_count_float.ceil() as u32;
-
-      for i in 0..chunk_count {
-        let start = i as usize * max_chunk_size;
-        let end = std::cmp::min((i + 1) as usize * max_chunk_size, compressed_length);
-        let chunk = &compressed_data[start..end];
-        sendcompressedtransitiondata(signalid, width, chunk_count, i as u32, min, max, chunk, original_size as u32);
-      }
-      return; // Exit early if compression was used
-    },
-    Err(_) => {
-      outputlog(&format!("LZ4 compression failed for signal {}, falling back to uncompressed", signalid));
-    }
+    sendenumdata(handle, name, chunk_count, i, chunk);
   }
 }
 
@@ -308,14 +231,25 @@ impl Read for WasmFileReader {
     //log(&format!("Reading data from offset: {:?}, size: {:?}", self.cursor, buf.len()));
 
     let mut bytes_read = 0;
-    let read_size = std::cmp::min(buf.len() as u32, self.file_size as u32 - self.cursor as u32) as usize;
+    let remaining = self.file_size.saturating_sub(self.cursor);
+    let read_size = std::cmp::min(buf.len() as u64, remaining) as usize;
+    let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
     while bytes_read < read_size {
-      let chunk_size = std::cmp::min(read_size - bytes_read, 32768);
-      let data = (self.read_callback)(self.fd, self.cursor, chunk_size as u32);
-      buf[bytes_read..bytes_read + chunk_size].copy_from_slice(&data);
+      let fd = self.fd;
+      let read_callback = &self.read_callback;
+      let page = cache.page(self.cursor / PAGE_SIZE, self.file_size, |offset, length| read_callback(fd, offset, length))?;
+      let page_offset = (self.cursor % PAGE_SIZE) as usize;
+      if page_offset >= page.len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Short read at offset {}", self.cursor)));
+      }
+      let chunk_size = std::cmp::min(read_size - bytes_read, page.len() - page_offset);
+      buf[bytes_read..bytes_read + chunk_size].copy_from_slice(&page[page_offset..page_offset + chunk_size]);
       self.cursor += chunk_size as u64;
       bytes_read += chunk_size;
     }
+    drop(cache);
+    self.read_position.store(self.cursor, Ordering::Relaxed);
+    self.report_progress();
     Ok(bytes_read)
   }
 
@@ -335,19 +269,22 @@ impl Read for WasmFileReader {
 impl Seek for WasmFileReader {
   fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
     //log(&format!("Seeking to: {:?}", pos));
-    let new_cursor;
-    match pos {
-      SeekFrom::Start(offset) => { new_cursor = offset; }
-      SeekFrom::End(offset) => { new_cursor = (self.file_size as i64 + offset) as u64; }
-      SeekFrom::Current(offset) => { new_cursor = (self.cursor as i64 + offset) as u64; }
-    }
-    if (new_cursor as i64) < 0 {
-      outputlog(&format!("Invalid seek to negative position: {:?}", new_cursor));
-      self.cursor = 0;
-      return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to negative position"));
+    let new_cursor = match pos {
+      SeekFrom::Start(offset) => Some(offset),
+      SeekFrom::End(offset) => self.file_size.checked_add_signed(offset),
+      SeekFrom::Current(offset) => self.cursor.checked_add_signed(offset),
+    };
+    match new_cursor {
+      Some(new_cursor) => {
+        self.cursor = std::cmp::min(new_cursor, self.file_size);
+        Ok(self.cursor)
+      },
+      None => {
+        outputlog(&format!("Invalid seek to negative position: {:?}", pos));
+        self.cursor = 0;
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to negative position"))
+      }
     }
-    self.cursor = std::cmp::min(new_cursor, self.file_size);
-    Ok(self.cursor)
   }
 
   fn rewind(&mut self) -> io::Result<()> {
@@ -360,89 +297,53 @@ impl Seek for WasmFileReader {
 
   fn seek_relative(&mut self, offset: i64) -> io::Result<()> {
     //log(&format!("Seeking relative: {:?}", offset));
-    let new_cursor = (self.cursor as i64 + offset) as i64;
-    if new_cursor < 0 {
-      outputlog(&format!("Invalid seek to negative position: {:?}", new_cursor));
-      self.cursor = 0;
-      return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to negative position"));
-    }
-    self.cursor = std::cmp::min(new_cursor, self.file_size as i64) as u64;
-    Ok(())
+    self.seek(SeekFrom::Current(offset)).map(|_| ())
   }
 }
 
-#[derive(serde::Serialize, Clone)]
-struct SearchEntry {
-  #[serde(rename = "instancePath")]
-  instance_path: String,
-  #[serde(rename = "type")]
-  item_type: String,
-  #[serde(rename = "isVar")]
-  is_var: bool,
-  #[serde(rename = "paramValue")]
-  param_value: String,
-  msb: i32,
-  lsb: i32,
-}
-
-#[derive(serde::Serialize)]
-struct SearchResult {
-  #[serde(rename = "totalResults")]
-  total_results: usize,
-  #[serde(rename = "searchResults")]
-  search_results: Vec<SearchEntry>,
-}
-
-fn var_search_entry(var_data: &Var, hierarchy: &Hierarchy) -> SearchEntry {
-  let param_value = get_parameter_value(var_data.signal_ref().index() as u32).unwrap_or_default();
-  let index = var_data.index();
-  // if index is Some, then get the msb and lsb
-  let (msb, lsb) = match index {
-    Some(i) => {(i.msb() as i32, i.lsb() as i32)},
-    None => {(-1, -1)}
-  };
-
-  return SearchEntry {
-    instance_path: var_data.full_name(hierarchy).to_string(),
-    item_type: format!("{:?}", var_data.var_type()),
-    is_var: true,
-    param_value: param_value,
-    msb: msb,
-    lsb: lsb,
-  };
+fn var_search_entry(v: VarRef, hierarchy: &Hierarchy, param_table: &Option<Vec<(u32, String)>>) -> SearchEntry {
+  SearchEntry {
+    instance_path: hierarchy.index(v).full_name(hierarchy).to_string(),
+    item: NetlistItem::Var(get_var_data(hierarchy, v, param_table)),
+  }
 }
 
-fn scope_search_entry(scope_data: &Scope, hierarchy: &Hierarchy) -> SearchEntry {
-  return SearchEntry {
-    instance_path: scope_data.full_name(hierarchy).to_string(),
-    item_type: format!("{:?}", scope_data.scope_type()),
-    is_var: false,
-    param_value: String::new(),
-    msb: -1,
-    lsb: -1,
-  };
+fn scope_search_entry(s: ScopeRef, hierarchy: &Hierarchy) -> SearchEntry {
+  SearchEntry {
+    instance_path: hierarchy.index(s).full_name(hierarchy).to_string(),
+    item: NetlistItem::Scope(get_scope_data(hierarchy, s)),
+  }
 }
 
-fn get_all_vars<'h>(hierarchy: &'h Hierarchy, scope: Option<&'h Scope>) -> Box<dyn Iterator<Item = &'h Var> + 'h> {
+fn get_all_vars<'h>(hierarchy: &'h Hierarchy, scope: Option<&'h Scope>, filter: &'h Option<HierarchyFilter>) -> Box<dyn Iterator<Item = VarRef> + 'h> {
   match scope {
-    None => Box::new(hierarchy.all_vars().map(move |v| hierarchy.index(v))),
-    Some(s) => Box::new(s.all_vars(hierarchy).map(move |v| hierarchy.index(v)))
+    None => Box::new(hierarchy.all_vars().filter(move |v| var_visible(filter, *v))),
+    Some(s) => Box::new(s.all_vars(hierarchy).filter(move |v| var_visible(filter, *v)))
   }
 }
 
-fn get_all_scopes<'h>(hierarchy: &'h Hierarchy, scope: Option<&'h Scope>) -> Box<dyn Iterator<Item = &'h Scope> + 'h> {
+fn get_all_scopes<'h>(hierarchy: &'h Hierarchy, scope: Option<&'h Scope>, filter: &'h Option<HierarchyFilter>) -> Box<dyn Iterator<Item = ScopeRef> + 'h> {
   match scope {
-    None => Box::new(hierarchy.all_scopes().map(move |s| hierarchy.index(s))),
-    Some(s) => Box::new(s.all_scopes(hierarchy).map(move |sr| hierarchy.index(sr)))
+    None => Box::new(hierarchy.all_scopes().filter(move |s| scope_visible(filter, *s))),
+    Some(s) => Box::new(s.all_scopes(hierarchy).filter(move |s| scope_visible(filter, *s)))
   }
 }
 
-fn search<'h>(
-  hierarchy: &'h Hierarchy,
+/// Estimate of the bytes held by a hierarchy, from its scopes and vars and
+/// their names, since wellen does not report it
+fn hierarchy_size(hierarchy: &Hierarchy) -> usize {
+  let scopes: usize = hierarchy.all_scopes().map(|s| std::mem::size_of::<Scope>() + hierarchy[s].name(hierarchy).len()).sum();
+  let vars: usize = hierarchy.all_vars().map(|v| std::mem::size_of::<wellen::Var>() + hierarchy[v].name(hierarchy).len()).sum();
+  scopes + vars
+}
+
+fn search(
+  hierarchy: &Hierarchy,
+  filter: &Option<HierarchyFilter>,
   scope_path: Vec<&str>,
-  iter_scopes: impl Iterator<Item = &'h Scope>,
-  iter_vars: Option<impl Iterator<Item = &'h Var>>,
-  search_results: &mut Vec<Item<'h>>,
+  iter_scopes: impl Iterator<Item = ScopeRef>,
+  iter_vars: Option<impl Iterator<Item = VarRef>>,
+  search_results: &mut Vec<ItemRef>,
 ) {
 
   if scope_path.is_empty() { return; }
@@ -450,73 +351,290 @@ fn search<'h>(
   let new_scope_path = scope_path[1..].to_vec();
   let search_depth = new_scope_path.len();
 
-  for scope_data in iter_scopes {
+  for scope_ref in iter_scopes {
+    let scope_data = hierarchy.index(scope_ref);
     let name = scope_data.name(hierarchy).to_string().to_lowercase();
     if name.contains(&search_string) {
       if search_depth == 0 {
-        search_results.push(Item::Scope(scope_data));
+        search_results.push(ItemRef::Scope(scope_ref));
       } else {
-        // Collect children before recursing to avoid overlapping borrows
         let search_scope_path = new_scope_path.clone();
-        let child_scopes: Vec<&'h Scope> = scope_data.scopes(hierarchy)
-          .map(|scope_ref| hierarchy.index(scope_ref))
-          .collect();
-        let mut child_vars: Option<Vec<&'h Var>> = None;
+        let child_scopes: Vec<ScopeRef> = scope_data.scopes(hierarchy)
+          .filter(|scope_ref| scope_visible(filter, *scope_ref)).collect();
+        let mut child_vars: Option<Vec<VarRef>> = None;
         if search_depth == 1 {
-          let var_iter: Vec<&'h Var> = scope_data.vars(hierarchy)
-            .map(|var_ref| hierarchy.index(var_ref))
-            .collect();
-          child_vars = Some(var_iter);
+          child_vars = Some(scope_data.vars(hierarchy).filter(|var_ref| var_visible(filter, *var_ref)).collect());
         }
-        search(hierarchy, search_scope_path, child_scopes.into_iter(), child_vars.map(|v| v.into_iter()), search_results);
+        search(hierarchy, filter, search_scope_path, child_scopes.into_iter(), child_vars.map(|v| v.into_iter()), search_results);
       }
     }
   }
 
   if search_depth > 0 { return; }
   if let Some(iter_vars) = iter_vars {
-    for var_data in iter_vars {
-      let name = var_data.name(hierarchy).to_string().to_lowercase();
+    for var_ref in iter_vars {
+      let name = hierarchy.index(var_ref).name(hierarchy).to_string().to_lowercase();
       if name.contains(&search_string) {
-        search_results.push(Item::Var(var_data));
+        search_results.push(ItemRef::Var(var_ref));
+      }
+    }
+  }
+}
+
+pub fn load_options() -> LoadOptions {
+  LoadOptions {
+    // The host gives the filehandler neither shared memory nor a way to
+    // spawn threads, so a wasm32-wasip1-threads build couldn't use them
+    multi_thread: false,
+    remove_scopes_with_empty_name: false,
+  }
+}
+
+// Signal ids from the host. Ids that don't exist get an empty response.
+fn signal_refs(handle: u32, signalidlist: &[u32]) -> Vec<SignalRef> {
+  let mut signal_ref_list: Vec<SignalRef> = Vec::new();
+  signalidlist.iter().for_each(|signalid| {
+
+    let signal_ref_option = SignalRef::from_index(*signalid as usize);
+    match signal_ref_option {
+      Some(s) => {signal_ref_list.push(s);},
+      None => {
+        outputlog(&format!("Signal not found: {}", signalid));
+        sendtransitiondatachunk(handle, *signalid, 1, 0, 0.0, 1.0, "[]");
       }
     }
+  });
+  signal_ref_list
+}
+
+fn signal_ref(signalid: u32) -> Result<SignalRef, LoadError> {
+  SignalRef::from_index(signalid as usize).ok_or_else(|| {
+    LoadError::new(ErrorKind::InvalidArgument, format!("Signal not found: {}", signalid))
+  })
+}
+
+// Sends the value changes of signals that are in the signal cache
+fn send_signal_data(handle: u32, document: &WaveformDocument, signal_ref_list: &[SignalRef]) -> Result<(), LoadError> {
+  let time_table = loaded(&document.time_table, "time table")?;
+  let time_window = document.time_window();
+  for signal in signal_ref_list.iter().filter_map(|signal_ref| document.signal_cache.get(*signal_ref)) {
+    let signalid = signal.signal_ref().index() as u32;
+    send_compressed(
+      |out| encode_transitions(signal, time_table, &time_window, document.time_indices, out),
+      |totalchunks, chunknum, chunk, summary| match summary {
+        Some(s) => sendcompressedtransitiondata(handle, signalid, s.width, totalchunks, chunknum, s.min, s.max, chunk, s.size as u32),
+        None => sendcompressedtransitiondata(handle, signalid, 0, totalchunks, chunknum, 0.0, 0.0, chunk, 0),
+      }
+    )?;
+  }
+  Ok(())
+}
+
+// Sends the metadata and top level of the hierarchy of a freshly loaded
+// document to the host
+fn send_hierarchy(handle: u32, document: &mut WaveformDocument) -> Result<(), LoadError> {
+  let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+  // loadfile() builds the filter before the body reader, which needs it
+  if document.filter.is_none() {
+    document.filter = HierarchyFilter::new(hierarchy, &document.settings);
+  }
+  let filter = &document.filter;
+
+  // Get Parameter Signal IDs
+  let signal_list = hierarchy.all_vars()
+    .filter(|var_ref| var_visible(filter, *var_ref))
+    .filter(|var_ref| { hierarchy.index(*var_ref).var_type() == wellen::VarType::Parameter })
+    .map(|var_ref| { hierarchy.index(var_ref).signal_ref() })
+    .collect::<Vec<SignalRef>>();
+
+  document.param_id_list = Some(signal_list);
+
+  // count the number of scopes and vars
+  let scope_count = hierarchy.all_scopes().filter(|s| scope_visible(filter, *s)).count() as u32;
+  let var_count = hierarchy.all_vars().filter(|v| var_visible(filter, *v)).count() as u32;
+  let time_scale_data = hierarchy.timescale();
+  let time_unit = match time_scale_data {
+    Some(scale) => {
+      match scale.unit {
+        TimescaleUnit::ZeptoSeconds => "zs".to_string(),
+        TimescaleUnit::AttoSeconds => "as".to_string(),
+        TimescaleUnit::FemtoSeconds => "fs".to_string(),
+        TimescaleUnit::PicoSeconds => "ps".to_string(),
+        TimescaleUnit::NanoSeconds => "ns".to_string(),
+        TimescaleUnit::MicroSeconds => "us".to_string(),
+        TimescaleUnit::MilliSeconds => "ms".to_string(),
+        TimescaleUnit::Seconds => "s".to_string(),
+        TimescaleUnit::Unknown => "s".to_string()
+      }
+    },
+    None => "s".to_string(),
+  };
+  let time_scale = match time_scale_data {
+    Some(scale) => scale.factor,
+    None => 1,
+  };
+  setmetadata(handle, scope_count, var_count, time_scale, time_unit.as_str());
+
+  for s in hierarchy.scopes().filter(|s| scope_visible(filter, *s)) {
+    let scope_data = get_scope_data(hierarchy, s);
+    setscopetop(handle, &scope_data.name, scope_data.id, &scope_data.tpe);
+  }
+
+  for v in hierarchy.vars().filter(|v| var_visible(filter, *v)) {
+    let var_data = get_var_data(hierarchy, v, &document.param_table);
+    setvartop(handle, &var_data.name, var_data.netlist_id, var_data.signal_id, &var_data.tpe, &var_data.encoding, var_data.width, var_data.msb, var_data.lsb, &var_data.enum_type);
+  }
+  Ok(())
+}
+
+fn decompress_error(e: io::Error) -> LoadError {
+  LoadError::new(ErrorKind::IoError, format!("Error decompressing file: {}", e))
+}
+
+// Sets up a VCD file to be parsed a step at a time by readbodystep().
+// `reader` reads the file from its start, the parser skips the header.
+fn vcd_body_reader(reader: WasmFileReader, compression: Option<Compression>, buffer_size: u32, hierarchy: &Hierarchy, window: &Option<TimeWindow>, filter: &Option<HierarchyFilter>) -> io::Result<VcdBodyReader> {
+  let read_position = reader.read_position.clone();
+  let reader: Box<dyn Read + Send> = match compression {
+    Some(c) => Box::new(DecompressReader::new(c, BufReader::with_capacity(buffer_size as usize, reader))?),
+    None => Box::new(reader),
+  };
+  let mut parser = VcdBodyParser::new(hierarchy, *window);
+  if let Some(filter) = filter {
+    parser = parser.keep_only(filter.kept_signals(hierarchy));
   }
+  Ok(VcdBodyReader::new(reader, read_position, parser))
+}
+
+// Parses the header of a file that is loaded into memory as a whole, after
+// decompression
+fn read_static_header(mut file: Vec<u8>, window: &Option<TimeWindow>) -> Result<HeaderResult<Cursor<Vec<u8>>>, LoadError> {
+  if let Some(window) = window {
+    truncate_vcd(&mut file, window);
+  }
+  read_header(Cursor::new(file), &load_options()).map_err(LoadError::from_wellen)
+}
+
+// Reads the header of a file loaded with loadbuffer(). The buffer can't be
+// loaded dynamically instead, so a buffer that decompresses to more than
+// max_size bytes is an error.
+fn read_buffer_header(file: Vec<u8>, compression: Option<Compression>, window: &Option<TimeWindow>, max_size: u64) -> Result<HeaderResult<Cursor<Vec<u8>>>, LoadError> {
+  let file = match compression {
+    Some(c) => match decompress_bounded(c, file, max_size).map_err(decompress_error)? {
+      Some(file) => file,
+      None => {return Err(LoadError::new(ErrorKind::InvalidArgument, format!("Decompressed buffer is over the static load limit of {} bytes", max_size)));}
+    },
+    None => file,
+  };
+  read_static_header(file, window)
 }
 
+// More buckets than pixel columns on any screen
+const MAX_SUMMARY_BUCKETS: u32 = 8192;
+
+// Version of the vaporview:fileparser package in wit/filehandler.wit
+const INTERFACE_VERSION: &str = "2.0.0";
+
+const FORMATS: [&str; 3] = ["vcd", "fst", "ghw"];
+const COMPRESSIONS: [&str; 3] = ["gzip", "zstd", "xz"];
+const FEATURES: [&str; 14] = [
+  "load-settings", "buffer-load", "read-body-step", "tail", "time-indices", "full-hierarchy", "region-load",
+  "sidecar-index", "signal-cache", "signal-queue", "transition-window", "signal-summary", "remote", "time-range",
+];
+
 struct Filecontext;
 
 impl Guest for Filecontext {
 
-  fn loadremotestatus(status_data: Vec<u8>) -> String {
-    libsurfer::SurferRemote::loadremotestatus(status_data)
+  fn getcapabilities() -> Capabilities {
+    Capabilities {
+      interface_version: INTERFACE_VERSION.to_string(),
+      formats: FORMATS.iter().map(|f| f.to_string()).collect(),
+      compressions: COMPRESSIONS.iter().map(|c| c.to_string()).collect(),
+      features: FEATURES.iter().map(|f| f.to_string()).collect(),
+    }
   }
 
-  fn loadremotechunk(chunk_type: u32, chunk_data: Vec<u8>, chunk_index: u32, total_chunks: u32) {
-    libsurfer::SurferRemote::loadremotechunk(chunk_type, chunk_data, chunk_index, total_chunks);
+  fn createdocument() -> u32 {
+    document::create_document()
   }
 
-  fn loadfile(size: u64, fd: u32, loadstatic: bool, buffersize: u32) {
+  fn closedocument(handle: u32) -> Result<(), LoadError> {
+    guard(|| document::remove_document(handle))
+  }
+
+  fn loadremotestatus(status_data: Vec<u8>) -> Result<String, LoadError> {
+    guard(|| libsurfer::SurferRemote::loadremotestatus(status_data))
+  }
+
+  fn loadremotechunk(handle: u32, chunk_type: u32, chunk_data: Vec<u8>, chunk_index: u32, total_chunks: u32) -> Result<(), LoadError> {
+    guard(|| libsurfer::SurferRemote::loadremotechunk(handle, chunk_type, chunk_data, chunk_index, total_chunks))
+  }
+
+  fn loadfile(handle: u32, size: u64, fd: u32, loadstatic: bool, buffersize: u32, maxstaticsize: u64) -> Result<(), LoadError> { guard(|| {
 
     //log(&format!("Loading file from bytes: {:?}", size));
 
-    let options = LoadOptions {
-      multi_thread: false, // WASM is currently single-threaded
-      remove_scopes_with_empty_name: false,
-    };
+    let options = load_options();
+    let settings = with_document(handle, |document| Ok(document.settings.clone()))?;
 
     let header_result: HeaderResultType;
-    let mut reader = WasmFileReader::new(fd, size);
+    let mut reader = WasmFileReader::new(fd, size).with_progress(handle);
+    let read_position = reader.read_position.clone();
+    let cache = reader.cache.clone();
+    let cache_counters = cache.lock().unwrap_or_else(PoisonError::into_inner).counters.clone();
+    // wellen's body continuation can't be parsed in steps, so VCD bodies
+    // are read again with a reader of their own that shares the page cache
+    let body_reader = || WasmFileReader::new(fd, size).with_progress(handle).with_cache(cache.clone());
+    let io_error = |e: io::Error| {
+      LoadError::new(ErrorKind::IoError, format!("Error reading file: {}", e))
+        .at_offset(Some(read_position.load(Ordering::Relaxed)))
+    };
 
-    if loadstatic {
+    let mut magic = [0; 6];
+    let magic_length = reader.read(&mut magic).map_err(io_error)?;
+    reader.rewind().map_err(io_error)?;
+    let compression = Compression::detect(&magic[..magic_length]);
+    if let Some(c) = compression {
+      outputlog(&format!("Loading {:?} compressed file", c));
+    }
+
+    // The whole file has to fit in one allocation to be loaded statically
+    let fits_in_memory = size <= maxstaticsize && usize::try_from(size).is_ok_and(|s| s <= isize::MAX as usize);
+    if loadstatic && !fits_in_memory {
+      outputlog(&format!("File size of {} bytes is over the static load limit of {} bytes, loading dynamically instead", size, maxstaticsize));
+    }
+
+    let static_file = if loadstatic && fits_in_memory {
       // Load a file statically into memory
       let mut file = vec![0; size as usize];
-      reader.read(&mut file).unwrap();
-      let file_reader = Cursor::new(file);
+      reader.read_exact(&mut file).map_err(io_error)?;
+      match compression {
+        Some(c) => {
+          // The limit applies to the decompressed size too
+          let decompressed = decompress_bounded(c, file, maxstaticsize).map_err(decompress_error)?;
+          if decompressed.is_none() {
+            outputlog(&format!("Decompressed file is over the static load limit of {} bytes, loading dynamically instead", maxstaticsize));
+            reader.rewind().map_err(io_error)?;
+          }
+          decompressed
+        },
+        None => Some(file),
+      }
+    } else {
+      None
+    };
+
+    if let Some(file) = static_file {
+      header_result = HeaderResultType::Static(read_static_header(file, &settings.time_window)?);
+    } else if let Some(c) = compression {
+      let compressed_reader = BufReader::with_capacity(buffersize as usize, reader);
+      let decompress_reader = DecompressReader::new(c, compressed_reader).map_err(io_error)?;
+      let file_reader = BufReader::with_capacity(buffersize as usize, decompress_reader);
       let result = read_header(file_reader, &options);
       header_result = match result {
-        Ok(header) => HeaderResultType::Static(header),
-        Err(e) => HeaderResultType::Err(e),
+        Ok(header) => HeaderResultType::Compressed(header),
+        Err(e) => {return Err(LoadError::from_wellen(e).at_offset(Some(read_position.load(Ordering::Relaxed))));}
       };
     } else {
       //let file_reader = BufReader::new(reader);
@@ -524,479 +642,686 @@ impl Guest for Filecontext {
       let result = read_header(file_reader, &options);
       header_result = match result {
         Ok(header) => HeaderResultType::Dynamic(header),
-        Err(e) => HeaderResultType::Err(e),
+        Err(e) => {return Err(LoadError::from_wellen(e).at_offset(Some(read_position.load(Ordering::Relaxed))));}
       };
     }
 
     //log(&format!("Done reading file data"));
 
-    //let mut contents = file_contents.lock().unwrap();
-    let mut global_hierarchy = _hierarchy.lock().unwrap();
-    let mut global_body = _body.lock().unwrap();
-    let mut global_file_format = _file_format.lock().unwrap();
-
-    match header_result {
-      HeaderResultType::Dynamic(header) => {
-        *global_hierarchy = Some(header.hierarchy);
-        *global_file_format = header.file_format;
-        *global_body = ReadBodyEnum::Dynamic(header.body);
-      },
-      HeaderResultType::Static(header) => {
-        *global_hierarchy = Some(header.hierarchy);
-        *global_file_format = header.file_format;
-        *global_body = ReadBodyEnum::Static(header.body);
-      },
-      HeaderResultType::Err(e) => {
-        outputlog(&format!("Error reading header: {:?}", e));
-        return;
+    with_document(handle, |document| {
+      document.clear();
+      document.file_size = size;
+      document.fd = Some(fd);
+      document.cache_counters = Some(cache_counters.clone());
+      document.compression = compression;
+
+      match header_result {
+        HeaderResultType::Dynamic(header) => {
+          document.body_start = size - header.body_len;
+          document.filter = HierarchyFilter::new(&header.hierarchy, &settings);
+          document.body = match header.file_format {
+            FileFormat::Vcd => ReadBodyEnum::Vcd(Box::new(vcd_body_reader(body_reader(), None, buffersize, &header.hierarchy, &settings.time_window, &document.filter).map_err(io_error)?)),
+            _ => ReadBodyEnum::Dynamic(header.body),
+          };
+          document.hierarchy = Some(header.hierarchy);
+          document.file_format = header.file_format;
+        },
+        HeaderResultType::Compressed(header) => {
+          // FST reads jump around the file, which would mean decompressing
+          // it over and over again
+          if header.file_format == FileFormat::Fst {
+            return Err(LoadError::new(ErrorKind::InvalidArgument, "Compressed FST files can only be loaded statically"));
+          }
+          document.filter = HierarchyFilter::new(&header.hierarchy, &settings);
+          document.body = match header.file_format {
+            FileFormat::Vcd => ReadBodyEnum::Vcd(Box::new(vcd_body_reader(body_reader(), compression, buffersize, &header.hierarchy, &settings.time_window, &document.filter).map_err(io_error)?)),
+            _ => ReadBodyEnum::Compressed(header.body),
+          };
+          document.hierarchy = Some(header.hierarchy);
+          document.file_format = header.file_format;
+        },
+        HeaderResultType::Static(header) => {
+          document.hierarchy = Some(header.hierarchy);
+          document.file_format = header.file_format;
+          document.body = ReadBodyEnum::Static(header.body);
+        },
       }
-    }
 
-    //log(&format!("Done loading File"));
-
-    let hierarchy = global_hierarchy.as_ref().unwrap();
-
-    // Get Parameter Signal IDs
-    let mut global_param_id_list = _param_id_list.lock().unwrap();
-    let signal_list = hierarchy.all_vars()
-      .filter(|var_ref| { hierarchy.index(*var_ref).var_type() == wellen::VarType::Parameter })
-      .map(|var_ref| { hierarchy.index(var_ref).signal_ref() })
-      .collect::<Vec<SignalRef>>();
-
-    *global_param_id_list = Some(signal_list);
-
-    // count the number of scopes and vars
-    let scope_count = hierarchy.all_scopes().count() as u32;
-    let var_count = hierarchy.all_vars().count() as u32;
-    let time_scale_data = hierarchy.timescale();
-    let time_unit = match time_scale_data {
-      Some(scale) => {
-        match scale.unit {
-          TimescaleUnit::ZeptoSeconds => "zs".to_string(),
-          TimescaleUnit::AttoSeconds => "as".to_string(),
-          TimescaleUnit::FemtoSeconds => "fs".to_string(),
-          TimescaleUnit::PicoSeconds => "ps".to_string(),
-          TimescaleUnit::NanoSeconds => "ns".to_string(),
-          TimescaleUnit::MicroSeconds => "us".to_string(),
-          TimescaleUnit::MilliSeconds => "ms".to_string(),
-          TimescaleUnit::Seconds => "s".to_string(),
-          TimescaleUnit::Unknown => "s".to_string()
-        }
-      },
-      None => "s".to_string(),
-    };
-    let time_scale = match time_scale_data {
-      Some(scale) => scale.factor,
-      None => 1,
-    } as u32;
-    setmetadata(scope_count, var_count, time_scale, time_unit.as_str());
-
-    for s in hierarchy.scopes() {
-      let scope_data = get_scope_data(&hierarchy, s);
-      setscopetop(&scope_data.name, scope_data.id, &scope_data.tpe);
-    }
+      //log(&format!("Done loading File"));
 
-    for v in hierarchy.vars() {
-      let var_data = get_var_data(&hierarchy, v);
-      setvartop(&var_data.name, var_data.id, var_data.signal_id, &var_data.var_type, &var_data.encoding, var_data.width, var_data.msb, var_data.lsb, &var_data.enum_name);
-    }
-  }
+      send_hierarchy(handle, document)
+    })
+  })}
 
-  fn readbody() {
-
-    //log(&format!("Reading body..."));
-
-    let global_hierarchy = _hierarchy.lock().unwrap();
-    let hierarchy = global_hierarchy.as_ref().unwrap();
-    let mut global_body = _body.lock().unwrap();
-    let mut global_time_table = _time_table.lock().unwrap();
-    let mut global_signal_source = _signal_source.lock().unwrap();
-    let body = std::mem::replace(&mut *global_body, ReadBodyEnum::None);
-    let body_result;
+  fn appendbuffer(handle: u32, data: Vec<u8>) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      document.buffer.extend_from_slice(&data);
+      Ok(())
+    }))
+  }
 
-    body_result = match body {
-      ReadBodyEnum::Dynamic(body) => {
-        read_body(body, hierarchy, None)
-      },
-      ReadBodyEnum::Static(body) => {
-        read_body(body, hierarchy, None)
-      },
-      ReadBodyEnum::None => {
-        Err(WellenError::FailedToLoad(FileFormat::Unknown, "No body found".to_string()))
+  fn loadbuffer(handle: u32, data: Vec<u8>, maxstaticsize: u64) -> Result<(), LoadError> { guard(|| {
+    let mut file = with_document(handle, |document| Ok(std::mem::take(&mut document.buffer)))?;
+    file.extend_from_slice(&data);
+    let size = file.len() as u64;
+    let compression = Compression::detect(&file);
+    let settings = with_document(handle, |document| Ok(document.settings.clone()))?;
+    let header = read_buffer_header(file, compression, &settings.time_window, maxstaticsize)?;
+
+    with_document(handle, |document| {
+      document.clear();
+      document.file_size = size;
+      document.compression = compression;
+      document.hierarchy = Some(header.hierarchy);
+      document.file_format = header.file_format;
+      document.body = ReadBodyEnum::Static(header.body);
+      send_hierarchy(handle, document)
+    })
+  })}
+
+  fn setloadsettings(handle: u32, settings: LoadSettings) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      if let Some(window) = &settings.time_window {
+        if window.start > window.end {
+          return Err(LoadError::new(ErrorKind::InvalidArgument, format!("Time window start {} is after its end {}", window.start, window.end)));
+        }
       }
-    };
+      document.settings = settings;
+      Ok(())
+    }))
+  }
 
-    //log(&format!("Done reading body"));
+  fn readbody(handle: u32) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| document.finish_body(handle)))
+  }
 
-    match body_result {
-      Ok(result) => {
-        *global_time_table = Some(result.time_table);
-        *global_signal_source = Some(result.source);
-      },
-      Err(e) => {
-        outputlog(&format!("Error reading body: {:?}", e));
-        return;
-      }
-    }
+  fn readbodystep(handle: u32, budget: u64) -> Result<ReadbodyStatus, LoadError> {
+    guard(|| with_document(handle, |document| document.read_body_step(handle, budget)))
+  }
 
-    let global_file_format = _file_format.lock().unwrap();
-    if *global_file_format != FileFormat::Fst {
-      load_parameters_and_signals(Vec::new(), hierarchy, &mut global_signal_source.as_mut().unwrap());
-    }
+  fn cancelload(handle: u32) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      document.cancel_load();
+      Ok(())
+    }))
+  }
 
-    let time_table = global_time_table.as_ref().unwrap();
-    let mut min_timestamp = 9999999;
-    let event_count = time_table.len();
-    let time_table_length = time_table.len(); 
-    let time_end = time_table[time_table_length - 1];
-    let time_extend = max((time_end as f32 / time_table_length as f32).ceil() as u64, 1);
-    let time_end_extend = time_end + time_extend;
-    //log(&format!("Event count: {:?}", event_count));
-    if event_count <= 128 {
-      min_timestamp = time_table[event_count - 1];
-    } else {
-      for i in 128..event_count {
-        let rolling_time_step = time_table[i] - time_table[i - 128];
-        min_timestamp = std::cmp::min(rolling_time_step, min_timestamp);
-      }
-    }
-    //log(&format!("Setting chunk size to: {:?}", min_timestamp));
-    // convert time_table_length to string with commas
+  fn setlivetail(handle: u32, enabled: bool) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      document.live_tail = enabled;
+      if !enabled {document.tail = None;}
+      Ok(())
+    }))
+  }
 
-    setchunksize(min_timestamp / 128, time_end_extend, time_table_length as u64);
+  fn tailfile(handle: u32, size: u64) -> Result<bool, LoadError> {
+    guard(|| with_document(handle, |document| document.tail(handle, size)))
+  }
 
-    // unload _body
-    *global_body = ReadBodyEnum::None;
+  fn setsignalcachebudget(handle: u32, bytes: u64) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      document.signal_cache.budget = bytes;
+      document.signal_cache.evict();
+      Ok(())
+    }))
   }
 
-  fn getparametervalues(signalidlist: Vec<u32>) -> String {
-    let mut result: Vec<(u32, String)> = Vec::new();
-    signalidlist.iter().for_each(|signalid| {
-      let param_value = get_parameter_value(*signalid);
-      if param_value.is_some() {
-        result.push((*signalid, param_value.unwrap()));
-      }
-    });
-    // convert result to JSON string
-    let result_string = serde_json::to_string(&result);
-    //log(result_string.as_ref().unwrap());
-    return result_string.unwrap_or("[]".to_string());
+  fn freesignals(handle: u32, signalidlist: Vec<u32>) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      signalidlist.iter().filter_map(|signalid| SignalRef::from_index(*signalid as usize)).for_each(|signal_ref| {
+        document.signal_cache.remove(signal_ref);
+      });
+      Ok(())
+    }))
   }
 
-  // returns a JSON string of the children of the given path
-  // Since WASM is limited to 64K memory, we need to limit the return size
-  // and allow the function to be called multiple times to get all the data
-  fn getchildren(id: u32, startindex: u32) -> String {
+  fn getmemoryusage(handle: u32) -> Result<MemoryUsage, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let time_table_size = document.time_table.as_ref().map_or(0, |t| t.len() * std::mem::size_of::<u64>());
+      Ok(MemoryUsage {
+        hierarchy: document.hierarchy.as_ref().map_or(0, hierarchy_size) as u64,
+        time_table: time_table_size as u64,
+        signal_cache: document.signal_cache.size(),
+        signal_cache_budget: document.signal_cache.budget,
+        signals: document.signal_cache.usage().into_iter().map(|(signal_id, bytes)| SignalMemory { signal_id, bytes }).collect(),
+      })
+    }))
+  }
 
-    let global_hierarchy = _hierarchy.lock().unwrap();
-    let hierarchy = global_hierarchy.as_ref().unwrap();
+  fn indexvcd(handle: u32, interval: u64) -> Result<Vec<u64>, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let fd = match (document.file_format, document.compression, document.fd, &document.body) {
+        (FileFormat::Vcd, None, Some(fd), ReadBodyEnum::Vcd(_)) => fd,
+        _ => {return Err(LoadError::new(ErrorKind::InvalidArgument, "Only uncompressed VCD files loaded dynamically can be indexed"));}
+      };
+      let index = VcdIndex::build(fd, document.file_size, document.body_start, interval)?;
+      let times = index.times();
+      outputlog(&format!("Indexed VCD body with {} checkpoints", times.len()));
+
+      // Regions are parsed from the index, the body is not read as a whole
+      document.body = ReadBodyEnum::None;
+      document.vcd_index = Some(index);
+      Ok(times)
+    }))
+  }
 
-    let parent_scope;
-    let parent = ScopeRef::from_index(id as usize);
-    match parent {
-      Some(parent_ref) => {parent_scope = hierarchy.index(parent_ref);},
-      None => {outputlog(&format!("No scopes found")); return "{\"scopes\": [], \"vars\": []}".to_string();}
+  fn loadregion(handle: u32, window: TimeWindow) -> Result<(), LoadError> { guard(|| {
+    if window.start > window.end {
+      return Err(LoadError::new(ErrorKind::InvalidArgument, "Region start is after its end"));
     }
+    let file = with_document(handle, |document| {
+      document.vcd_index.as_mut().ok_or_else(|| LoadError::not_loaded("VCD index"))?.region(&window)
+    })?;
+
+    // The region has the full header, so signal ids are the same as in the
+    // hierarchy that is already loaded
+    let header = read_header(Cursor::new(file), &load_options()).map_err(LoadError::from_wellen)?;
+    let body = read_body(header.body, &header.hierarchy, None).map_err(LoadError::from_wellen)?;
+
+    with_document(handle, |document| {
+      document.time_table = Some(body.time_table);
+      document.signal_source = Some(body.source);
+      document.signal_cache.clear();
+      document.region = Some(window);
+      document.cache_signals(&[])?;
+      document.send_chunk_size(handle)
+    })
+  })}
+
+  fn fingerprint(fd: u32, size: u64, stamp: u64) -> Result<u64, LoadError> {
+    guard(|| sidecar::fingerprint(fd, size, stamp))
+  }
 
-    //log(&format!("Parent Scope: {:?}", parent));
-
-    let max_return_length = 65000;
-    let mut result = String::from("{\"scopes\": [");
-    let mut index = 0;
-    let mut return_length = result.len() as u32;
-    let mut child_scopes_string: Vec<String> = Vec::new();
-    let mut items_returned = 0;
-    let child_scopes = parent_scope.scopes(&hierarchy);
-    let mut total_scopes = 0;
+  fn loadindex(handle: u32, fd: u32, size: u64, fingerprint: u64) -> Result<bool, LoadError> { guard(|| {
+    let contents = match sidecar::read_index(fd, size, fingerprint)? {
+      Some(contents) => contents,
+      None => {return Ok(false);}
+    };
+    outputlog("Loading from index file");
+    with_document(handle, |document| {
+      document.clear();
+      document.file_format = contents.file_format;
+      document.hierarchy = Some(contents.hierarchy);
+      document.time_table = Some(contents.time_table);
+      document.index = Some(contents.index);
+      send_hierarchy(handle, document)?;
+      document.finish_index(handle)?;
+      Ok(true)
+    })
+  })}
+
+  fn buildindex(handle: u32, fingerprint: u64) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      // A window or region load is missing the data outside of it, and a
+      // filtered load the signals it filtered out
+      if document.time_window().is_some() {
+        return Err(LoadError::new(ErrorKind::InvalidArgument, "Cannot build an index for a time window load"));
+      }
+      if document.filter.is_some() {
+        return Err(LoadError::new(ErrorKind::InvalidArgument, "Cannot build an index for a filtered load"));
+      }
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+      let time_table = loaded(&document.time_table, "time table")?;
+      loaded(&document.signal_source, "waveform body")?;
+      document.index_builder = Some(IndexBuilder::start(handle, fingerprint, document.file_format, hierarchy, time_table)?);
+      Ok(())
+    }))
+  }
 
-    for s in child_scopes {
-      total_scopes += 1;
-      if (index < startindex) || (return_length > max_return_length) {index+=1; continue;}
-      index+=1;
+  fn buildindexstep(handle: u32) -> Result<bool, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+      let signal_source = loaded_mut(&mut document.signal_source, "waveform body")?;
+      let builder = document.index_builder.as_mut().ok_or_else(|| {
+        LoadError::new(ErrorKind::NotLoaded, "No index is being built, call buildindex first")
+      })?;
+      let result = builder.step(hierarchy, signal_source);
+      // A failed index is abandoned, the host removes what was written
+      if !matches!(result, Ok(false)) {document.index_builder = None;}
+      result
+    }))
+  }
 
-      let scope_data = get_scope_data(&hierarchy, s);
-      let scope_string = format!("{{\"name\": {:?},\"id\": {:?},\"type\": {:?}}}", scope_data.name, scope_data.id, scope_data.tpe);
+  fn gettimerange(handle: u32) -> Result<TimeWindow, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let time_table = document.windowed_time_table()?;
+      Ok(TimeWindow {
+        start: time_table.first().copied().unwrap_or(0),
+        end: time_table.last().copied().unwrap_or(0),
+      })
+    }))
+  }
 
-      items_returned += 1;
-      return_length += (scope_string.len() as u32) + 1;
-      child_scopes_string.push(scope_string);
-    }
+  fn sendtimetable(handle: u32) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      let time_table = loaded(&document.time_table, "time table")?;
+      let length = time_table.len() as u64;
+      send_compressed(
+        |out| encode_time_table(time_table, out),
+        |totalchunks, chunknum, chunk, _| sendtimetablechunk(handle, totalchunks, chunknum, chunk, length)
+      )?;
+      document.time_indices = true;
+      Ok(())
+    }))
+  }
 
-    result.push_str(&child_scopes_string.join(","));
-    result.push_str("], \"vars\": [");
+  fn sendhierarchy(handle: u32) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+      send_compressed(
+        |out| encode_hierarchy(hierarchy, &document.filter, &document.param_table, out),
+        |totalchunks, chunknum, chunk, items| sendhierarchychunk(handle, totalchunks, chunknum, chunk, items.copied().unwrap_or(0))
+      )
+    }))
+  }
 
-    let child_vars = parent_scope.vars(&hierarchy);
-    let mut child_vars_string: Vec<String> = Vec::new();
-    let mut total_vars = 0;
+  fn getreaderstats(handle: u32) -> Result<ReaderStats, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let counters = loaded(&document.cache_counters, "file")?;
+      Ok(ReaderStats {
+        cache_hits: counters.hits.load(Ordering::Relaxed),
+        cache_misses: counters.misses.load(Ordering::Relaxed),
+        host_reads: counters.host_reads.load(Ordering::Relaxed),
+        bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+      })
+    }))
+  }
 
-    for v in child_vars {
-      total_vars += 1;
-      if (index < startindex) || (return_length > max_return_length) {index+=1; continue;}
-      index+=1;
+  fn getparametervalues(handle: u32, signalidlist: Vec<u32>) -> Result<Vec<ParameterValue>, LoadError> { guard(|| {
+    with_document(handle, |document| {
+      Ok(signalidlist.iter().filter_map(|signalid| {
+        get_parameter_value(&document.param_table, *signalid).map(|value| ParameterValue { signal_id: *signalid, value })
+      }).collect())
+    })
+  })}
 
-      let var_data = get_var_data(&hierarchy, v);
-      let param_value = match &var_data.param_value {
-        Some(v) => v.clone(),
-        None => "".to_string(),
-      };
-      let var_string = format!("{{\"name\": {:?},\"netlistId\": {:?},\"signalId\": {:?},\"type\": {:?},\"encoding\": {:?}, \"width\": {:?}, \"msb\": {:?}, \"lsb\": {:?}, \"enumType\": {:?}, \"paramValue\": {:?}}}", var_data.name, var_data.id, var_data.signal_id, var_data.var_type, var_data.encoding, var_data.width, var_data.msb, var_data.lsb, var_data.enum_name, param_value);
+  // returns the children of the given scope
+  // Since WASM is limited to 64K memory, we need to limit the return size
+  // and allow the function to be called multiple times to get all the data
+  fn getchildren(handle: u32, id: u32, startindex: u32) -> Result<ScopeChildren, LoadError> { guard(|| {
 
-      items_returned += 1;
-      return_length += (var_string.len() as u32) + 1;
-      child_vars_string.push(var_string);
-    }
+    with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
 
-    let total_items = total_scopes + total_vars;
-    let remaining_items = total_items - (items_returned + startindex);
+      let parent_scope = match ScopeRef::from_index(id as usize) {
+        Some(parent_ref) => hierarchy.index(parent_ref),
+        None => {return Err(LoadError::new(ErrorKind::InvalidArgument, format!("No scope with id {}", id)));}
+      };
 
-    result.push_str(&child_vars_string.join(","));
-    result.push_str(format!("],\"totalReturned\": {:?},\"remainingItems\": {:?}}}", items_returned, remaining_items).as_str());
-    result
-  }
+      //log(&format!("Parent Scope: {:?}", parent));
+
+      let max_return_length = 65000;
+      let mut index = 0;
+      let mut return_length = 0;
+      let mut scopes: Vec<ScopeItem> = Vec::new();
+      let mut items_returned = 0;
+      let child_scopes = parent_scope.scopes(hierarchy).filter(|s| scope_visible(&document.filter, *s));
+      let mut total_scopes = 0;
+
+      for s in child_scopes {
+        total_scopes += 1;
+        if (index < startindex) || (return_length > max_return_length) {index+=1; continue;}
+        index+=1;
+
+        let scope_data = get_scope_data(hierarchy, s);
+        items_returned += 1;
+        return_length += scope_item_size(&scope_data);
+        scopes.push(scope_data);
+      }
 
-  fn getsignaldata(signalidlist: Vec<u32>) {
-    //log(&format!("Getting signal data for signal: {:?}", signalid));
+      let child_vars = parent_scope.vars(hierarchy).filter(|v| var_visible(&document.filter, *v));
+      let mut vars: Vec<VarItem> = Vec::new();
+      let mut total_vars = 0;
 
-    let global_param_id_list = _param_id_list.lock().unwrap();
-    let param_id_list = global_param_id_list.as_ref();
+      for v in child_vars {
+        total_vars += 1;
+        if (index < startindex) || (return_length > max_return_length) {index+=1; continue;}
+        index+=1;
 
-    let global_hierarchy = _hierarchy.lock().unwrap();
-    let hierarchy = global_hierarchy.as_ref().unwrap();
+        let var_data = get_var_data(hierarchy, v, &document.param_table);
+        items_returned += 1;
+        return_length += var_item_size(&var_data);
+        vars.push(var_data);
+      }
 
-    let mut global_signal_source = _signal_source.lock().unwrap();
-    let signal_source = global_signal_source.as_mut().unwrap();
+      let total_items = total_scopes + total_vars;
+      let remaining_items = total_items - std::cmp::min(total_items, items_returned + startindex);
 
-    let mut signal_ref_list: Vec<SignalRef> = Vec::new();
-    signalidlist.iter().for_each(|signalid| {
+      Ok(ScopeChildren { scopes, vars, total_returned: items_returned, remaining_items })
+    })
+  })}
 
-      let signal_ref_option = SignalRef::from_index(*signalid as usize);
-      match signal_ref_option {
-        Some(s) => {signal_ref_list.push(s);},
+  fn getchildrenpage(handle: u32, id: u32, order: String, token: Option<u64>) -> Result<ChildrenPage, LoadError> { guard(|| {
+    with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+      let token = match token {
+        Some(token) => token,
         None => {
-          outputlog(&format!("Signal not found: {}", signalid));
-          sendtransitiondatachunk(*signalid, 1, 0, 0.0, 1.0, "[]");
-          return;
+          let scope = ScopeRef::from_index(id as usize)
+            .ok_or_else(|| LoadError::new(ErrorKind::InvalidArgument, format!("No scope with id {}", id)))?;
+          document.child_pages.start(hierarchy, &document.filter, scope, ChildOrder::parse(&order)?)
+        },
+      };
+
+      let max_return_length = 65000;
+      let mut return_length = 0;
+      let mut scopes: Vec<ScopeItem> = Vec::new();
+      let mut vars: Vec<VarItem> = Vec::new();
+      let children = document.child_pages.children(token)?;
+      for child in children {
+        if return_length > max_return_length {break;}
+        match child {
+          ItemRef::Scope(s) => {
+            let scope_data = get_scope_data(hierarchy, *s);
+            return_length += scope_item_size(&scope_data);
+            scopes.push(scope_data);
+          },
+          ItemRef::Var(v) => {
+            let var_data = get_var_data(hierarchy, *v, &document.param_table);
+            return_length += var_item_size(&var_data);
+            vars.push(var_data);
+          },
         }
       }
-    });
-
-    let parameters_loaded = param_id_list.is_none();
-    drop(global_param_id_list);
 
-    // load_signals() is a potentially expensive operation, so we want to batch them together
-    // if the parameters are not loaded, we load them with the signals
-    let signals_loaded: Vec<Signal>;
-    if parameters_loaded {
-      signals_loaded = signal_source.load_signals(&signal_ref_list, hierarchy, false);
-    } else {
-      signals_loaded = load_parameters_and_signals(signal_ref_list, hierarchy, signal_source);
-    }
-    signals_loaded.iter().for_each(|signal| {
-      let signal_ref = signal.signal_ref();
-      let signalid = signal_ref.index() as u32;
-      let time_index = signal.time_indices();
-      let value_changes = time_index.len();
-      let data_offset = signal.get_offset(0);
-
-      // Find out if the signal data is a real or string
-      let width = match data_offset {
-        Some(offset) => {
-          let value = signal.get_value_at(&offset, 0);
-          let bits = value.width();
-          match bits {
-            Some(b) => b,
-            None => 0,
-          }
-        }, None => 0
-      };
-      let vc_data_size = (value_changes as u32) * (width + 8);
-      // We only want to use compression on bit vectors with lots of value changes
-      let use_compression = (width > 0) && (vc_data_size > 65000);
+      let returned = (scopes.len() + vars.len()) as u32;
+      let remaining_items = children.len() as u32 - returned;
+      let next_token = document.child_pages.advance(token, returned);
+      Ok(ChildrenPage { scopes, vars, next_token, remaining_items })
+    })
+  })}
 
-      if use_compression {
-        parse_value_change_data_lz4(signal, &time_index, signalid);
-      } else {
-        parse_value_change_data_json(signal, &time_index, signalid);
-      }
-      //log(&format!("Signal Data Sent!"));
-    });
+  fn getsignaldata(handle: u32, signalidlist: Vec<u32>) -> Result<(), LoadError> { guard(|| {
+    //log(&format!("Getting signal data for signal: {:?}", signalid));
 
+    with_document(handle, |document| {
+      let signal_ref_list = signal_refs(handle, &signalidlist);
+
+      // load_signals() is a potentially expensive operation, so we want to batch them together
+      // if the parameters are not loaded, we load them with the signals
+      document.cache_signals(&signal_ref_list)?;
+      send_signal_data(handle, document, &signal_ref_list)
+    })
+  })}
+
+  fn queuesignals(handle: u32, requestid: u32, signalidlist: Vec<u32>, priority: u32) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      let signal_ref_list = signal_refs(handle, &signalidlist);
+      // Ids that don't exist were answered right away
+      signalidlist.iter().filter(|signalid| SignalRef::from_index(**signalid as usize).is_none()).for_each(|signalid| {
+        signalloaded(handle, requestid, *signalid);
+      });
+      document.signal_queue.push(requestid, &signal_ref_list, priority);
+      Ok(())
+    }))
   }
 
-  fn getenumdata(netlistidlist: Vec<u32>) {
-    let global_hierarchy = _hierarchy.lock().unwrap();
-    let hierarchy = global_hierarchy.as_ref().unwrap();
+  fn cancelsignalrequest(handle: u32, requestid: u32) -> Result<u32, LoadError> {
+    guard(|| with_document(handle, |document| Ok(document.signal_queue.cancel(requestid))))
+  }
 
-    //log(&format!("Getting enum data for netlist IDs: {:?}", netlistidlist));
+  fn loadqueuedsignals(handle: u32, maxsignals: u32) -> Result<u32, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let batch = document.signal_queue.next_batch(maxsignals as usize);
+      let mut signal_ref_list: Vec<SignalRef> = batch.iter().map(|(_, signal_ref)| *signal_ref).collect();
+      // Requests can share a signal, it is only sent once
+      signal_ref_list.sort();
+      signal_ref_list.dedup();
+
+      document.cache_signals(&signal_ref_list)?;
+      send_signal_data(handle, document, &signal_ref_list)?;
+      batch.iter().for_each(|(request_id, signal_ref)| {
+        signalloaded(handle, *request_id, signal_ref.index() as u32);
+      });
+      Ok(document.signal_queue.len() as u32)
+    }))
+  }
 
-    netlistidlist.iter().for_each(|netlistid| {
-      let var_ref_option = VarRef::from_index(*netlistid as usize);
-      match var_ref_option {
-        Some(var_ref) => {
-          let variable = hierarchy.index(var_ref);
-          let enum_data = variable.enum_type(&hierarchy);
-          match enum_data {
-            Some(data) => {
-              let name = data.0.to_string();
-              let values = data.1;
-              serde_json::to_string(&values).map_or_else(
-                |err| {outputlog(&format!("Error serializing enum values for {}: {:?}", name, err));},
-                |json| {send_enum_data(&name, &json);}
-              );
-            },
-            None => {return;}
-          }
-        },
-        None => {return;}
+  fn gettransitions(handle: u32, signalid: u32, starttime: u64, endtime: u64) -> Result<String, LoadError> {
+    guard(|| with_document(handle, |document| {
+      if starttime > endtime {
+        return Err(LoadError::new(ErrorKind::InvalidArgument, "Start time is after end time"));
       }
-  
-    });
+      let signal_ref = signal_ref(signalid)?;
+      // Nothing outside of the time window is sent
+      let (starttime, endtime) = match document.time_window() {
+        Some(window) => (std::cmp::max(starttime, window.start), std::cmp::min(endtime, window.end)),
+        None => (starttime, endtime),
+      };
+      if starttime > endtime {return Ok("[]".to_string());}
+      document.cache_signals(&[signal_ref])?;
+      let time_table = loaded(&document.time_table, "time table")?;
+      Ok(document.signal_cache.get(signal_ref).map_or_else(
+        || "[]".to_string(),
+        |signal| transitions_in_range(signal, time_table, starttime, endtime)
+      ))
+    }))
   }
 
-  fn getvaluesattime(time: u64, paths: String) -> String {
+  fn getnexttransitions(handle: u32, signalid: u32, time: u64, count: u32) -> Result<String, LoadError> {
+    guard(|| with_document(handle, |document| {
+      let signal_ref = signal_ref(signalid)?;
+      let window = document.time_window();
+      document.cache_signals(&[signal_ref])?;
+      let time_table = loaded(&document.time_table, "time table")?;
+      Ok(document.signal_cache.get(signal_ref).map_or_else(
+        || "[]".to_string(),
+        |signal| transitions_after(signal, time_table, time, count, &window)
+      ))
+    }))
+  }
 
-    let mut global_signal_source = _signal_source.lock().unwrap();
-    let signal_source = global_signal_source.as_mut().unwrap();
+  fn getsignalsummary(handle: u32, signalid: u32, starttime: u64, endtime: u64, buckets: u32) -> Result<String, LoadError> {
+    guard(|| with_document(handle, |document| {
+      if starttime > endtime || buckets == 0 {
+        return Err(LoadError::new(ErrorKind::InvalidArgument, "Summary needs a time range and at least one bucket"));
+      }
+      let signal_ref = signal_ref(signalid)?;
+      // Nothing outside of the time window is summarized
+      let (starttime, endtime) = match document.time_window() {
+        Some(window) => (std::cmp::max(starttime, window.start), std::cmp::min(endtime, window.end)),
+        None => (starttime, endtime),
+      };
+      if starttime > endtime {return Ok("[]".to_string());}
+      // A bucket is at least one time step wide
+      let time_steps = (endtime - starttime).saturating_add(1);
+      let buckets = std::cmp::min(std::cmp::min(buckets, MAX_SUMMARY_BUCKETS) as u64, time_steps) as u32;
+      document.cache_signals(&[signal_ref])?;
+      let time_table = loaded(&document.time_table, "time table")?;
+      let result = document.signal_cache.get_with_lod(signal_ref).map_or_else(
+        || "[]".to_string(),
+        |(signal, lod)| lod.buckets(signal, time_table, starttime, endtime, buckets)
+      );
+      // Building the summaries can take the cache over budget
+      document.signal_cache.evict();
+      Ok(result)
+    }))
+  }
 
-    let global_hierarchy = _hierarchy.lock().unwrap();
-    let hierarchy = global_hierarchy.as_ref().unwrap();
+  fn getenumdata(handle: u32, netlistidlist: Vec<u32>) -> Result<(), LoadError> { guard(|| {
+    with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
 
-    let global_time_table = _time_table.lock().unwrap();
-    let time_table = global_time_table.as_ref().unwrap();
+      //log(&format!("Getting enum data for netlist IDs: {:?}", netlistidlist));
 
-    let mut signal_ref_list: Vec<SignalRef> = Vec::new();
-    let mut result_struct: Vec<(String, SignalRef)> = Vec::new();
+      netlistidlist.iter().for_each(|netlistid| {
+        let var_ref_option = VarRef::from_index(*netlistid as usize);
+        if let Some(var_ref) = var_ref_option {
+          let variable = hierarchy.index(var_ref);
+          if let Some(data) = variable.enum_type(hierarchy) {
+            let name = data.0.to_string();
+            let values = data.1;
+            serde_json::to_string(&values).map_or_else(
+              |err| {outputlog(&format!("Error serializing enum values for {}: {:?}", name, err));},
+              |json| {send_enum_data(handle, &name, &json);}
+            );
+          }
+        }
 
-    let path_list = paths.split(" ").collect::<Vec<&str>>();
-    path_list.iter().for_each(|path| {
-      let path_parts: Vec<&str> = path.split('.').collect();
-      let name = path_parts.last().unwrap();
-      let scope_path = &path_parts[0..path_parts.len() - 1];
-      let var_ref_option = hierarchy.lookup_var(&scope_path, name);
-      match var_ref_option {
-        Some(s) => {
+      });
+      Ok(())
+    })
+  })}
+
+  fn getvaluesattime(handle: u32, time: u64, paths: String) -> Result<Vec<ValueAtTime>, LoadError> { guard(|| {
+
+    with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+
+      let mut signal_ref_list: Vec<SignalRef> = Vec::new();
+      let mut result_struct: Vec<(String, SignalRef)> = Vec::new();
+
+      let path_list = paths.split(" ").collect::<Vec<&str>>();
+      path_list.iter().for_each(|path| {
+        let (scope_path, name) = match path.rsplit_once('.') {
+          Some((scope_path, name)) => (scope_path.split('.').collect::<Vec<&str>>(), name),
+          None => (Vec::new(), *path),
+        };
+        let var_ref_option = hierarchy.lookup_var(&scope_path, name);
+        if let Some(s) = var_ref_option {
           let var = hierarchy.index(s);
           let signal_ref = var.signal_ref();
           signal_ref_list.push(signal_ref);
           result_struct.push((path.to_string(), signal_ref));
-        },
-        None => {return;}
-      }
-    });
-
-    //log(&format!("Signal Ref List: {:?}", signal_ref_list));
-
-    let signals_loaded = signal_source.load_signals(&signal_ref_list, hierarchy, false);
-
-    let mut result = String::new();
-    result.push_str("[");
-    signals_loaded.iter().for_each(|signal| {
-      let s = signal.signal_ref();
-      let transitions = signal.iter_changes();
-      let time_index = signal.time_indices();
-
-      //log(&format!("Total Time Indices: {:?}", time_index.len()));
-      let mut i: usize = 0;
-      let mut v = "[]".to_string();
-      let mut last_value = None;
-
-      for (_, value) in transitions {
-          let current_time = time_table[time_index[i] as usize];
-          if current_time > time {
-              if let Some(ref last) = last_value {
-                  v = format!("[\"{}\"]", last);
-              }
-              break;
-          }
-          if current_time == time {
-              if let Some(ref last) = last_value {
-                v = format!("[\"{}\"", last);
-              }
-              v.push_str(&format!(",\"{}\"]", value.to_string()));
-              break;
-          }
-          last_value = Some(value.to_string());
-          i += 1;
-      }
-
-      if v == "[]" && last_value.is_some() {
-          v = format!("[\"{}\"]", last_value.unwrap());
-      }
-
-      result_struct.iter().for_each(|(path, signalid)| {
-        if s.index() == signalid.index() {
-          result.push_str(&format!("{{\"instancePath\": {:?}, \"value\": {:?}}},", path, v));
         }
       });
 
-      // Send the data in chunks
-    });
-    
-    if result.len() > 1 {result.pop();}
-    result.push_str("]");
-    return result;
+      //log(&format!("Signal Ref List: {:?}", signal_ref_list));
+
+      // Paths can share a signal
+      signal_ref_list.sort();
+      signal_ref_list.dedup();
+      document.cache_signals(&signal_ref_list)?;
+      let time_table = loaded(&document.time_table, "time table")?;
+
+      let mut result: Vec<ValueAtTime> = Vec::new();
+      signal_ref_list.iter().filter_map(|signal_ref| document.signal_cache.get(*signal_ref)).for_each(|signal| {
+        let s = signal.signal_ref();
+        let transitions = signal.iter_changes();
+
+        let mut values: Vec<String> = Vec::new();
+        let mut last_value = None;
+
+        for (time_table_index, value) in transitions {
+            let current_time = table_time(time_table, time_table_index);
+            if current_time > time {break;}
+            if current_time == time {
+                values.extend(last_value.take());
+                values.push(value.to_string());
+                break;
+            }
+            last_value = Some(value.to_string());
+        }
 
-  }
+        if values.is_empty() {
+          values.extend(last_value);
+        }
+
+        result_struct.iter().for_each(|(path, signalid)| {
+          if s.index() == signalid.index() {
+            result.push(ValueAtTime { instance_path: path.clone(), values: values.clone() });
+          }
+        });
+      });
 
-  fn searchnetlist(searchquery: String, scopeid: u32) -> String {
-    let global_hierarchy = _hierarchy.lock().unwrap();
-    let hierarchy = global_hierarchy.as_ref().unwrap();
-    let empty_result = "{\"totalResults\":0,\"searchResults\":[]}".to_string();
+      Ok(result)
+    })
+  })}
 
+  fn searchnetlist(handle: u32, searchquery: String, scopeid: u32) -> Result<SearchResult, LoadError> { guard(|| {
     if searchquery.is_empty() {
-      return empty_result;
+      return Ok(SearchResult { total_results: 0, search_results: Vec::new() });
     }
 
-    let mut search_scope = None::<&Scope>;
-    if scopeid != 0xFFFFFFFF {
-      let search_scope_id_option = ScopeRef::from_index(scopeid as usize);
-      match search_scope_id_option {
-        Some(search_scope_id) => {search_scope = Some(hierarchy.index(search_scope_id));},
-        None => {search_scope = None;}
+    with_document(handle, |document| {
+      let hierarchy = loaded(&document.hierarchy, "hierarchy")?;
+
+      let mut search_scope = None::<&Scope>;
+      if scopeid != 0xFFFFFFFF {
+        let search_scope_id_option = ScopeRef::from_index(scopeid as usize);
+        match search_scope_id_option {
+          Some(search_scope_id) => {search_scope = Some(hierarchy.index(search_scope_id));},
+          None => {search_scope = None;}
+        }
       }
-    }
 
-    let mut search_results: Vec<Item> = Vec::new();
-    let lower_query = searchquery.to_lowercase();
-    let scope_path = lower_query.split(".").collect::<Vec<&str>>();
+      let mut search_results: Vec<ItemRef> = Vec::new();
+      let lower_query = searchquery.to_lowercase();
+      let scope_path = lower_query.split(".").collect::<Vec<&str>>();
 
-    let all_scopes = get_all_scopes(hierarchy, search_scope);
-    //let all_scopes = hierarchy.all_scopes();
-    let mut all_vars = None;
-    if scope_path.len() == 1 {
-      all_vars = Some(get_all_vars(hierarchy, search_scope));
-      //all_vars = Some(hierarchy.all_vars());
-    }
-
-    search(hierarchy, scope_path, all_scopes, all_vars, &mut search_results);
+      let all_scopes = get_all_scopes(hierarchy, search_scope, &document.filter);
+      //let all_scopes = hierarchy.all_scopes();
+      let mut all_vars = None;
+      if scope_path.len() == 1 {
+        all_vars = Some(get_all_vars(hierarchy, search_scope, &document.filter));
+        //all_vars = Some(hierarchy.all_vars());
+      }
 
-    let total = search_results.len();
-    let return_amount = std::cmp::min(total, 100);
-    let results_slice = search_results.iter()
-      .take(return_amount)
-      .map(|s| {
-        match s {
-          Item::Scope(s) => {scope_search_entry(s, hierarchy)},
-          Item::Var(v) => {var_search_entry(v, hierarchy)},
-        }
-      }).collect::<Vec<SearchEntry>>();
-    let result: SearchResult = SearchResult { total_results: total, search_results: results_slice };
-    serde_json::to_string(&result).unwrap_or_else(|_| empty_result)
-  }
+      search(hierarchy, &document.filter, scope_path, all_scopes, all_vars, &mut search_results);
 
-  fn unload() {
-    let mut global_signal_source = _signal_source.lock().unwrap();
-    let mut global_time_table = _time_table.lock().unwrap();
-    let mut global_body = _body.lock().unwrap();
-    let mut global_hierarchy = _hierarchy.lock().unwrap();
-    let mut global_file_format = _file_format.lock().unwrap();
-    *global_signal_source = None;
-    *global_time_table = None;
-    *global_body = ReadBodyEnum::None;
-    *global_hierarchy = None;
-    *global_file_format = FileFormat::Unknown;
+      let total = search_results.len();
+      let return_amount = std::cmp::min(total, 100);
+      let results_slice = search_results.iter()
+        .take(return_amount)
+        .map(|s| {
+          match s {
+            ItemRef::Scope(s) => {scope_search_entry(*s, hierarchy)},
+            ItemRef::Var(v) => {var_search_entry(*v, hierarchy, &document.param_table)},
+          }
+        }).collect::<Vec<SearchEntry>>();
+      Ok(SearchResult { total_results: total as u32, search_results: results_slice })
+    })
+  })}
+
+  fn unload(handle: u32) -> Result<(), LoadError> {
+    guard(|| with_document(handle, |document| {
+      document.clear();
+      Ok(())
+    }))
   }
 }
 
 // Export the Filecontext to the extension code.
 export!(Filecontext);
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json_time_quotes_times_past_2_53() {
+    assert_eq!(json_time(0), "0");
+    assert_eq!(json_time(MAX_SAFE_INTEGER), "9007199254740991");
+    assert_eq!(json_time(MAX_SAFE_INTEGER + 1), "\"9007199254740992\"");
+    assert_eq!(json_time(u64::MAX), "\"18446744073709551615\"");
+  }
+
+  #[test]
+  fn reads_a_compressed_buffer_up_to_the_window() {
+    let file = test_util::vcd("#0\n0!\n#5\n1!\n#10\n0!\n");
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    io::Write::write_all(&mut encoder, file.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let compression = Compression::detect(&compressed);
+    assert_eq!(compression, Some(Compression::Gzip));
+
+    let header = read_buffer_header(compressed.clone(), compression, &Some(TimeWindow { start: 0, end: 6 }), file.len() as u64).unwrap();
+    assert!(header.hierarchy.lookup_var(&["top"], "clk").is_some());
+    let body = read_body(header.body, &header.hierarchy, None).unwrap();
+    assert_eq!(body.time_table, [0, 5]);
+
+    let error = read_buffer_header(compressed, compression, &None, file.len() as u64 - 1).err().unwrap();
+    assert_eq!(error.kind, ErrorKind::InvalidArgument);
+  }
+
+  #[test]
+  fn rejects_a_buffer_that_is_not_a_waveform() {
+    assert!(read_buffer_header(b"not a waveform".to_vec(), None, &None, u64::MAX).is_err());
+  }
+
+  #[test]
+  fn chunk_size_near_u64_max() {
+    let time_table: Vec<u64> = (0..200).map(|i| u64::MAX - 199 + i).collect();
+    assert_eq!(get_chunk_size(&time_table), (1, u64::MAX));
+  }
+}
@@ -1,7 +1,9 @@
 use wellen::{FileFormat, Hierarchy, TimescaleUnit, CompressedTimeTable};
 use bincode::Options;
 
-use crate::{BINCODE_OPTIONS, _hierarchy, _file_format, _time_table, _chunks, _total_chunks, get_scope_data, get_var_data, outputlog, setmetadata, setscopetop, setvartop, setchunksize, sendtransitiondatachunk, SurferStatus};
+use crate::{BINCODE_OPTIONS, get_chunk_size, json_time, table_time, get_scope_data, get_var_data, outputlog, setmetadata, setscopetop, setvartop, setchunksize, sendtransitiondatachunk, SurferStatus};
+use crate::document::with_document;
+use crate::error::{ErrorKind, LoadError};
 
 const MAX_CHUNK_SIZE: u32 = 1024 * 32;
 
@@ -21,60 +23,70 @@ pub struct SurferRemote;
  */
 
 impl SurferRemote {
-  fn handle_chunk(chunk_type: ChunkType, chunk_data: Vec<u8>, chunk_index: u32, total_chunks: u32) {
+  fn handle_chunk(handle: u32, chunk_type: ChunkType, chunk_data: Vec<u8>, chunk_index: u32, total_chunks: u32) -> Result<(), LoadError> {
     let process_fn = match chunk_type {
       ChunkType::Hierarchy => Self::loadremotehierarchy,
       ChunkType::TimeTable => Self::loadremotetimetable,
       ChunkType::Signals => Self::loadremotesignals,
     };
     
-    Self::handle_chunk_impl(process_fn, chunk_data, chunk_index, total_chunks);
+    Self::handle_chunk_impl(handle, process_fn, chunk_data, chunk_index, total_chunks)
   }
 
   fn handle_chunk_impl(
-    process_fn: fn(Vec<u8>),
+    handle: u32,
+    process_fn: fn(u32, Vec<u8>) -> Result<(), LoadError>,
     chunk_data: Vec<u8>,
     chunk_index: u32,
     total_chunks: u32,
-  ) {
-    let (mut chunks, mut total_chunks_ref) = (
-      _chunks.lock().unwrap(),
-      _total_chunks.lock().unwrap()
-    );
-    
-    if chunk_index == 0 {
-      *total_chunks_ref = total_chunks;
-      chunks.clear();
-      chunks.resize(total_chunks as usize, Vec::new());
-    }
-    
-    chunks[chunk_index as usize] = chunk_data;
-    
-    if chunks.iter().all(|chunk| !chunk.is_empty()) {
-      let mut reassembled_data = Vec::new();
-      for chunk in chunks.iter() {
-        reassembled_data.extend_from_slice(chunk);
+  ) -> Result<(), LoadError> {
+    let reassembled_data = with_document(handle, |document| {
+      let chunks = &mut document.chunks;
+
+      if chunk_index == 0 {
+        document.total_chunks = total_chunks;
+        chunks.clear();
+        chunks.resize(total_chunks as usize, Vec::new());
+      }
+
+      if chunk_index as usize >= chunks.len() {
+        return Err(LoadError::new(ErrorKind::InvalidArgument, format!("Chunk {} is out of range ({} chunks expected)", chunk_index, chunks.len())));
+      }
+      chunks[chunk_index as usize] = chunk_data;
+
+      if chunks.iter().all(|chunk| !chunk.is_empty()) {
+        let mut reassembled_data = Vec::new();
+        for chunk in chunks.iter() {
+          reassembled_data.extend_from_slice(chunk);
+        }
+        chunks.clear();
+        document.total_chunks = 0;
+        Ok(Some(reassembled_data))
+      } else {
+        Ok(None)
       }
-      process_fn(reassembled_data);
-      chunks.clear();
-      *total_chunks_ref = 0;
+    })?;
+
+    // The document lock is released before processing, since the process
+    // functions look the document up again
+    match reassembled_data {
+      Some(data) => process_fn(handle, data),
+      None => Ok(()),
     }
   }
 
-  pub fn loadremotestatus(status_data: Vec<u8>) -> String {
+  pub fn loadremotestatus(status_data: Vec<u8>) -> Result<String, LoadError> {
     let status_text = match String::from_utf8(status_data) {
       Ok(text) => text,
       Err(_) => {
-        outputlog("Failed to decode status data as UTF-8");
-        return "{}".to_string();
+        return Err(LoadError::new(ErrorKind::ParseError, "Failed to decode status data as UTF-8"));
       }
     };
     
     let status: SurferStatus = match serde_json::from_str(&status_text) {
       Ok(status) => status,
       Err(e) => {
-        outputlog(&format!("Failed to parse status JSON: {:?}", e));
-        return "{}".to_string();
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to parse status JSON: {:?}", e)));
       }
     };
     
@@ -83,15 +95,14 @@ impl SurferRemote {
         status.file_format, status.wellen_version, status.surfer_version));
     outputlog(&format!("Bytes loaded: {}/{}", status.bytes_loaded, status.bytes));
     
-    status.filename
+    Ok(status.filename)
   }
 
-  pub fn loadremotehierarchy(hierarchy_data: Vec<u8>) {
+  pub fn loadremotehierarchy(handle: u32, hierarchy_data: Vec<u8>) -> Result<(), LoadError> {
     let raw = match lz4_flex::decompress_size_prepended(&hierarchy_data) {
       Ok(raw) => raw,
       Err(e) => {
-        outputlog(&format!("Failed to decompress hierarchy data: {:?}", e));
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to decompress hierarchy data: {:?}", e)));
       }
     };
     
@@ -101,101 +112,84 @@ impl SurferRemote {
     let file_format: FileFormat = match opts.deserialize_from(&mut reader) {
       Ok(format) => format,
       Err(e) => {
-        outputlog(&format!("Failed to deserialize file format: {:?}", e));
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to deserialize file format: {:?}", e)));
       }
     };
     
     let hierarchy: Hierarchy = match BINCODE_OPTIONS.deserialize_from(&mut reader) {
       Ok(hierarchy) => hierarchy,
       Err(e) => {
-        outputlog(&format!("Failed to deserialize hierarchy: {:?}", e));
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to deserialize hierarchy: {:?}", e)));
       }
     };
     
-    let mut global_hierarchy = _hierarchy.lock().unwrap();
-    let mut global_file_format = _file_format.lock().unwrap();
-    *global_hierarchy = Some(hierarchy);
-    *global_file_format = file_format;
-    
-    let hier = global_hierarchy.as_ref().unwrap();
-    let scope_count = hier.scopes().count() as u32;
-    let var_count = hier.vars().count() as u32;
-    
-    let time_unit = hier.timescale().map_or("s".to_string(), |scale| {
-      match scale.unit {
-        TimescaleUnit::ZeptoSeconds => "zs",
-        TimescaleUnit::AttoSeconds => "as",
-        TimescaleUnit::FemtoSeconds => "fs",
-        TimescaleUnit::PicoSeconds => "ps",
-        TimescaleUnit::NanoSeconds => "ns",
-        TimescaleUnit::MicroSeconds => "us",
-        TimescaleUnit::MilliSeconds => "ms",
-        TimescaleUnit::Seconds | TimescaleUnit::Unknown => "s",
-      }.to_string()
-    });
+    with_document(handle, |document| {
+      let hier = document.hierarchy.insert(hierarchy);
+      document.file_format = file_format;
+
+      let scope_count = hier.scopes().count() as u32;
+      let var_count = hier.vars().count() as u32;
     
-    let time_scale = hier.timescale().map_or(1, |scale| scale.factor) as u32;
-    setmetadata(scope_count, var_count, time_scale, &time_unit);
+      let time_unit = hier.timescale().map_or("s".to_string(), |scale| {
+        match scale.unit {
+          TimescaleUnit::ZeptoSeconds => "zs",
+          TimescaleUnit::AttoSeconds => "as",
+          TimescaleUnit::FemtoSeconds => "fs",
+          TimescaleUnit::PicoSeconds => "ps",
+          TimescaleUnit::NanoSeconds => "ns",
+          TimescaleUnit::MicroSeconds => "us",
+          TimescaleUnit::MilliSeconds => "ms",
+          TimescaleUnit::Seconds | TimescaleUnit::Unknown => "s",
+        }.to_string()
+      });
     
-    for s in hier.scopes() {
-      let scope_data = get_scope_data(hier, s);
-      setscopetop(&scope_data.name, scope_data.id, &scope_data.tpe);
-    }
+      let time_scale = hier.timescale().map_or(1, |scale| scale.factor) as u32;
+      setmetadata(handle, scope_count, var_count, time_scale, &time_unit);
     
-    for v in hier.vars() {
-      let var_data = get_var_data(hier, v);
-      setvartop(&var_data.name, var_data.id, var_data.signal_id, &var_data.var_type, &var_data.encoding, var_data.width, var_data.msb, var_data.lsb, &var_data.enum_name);
-    }
+      for s in hier.scopes() {
+        let scope_data = get_scope_data(hier, s);
+        setscopetop(handle, &scope_data.name, scope_data.id, &scope_data.tpe);
+      }
     
+      for v in hier.vars() {
+        let var_data = get_var_data(hier, v, &document.param_table);
+        setvartop(handle, &var_data.name, var_data.netlist_id, var_data.signal_id, &var_data.tpe, &var_data.encoding, var_data.width, var_data.msb, var_data.lsb, &var_data.enum_type);
+      }
+      Ok(())
+    })
   }
 
-  pub fn loadremotetimetable(timetable_data: Vec<u8>) {
+  pub fn loadremotetimetable(handle: u32, timetable_data: Vec<u8>) -> Result<(), LoadError> {
     let compressed: CompressedTimeTable = match BINCODE_OPTIONS.deserialize(&timetable_data) {
       Ok(compressed) => compressed,
       Err(e) => {
-        outputlog(&format!("Failed to deserialize time table: {:?}", e));
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to deserialize time table: {:?}", e)));
       }
     };
     
     let time_table = compressed.uncompress();
-    let mut global_time_table = _time_table.lock().unwrap();
-    *global_time_table = Some(time_table);
-    
-    let tt = global_time_table.as_ref().unwrap();
-    let event_count = tt.len();
-    
-    let min_timestamp = if event_count <= 128 {
-      tt[event_count - 1]
-    } else {
-      (128..event_count)
-        .map(|i| tt[i] - tt[i - 128])
-        .min()
-        .unwrap_or(9999999)
-    };
-    
-    let time_end = tt[event_count - 1];
-    let time_end_extend = time_end + (time_end as f32 / event_count as f32).ceil() as u64;
+    with_document(handle, |document| {
+      let tt = document.time_table.insert(time_table);
+      let (chunk_size, time_end_extend) = get_chunk_size(tt);
     
-    setchunksize(min_timestamp / 128, time_end_extend, event_count as u64);
+      setchunksize(handle, chunk_size, time_end_extend, tt.len() as u64);
+      Ok(())
+    })
   }
 
-  pub fn loadremotesignals(signals_data: Vec<u8>) {
+  pub fn loadremotesignals(handle: u32, signals_data: Vec<u8>) -> Result<(), LoadError> {
     let mut reader = std::io::Cursor::new(signals_data);
     
     let num_ids = match leb128::read::unsigned(&mut reader) {
       Ok(num_ids) => num_ids,
       Err(e) => {
-        outputlog(&format!("Failed to read signal count: {:?}", e));
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to read signal count: {:?}", e)));
       }
     };
     
     if num_ids == 0 {
       outputlog("No signals in remote response");
-      return;
+      return Ok(());
     }
     
     let opts = BINCODE_OPTIONS.allow_trailing_bytes();
@@ -206,16 +200,14 @@ impl SurferRemote {
       let compressed: wellen::CompressedSignal = match opts.deserialize_from(&mut reader) {
         Ok(compressed) => compressed,
         Err(e) => {
-          outputlog(&format!("Failed to deserialize signal {}: {:?}", i, e));
-          return;
+          return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to deserialize signal {}: {:?}", i, e)));
         }
       };
       
       let signal = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| compressed.uncompress())) {
         Ok(signal) => signal,
         Err(_) => {
-          outputlog(&format!("Failed to uncompress signal {} (panic caught)", i));
-          return;
+          return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to uncompress signal {} (panic caught)", i)));
         }
       };
       
@@ -226,75 +218,72 @@ impl SurferRemote {
     let final_compressed: wellen::CompressedSignal = match BINCODE_OPTIONS.deserialize_from(&mut reader) {
       Ok(compressed) => compressed,
       Err(e) => {
-        outputlog(&format!("Failed to deserialize final signal: {:?}", e));
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, format!("Failed to deserialize final signal: {:?}", e)));
       }
     };
     
     let final_signal = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| final_compressed.uncompress())) {
       Ok(signal) => signal,
       Err(_) => {
-        outputlog("Failed to uncompress final signal (panic caught)");
-        return;
+        return Err(LoadError::new(ErrorKind::ParseError, "Failed to uncompress final signal (panic caught)"));
       }
     };
     
     signals.push((final_signal.signal_ref(), final_signal));
     
-    let global_time_table = _time_table.lock().unwrap();
-    let time_table = match global_time_table.as_ref() {
-      Some(time_table) => time_table,
-      None => {
-        outputlog("Warning: No time table available to process remote signals");
-        return;
-      }
-    };
+    with_document(handle, |document| {
+      let time_table = match document.time_table.as_ref() {
+        Some(time_table) => time_table,
+        None => {
+          return Err(LoadError::not_loaded("time table"));
+        }
+      };
     
-    for (signal_ref, signal) in signals.iter() {
-      let signalid = signal_ref.index() as u32;
-      let time_index = signal.time_indices();
-      let mut result = String::from("[");
-      let mut min = 0.0;
-      let mut max = 0.0;
+      for (signal_ref, signal) in signals.iter() {
+        let signalid = signal_ref.index() as u32;
+        let mut result = String::from("[");
+        let mut min = 0.0;
+        let mut max = 0.0;
       
-      for (i, (_, value)) in signal.iter_changes().enumerate() {
-        let v = value.to_string();
-        let time = time_table[time_index[i] as usize];
+        for (time_table_index, value) in signal.iter_changes() {
+          let v = value.to_string();
+          let time = table_time(time_table, time_table_index);
         
-        if let wellen::SignalValueRef::Real(v) = value {
-          min = f64::min(min, v);
-          max = f64::max(max, v);
-        }
+          if let wellen::SignalValueRef::Real(v) = value {
+            min = f64::min(min, v);
+            max = f64::max(max, v);
+          }
         
-        result.push_str(&format!("[{:?},{:?}],", time, v));
-      }
+          result.push_str(&format!("[{},{:?}],", json_time(time), v));
+        }
       
-      if result.len() > 1 { result.pop(); }
-      result.push(']');
+        if result.len() > 1 { result.pop(); }
+        result.push(']');
       
-      // Send the data in chunks
-      let result_length = result.len() as u32;
-      let chunk_count = (result_length as f32 / MAX_CHUNK_SIZE as f32).ceil() as u32;
+        // Send the data in chunks
+        let result_length = result.len() as u32;
+        let chunk_count = (result_length as f32 / MAX_CHUNK_SIZE as f32).ceil() as u32;
       
-      for i in 0..chunk_count {
-        let start = (i * MAX_CHUNK_SIZE) as usize;
-        let end = std::cmp::min(((i + 1) * MAX_CHUNK_SIZE) as usize, result.len());
-        let chunk = &result[start..end];
-        sendtransitiondatachunk(signalid, chunk_count, i, min, max, chunk);
+        for i in 0..chunk_count {
+          let start = (i * MAX_CHUNK_SIZE) as usize;
+          let end = std::cmp::min(((i + 1) * MAX_CHUNK_SIZE) as usize, result.len());
+          let chunk = &result[start..end];
+          sendtransitiondatachunk(handle, signalid, chunk_count, i, min, max, chunk);
+        }
       }
-    }
+      Ok(())
+    })
   }
 
-  pub fn loadremotechunk(chunk_type: u32, chunk_data: Vec<u8>, chunk_index: u32, total_chunks: u32) {
+  pub fn loadremotechunk(handle: u32, chunk_type: u32, chunk_data: Vec<u8>, chunk_index: u32, total_chunks: u32) -> Result<(), LoadError> {
     let chunk_type_enum = match chunk_type {
       0 => ChunkType::Hierarchy,
       1 => ChunkType::TimeTable,
       2 => ChunkType::Signals,
       _ => {
-        outputlog(&format!("Invalid chunk type: {}", chunk_type));
-        return;
+        return Err(LoadError::new(ErrorKind::InvalidArgument, format!("Invalid chunk type: {}", chunk_type)));
       }
     };
-    Self::handle_chunk(chunk_type_enum, chunk_data, chunk_index, total_chunks);
+    Self::handle_chunk(handle, chunk_type_enum, chunk_data, chunk_index, total_chunks)
   }
 }
\ No newline at end of file
@@ -0,0 +1,212 @@
+use serde_json::{json, Value};
+use wellen::{Signal, SignalValueRef, States, TimeTable};
+
+use crate::table_time;
+
+/*
+  Level of detail summaries, for drawing signals that have far more value
+  changes than there are pixels. The pyramid is a tree with FANOUT children
+  per node. Nodes of the lowest level summarize FANOUT value changes each,
+  and nodes of every level above summarize FANOUT nodes of the level below,
+  so any range of value changes is summarized by visiting O(FANOUT * levels)
+  nodes and value changes.
+ */
+
+const FANOUT: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Node {
+  unknown: bool,
+  min: f64,
+  max: f64,
+}
+
+impl Node {
+  fn empty() -> Self {
+    Node { unknown: false, min: f64::INFINITY, max: f64::NEG_INFINITY }
+  }
+
+  fn of(value: &SignalValueRef) -> Self {
+    match value {
+      // Any bit other than 0 or 1 makes the value unknown, which only 4 and
+      // 9 state values can hold
+      SignalValueRef::BitVec(bits) if bits.states() != States::Two => {
+        Node { unknown: bits.iter_lsb_to_msb().any(|bit| u8::from(bit) > 1), ..Node::empty() }
+      },
+      SignalValueRef::Real(v) => Node { unknown: false, min: *v, max: *v },
+      _ => Node::empty(),
+    }
+  }
+
+  fn merge(&mut self, other: &Node) {
+    self.unknown |= other.unknown;
+    self.min = f64::min(self.min, other.min);
+    self.max = f64::max(self.max, other.max);
+  }
+}
+
+/// Summaries of the value changes of one signal, built once when the signal
+/// is first drawn zoomed out, and kept in the signal cache with it
+pub struct LodPyramid {
+  changes: usize,
+  levels: Vec<Vec<Node>>,
+}
+
+fn value_at(signal: &Signal, position: usize) -> Option<SignalValueRef<'_>> {
+  let offset = signal.get_offset(*signal.time_indices().get(position)?)?;
+  Some(signal.get_value_at(&offset, position.checked_sub(offset.start)? as u16))
+}
+
+impl LodPyramid {
+  pub fn build(signal: &Signal) -> Self {
+    let changes = signal.time_indices().len();
+    let mut levels: Vec<Vec<Node>> = Vec::new();
+    let mut level = Vec::with_capacity(changes / FANOUT + 1);
+    let mut node = Node::empty();
+    for (i, (_, value)) in signal.iter_changes().enumerate() {
+      node.merge(&Node::of(&value));
+      if (i + 1) % FANOUT == 0 || i + 1 == changes {
+        level.push(node);
+        node = Node::empty();
+      }
+    }
+    while level.len() > FANOUT {
+      let parents = level.chunks(FANOUT).map(|children| {
+        let mut parent = Node::empty();
+        children.iter().for_each(|child| parent.merge(child));
+        parent
+      }).collect();
+      levels.push(level);
+      level = parents;
+    }
+    if !level.is_empty() {levels.push(level);}
+    LodPyramid { changes, levels }
+  }
+
+  pub fn size_in_memory(&self) -> usize {
+    self.levels.iter().map(|level| level.len() * std::mem::size_of::<Node>()).sum::<usize>() + std::mem::size_of::<Self>()
+  }
+
+  // Summarizes the value changes at positions [start, end)
+  fn summarize(&self, signal: &Signal, start: usize, end: usize) -> Node {
+    let mut summary = Node::empty();
+    if let Some(top) = self.levels.last() {
+      for index in 0..top.len() {
+        self.collect(signal, self.levels.len() - 1, index, start, end, &mut summary);
+      }
+    }
+    summary
+  }
+
+  fn collect(&self, signal: &Signal, level: usize, index: usize, start: usize, end: usize, summary: &mut Node) {
+    let span = FANOUT.pow(level as u32 + 1);
+    let node_start = index * span;
+    let node_end = std::cmp::min(node_start + span, self.changes);
+    if end <= node_start || node_end <= start {return;}
+    if start <= node_start && node_end <= end {
+      summary.merge(&self.levels[level][index]);
+      return;
+    }
+    if level == 0 {
+      for position in std::cmp::max(start, node_start)..std::cmp::min(end, node_end) {
+        if let Some(value) = value_at(signal, position) {summary.merge(&Node::of(&value));}
+      }
+      return;
+    }
+    let children = std::cmp::min(index * FANOUT + FANOUT, self.levels[level - 1].len());
+    for child in index * FANOUT..children {
+      self.collect(signal, level - 1, child, start, end, summary);
+    }
+  }
+
+  /// Splits start to end, both included, into equal buckets, and summarizes
+  /// each as {"changes", "first", "last", "unknown"}, where first is the value
+  /// going into the bucket and last the value at its end. Values are null
+  /// before the first value change. Real signals have "min" and "max"
+  /// instead of "unknown".
+  pub fn buckets(&self, signal: &Signal, time_table: &TimeTable, start: u64, end: u64, buckets: u32) -> String {
+    let time_indices = signal.time_indices();
+    let is_real = time_indices.first().and_then(|_| value_at(signal, 0)).is_some_and(|v| matches!(v, SignalValueRef::Real(_)));
+    let span = (end - start) as u128 + 1;
+    let bucket_start = |b: u32| start + (span * b as u128 / buckets as u128) as u64;
+    let position = |time: u64| time_indices.partition_point(|t| table_time(time_table, *t) < time);
+
+    let mut result = Vec::with_capacity(buckets as usize);
+    let mut first = position(start);
+    for b in 0..buckets {
+      // Bucket b holds the value changes at positions [first, last)
+      let last = if b + 1 == buckets {time_indices.partition_point(|t| table_time(time_table, *t) <= end)} else {position(bucket_start(b + 1))};
+      let value_before = |p: usize| if p > 0 {value_at(signal, p - 1).map(|v| v.to_string())} else {None};
+      let summary = self.summarize(signal, first.saturating_sub(1), last);
+
+      let mut bucket = json!({"changes": last - first, "first": value_before(first), "last": value_before(last)});
+      if is_real {
+        // Buckets without a value have min above max. JSON has no NaN or
+        // infinity, serde_json writes those as null.
+        let (min, max) = if summary.min <= summary.max {(Some(summary.min), Some(summary.max))} else {(None, None)};
+        bucket["min"] = json!(min);
+        bucket["max"] = json!(max);
+      } else {
+        bucket["unknown"] = json!(summary.unknown);
+      }
+      result.push(bucket);
+      first = last;
+    }
+    Value::Array(result).to_string()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{load_signal, vcd};
+
+  #[test]
+  fn writes_null_for_missing_and_infinite_values() {
+    let file = "$scope module top $end\n$var real 1 ! r $end\n$upscope $end\n$enddefinitions $end\n#5\nr1.5 !\n#6\nrinf !\n";
+    let loaded = load_signal(file, "top.r");
+    let lod = LodPyramid::build(loaded.signal());
+    let result = lod.buckets(loaded.signal(), &loaded.time_table, 0, 9, 2);
+    let parsed: Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(parsed[0], json!({"changes": 0, "first": null, "last": null, "min": null, "max": null}));
+    assert_eq!(parsed[1], json!({"changes": 2, "first": null, "last": "inf", "min": 1.5, "max": null}));
+  }
+
+  #[test]
+  fn marks_only_the_buckets_with_an_unknown_value() {
+    let file = vcd("#0\nb0000 \"\n#5\nb00z1 \"\n#6\nb0011 \"\n#8\nb0111 \"\n");
+    let loaded = load_signal(&file, "top.data");
+    let lod = LodPyramid::build(loaded.signal());
+    let summary = |start, end, buckets| -> Value {
+      serde_json::from_str(&lod.buckets(loaded.signal(), &loaded.time_table, start, end, buckets)).unwrap()
+    };
+    let parsed = summary(0, 9, 2);
+    assert_eq!(parsed[0], json!({"changes": 1, "first": null, "last": "0000", "unknown": false}));
+    assert_eq!(parsed[1], json!({"changes": 3, "first": "0000", "last": "0111", "unknown": true}));
+    // The value going into a bucket counts too
+    assert_eq!(summary(6, 9, 1)[0]["unknown"], json!(true));
+    assert_eq!(summary(7, 9, 1)[0]["unknown"], json!(false));
+  }
+
+  #[test]
+  fn summarizes_from_several_levels() {
+    // Toggles at every time step, with an x at time 200
+    let changes: String = (0..300).map(|i| {
+      let value = if i == 200 {"x".to_string()} else {(i % 2).to_string()};
+      format!("#{}\n{}!\n", i, value)
+    }).collect();
+    let loaded = load_signal(&vcd(&changes), "top.clk");
+    let lod = LodPyramid::build(loaded.signal());
+    assert_eq!(lod.levels.len(), 2);
+
+    let result = lod.buckets(loaded.signal(), &loaded.time_table, 0, 299, 3);
+    let parsed: Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(parsed[0], json!({"changes": 100, "first": null, "last": "1", "unknown": false}));
+    assert_eq!(parsed[1], json!({"changes": 100, "first": "1", "last": "1", "unknown": false}));
+    assert_eq!(parsed[2], json!({"changes": 100, "first": "1", "last": "1", "unknown": true}));
+
+    let result = lod.buckets(loaded.signal(), &loaded.time_table, 0, 299, 1);
+    let parsed: Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(parsed[0], json!({"changes": 300, "first": null, "last": "1", "unknown": true}));
+  }
+}
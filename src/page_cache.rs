@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Every fsread() is a round trip to the host, which is slow on network file
+// systems. The reader keeps recently used pages of the file, and reads several
+// pages in one call when the file is read sequentially. The readers of a
+// document share one cache, so a document keeps at most 16 MiB of pages.
+pub const PAGE_SIZE: u64 = 65536;
+const CACHE_PAGES: usize = 256;
+const READ_AHEAD_PAGES: u64 = 8;
+
+/// Page cache counters. They are shared with the document, so they can still
+/// be read after the reader has been handed to wellen.
+#[derive(Default)]
+pub struct CacheCounters {
+  pub hits: AtomicU64,
+  pub misses: AtomicU64,
+  pub host_reads: AtomicU64,
+  pub bytes_read: AtomicU64,
+}
+
+struct Page {
+  data: Vec<u8>,
+  last_used: u64,
+}
+
+pub struct PageCache {
+  pages: HashMap<u64, Page>,
+  tick: u64,
+  last_page: Option<u64>,
+  pub counters: Arc<CacheCounters>,
+}
+
+impl PageCache {
+  pub fn new() -> Self {
+    PageCache { pages: HashMap::new(), tick: 0, last_page: None, counters: Arc::new(CacheCounters::default()) }
+  }
+
+  /// Returns page `index` of the file, reading it from the host on a miss.
+  /// `read` is called with a file offset and length.
+  pub fn page(&mut self, index: u64, file_size: u64, read: impl Fn(u64, u32) -> Vec<u8>) -> io::Result<&[u8]> {
+    self.tick += 1;
+    let sequential = self.last_page.is_some_and(|last| index == last + 1);
+    self.last_page = Some(index);
+
+    if self.pages.contains_key(&index) {
+      self.counters.hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.counters.misses.fetch_add(1, Ordering::Relaxed);
+      let page_count = if sequential {READ_AHEAD_PAGES} else {1};
+      self.load(index, page_count, file_size, read)?;
+    }
+
+    let tick = self.tick;
+    match self.pages.get_mut(&index) {
+      Some(page) => {
+        page.last_used = tick;
+        Ok(&page.data)
+      },
+      None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Read past end of file at offset {}", index * PAGE_SIZE))),
+    }
+  }
+
+  // Reads up to page_count pages starting at page index with one host call,
+  // stopping early at a page that is already cached
+  fn load(&mut self, index: u64, page_count: u64, file_size: u64, read: impl Fn(u64, u32) -> Vec<u8>) -> io::Result<()> {
+    let mut last = index + 1;
+    while last < index + page_count && !self.pages.contains_key(&last) {last += 1;}
+
+    let start = index * PAGE_SIZE;
+    let end = std::cmp::min(file_size, last * PAGE_SIZE);
+    if start >= end {return Ok(());}
+
+    let length = (end - start) as usize;
+    let data = read(start, length as u32);
+    self.counters.host_reads.fetch_add(1, Ordering::Relaxed);
+    self.counters.bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+    if data.len() < length {
+      return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Short read at offset {}", start + data.len() as u64)));
+    }
+
+    for (i, chunk) in data[..length].chunks(PAGE_SIZE as usize).enumerate() {
+      self.evict();
+      self.pages.insert(index + i as u64, Page { data: chunk.to_vec(), last_used: self.tick });
+    }
+    Ok(())
+  }
+
+  fn evict(&mut self) {
+    if self.pages.len() < CACHE_PAGES {return;}
+    let oldest = self.pages.iter().min_by_key(|(_, page)| page.last_used).map(|(index, _)| *index);
+    if let Some(index) = oldest {
+      self.pages.remove(&index);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::RefCell;
+
+  // Every byte of the file is the number of its page
+  fn file_read(reads: &RefCell<Vec<(u64, u32)>>) -> impl Fn(u64, u32) -> Vec<u8> + '_ {
+    move |offset, length| {
+      reads.borrow_mut().push((offset, length));
+      (offset..offset + length as u64).map(|i| (i / PAGE_SIZE) as u8).collect()
+    }
+  }
+
+  #[test]
+  fn reads_ahead_when_reading_sequentially() {
+    let reads = RefCell::new(Vec::new());
+    let mut cache = PageCache::new();
+    let file_size = 20 * PAGE_SIZE;
+    assert_eq!(cache.page(0, file_size, file_read(&reads)).unwrap()[0], 0);
+    assert_eq!(cache.page(1, file_size, file_read(&reads)).unwrap()[0], 1);
+    for index in 2..1 + READ_AHEAD_PAGES {
+      assert_eq!(cache.page(index, file_size, file_read(&reads)).unwrap()[0], index as u8);
+    }
+    assert_eq!(*reads.borrow(), [(0, PAGE_SIZE as u32), (PAGE_SIZE, (READ_AHEAD_PAGES * PAGE_SIZE) as u32)]);
+    assert_eq!(cache.counters.misses.load(Ordering::Relaxed), 2);
+    assert_eq!(cache.counters.hits.load(Ordering::Relaxed), READ_AHEAD_PAGES - 1);
+  }
+
+  #[test]
+  fn reads_ahead_only_up_to_a_cached_page_or_the_end() {
+    let reads = RefCell::new(Vec::new());
+    let mut cache = PageCache::new();
+    let file_size = 3 * PAGE_SIZE + 100;
+    cache.page(2, file_size, file_read(&reads)).unwrap();
+    cache.page(0, file_size, file_read(&reads)).unwrap();
+    cache.page(1, file_size, file_read(&reads)).unwrap();
+    assert_eq!(cache.page(3, file_size, file_read(&reads)).unwrap().len(), 100);
+    assert_eq!(reads.borrow()[2], (PAGE_SIZE, PAGE_SIZE as u32));
+    assert!(cache.page(4, file_size, file_read(&reads)).is_err());
+  }
+
+  #[test]
+  fn evicts_the_least_recently_used_page() {
+    let reads = RefCell::new(Vec::new());
+    let mut cache = PageCache::new();
+    let file_size = 4 * CACHE_PAGES as u64 * PAGE_SIZE;
+    // Every other page, so nothing is read ahead
+    for i in 0..CACHE_PAGES as u64 {
+      cache.page(2 * i, file_size, file_read(&reads)).unwrap();
+    }
+    cache.page(0, file_size, file_read(&reads)).unwrap();
+    cache.page(2 * CACHE_PAGES as u64, file_size, file_read(&reads)).unwrap();
+    assert_eq!(cache.pages.len(), CACHE_PAGES);
+    assert!(cache.pages.contains_key(&0));
+    assert!(!cache.pages.contains_key(&2));
+  }
+
+  #[test]
+  fn fails_on_a_short_read() {
+    let mut cache = PageCache::new();
+    assert!(cache.page(0, PAGE_SIZE, |_, length| vec![0; length as usize - 1]).is_err());
+    assert!(cache.pages.is_empty());
+  }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use bincode::Options;
+use wellen::{CompressedSignal, CompressedTimeTable, FileFormat, Hierarchy, Signal, SignalRef, SignalSource, TimeTable};
+
+use crate::{fsread, writeindex, BINCODE_OPTIONS, WasmFileReader};
+use crate::error::{ErrorKind, LoadError};
+
+/*
+  The sidecar index lets a large file be reopened without parsing it again.
+  It is written through writeindex() and read back through fsread(), and is
+  laid out as:
+
+    magic, version (u32), fingerprint of the source file (u64)
+    length (u64) + bincode (FileFormat, Hierarchy)
+    length (u64) + bincode CompressedTimeTable
+    bincode CompressedSignal for every signal
+    bincode table of (signal id, offset, length) for the signals
+    offset of the table (u64)
+
+  All integers outside of bincode data are little endian.
+ */
+
+const MAGIC: &[u8; 6] = b"VVIDX\0";
+const VERSION: u32 = 1;
+const FIXED_HEADER_SIZE: u64 = 18;
+const SIGNAL_BATCH_SIZE: usize = 256;
+const WRITE_CHUNK_SIZE: usize = 1024 * 1024;
+const FINGERPRINT_BLOCKS: u64 = 16;
+const FINGERPRINT_BLOCK_SIZE: u64 = 65536;
+
+fn corrupt(what: &str) -> LoadError {
+  LoadError::new(ErrorKind::ParseError, format!("Corrupt index file: {}", what))
+}
+
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+  for byte in data {
+    hash ^= *byte as u64;
+    hash = hash.wrapping_mul(0x100000001b3);
+  }
+  hash
+}
+
+/// Fingerprint of a version of a file. Hashing a large file in full would
+/// take as long as parsing it, so only the size and blocks spread evenly over
+/// the file are hashed, together with the stamp the host has for the file.
+/// An edit between the blocks still changes the stamp.
+pub fn fingerprint(fd: u32, size: u64, stamp: u64) -> Result<u64, LoadError> {
+  let mut hash = fnv1a(0xcbf29ce484222325, &size.to_le_bytes());
+  hash = fnv1a(hash, &stamp.to_le_bytes());
+  let block_size = std::cmp::min(size, FINGERPRINT_BLOCK_SIZE);
+  let span = size - block_size;
+  for i in 0..FINGERPRINT_BLOCKS {
+    let offset = (span as u128 * i as u128 / (FINGERPRINT_BLOCKS - 1) as u128) as u64;
+    let data = fsread(fd, offset, block_size as u32);
+    if (data.len() as u64) < block_size {
+      return Err(LoadError::new(ErrorKind::IoError, "Short read while fingerprinting file").at_offset(Some(offset)));
+    }
+    hash = fnv1a(hash, &data[..block_size as usize]);
+    if span == 0 {break;}
+  }
+  Ok(hash)
+}
+
+// Buffers the index and hands it to the host in large pieces
+struct IndexWriter {
+  handle: u32,
+  buffer: Vec<u8>,
+  position: u64,
+}
+
+impl IndexWriter {
+  fn write(&mut self, data: &[u8]) {
+    self.buffer.extend_from_slice(data);
+    self.position += data.len() as u64;
+    if self.buffer.len() >= WRITE_CHUNK_SIZE {self.flush();}
+  }
+
+  fn write_section(&mut self, data: &[u8]) {
+    self.write(&(data.len() as u64).to_le_bytes());
+    self.write(data);
+  }
+
+  fn flush(&mut self) {
+    if self.buffer.is_empty() {return;}
+    writeindex(self.handle, &self.buffer);
+    self.buffer.clear();
+  }
+}
+
+fn serialize<T: serde::Serialize>(value: &T, what: &str) -> Result<Vec<u8>, LoadError> {
+  BINCODE_OPTIONS.serialize(value).map_err(|e| {
+    LoadError::new(ErrorKind::Internal, format!("Failed to serialize {}: {:?}", what, e))
+  })
+}
+
+/// Writes the index for a loaded document a step at a time, so that other
+/// calls can be served in between. Every signal is loaded once for this, in
+/// batches so that memory use stays bounded.
+pub struct IndexBuilder {
+  writer: IndexWriter,
+  signal_refs: Vec<SignalRef>,
+  next: usize,
+  table: Vec<(u32, u64, u64)>,
+}
+
+impl IndexBuilder {
+  /// Writes everything up to the signals
+  pub fn start(handle: u32, fingerprint: u64, file_format: FileFormat, hierarchy: &Hierarchy, time_table: &TimeTable) -> Result<Self, LoadError> {
+    let mut writer = IndexWriter { handle, buffer: Vec::new(), position: 0 };
+    writer.write(MAGIC);
+    writer.write(&VERSION.to_le_bytes());
+    writer.write(&fingerprint.to_le_bytes());
+    writer.write_section(&serialize(&(file_format, hierarchy), "hierarchy")?);
+    writer.write_section(&serialize(&CompressedTimeTable::compress(time_table), "time table")?);
+
+    let mut signal_refs: Vec<SignalRef> = hierarchy.all_vars().map(|v| hierarchy[v].signal_ref()).collect();
+    signal_refs.sort_by_key(|s| s.index());
+    signal_refs.dedup();
+    let table = Vec::with_capacity(signal_refs.len());
+    Ok(IndexBuilder { writer, signal_refs, next: 0, table })
+  }
+
+  /// Writes the next batch of signals, and the signal table after the last
+  /// one. Returns whether the index is complete.
+  pub fn step(&mut self, hierarchy: &Hierarchy, signal_source: &mut SignalSource) -> Result<bool, LoadError> {
+    let end = std::cmp::min(self.next + SIGNAL_BATCH_SIZE, self.signal_refs.len());
+    for signal in signal_source.load_signals(&self.signal_refs[self.next..end], hierarchy, false) {
+      let data = serialize(&CompressedSignal::compress(&signal), "signal")?;
+      self.table.push((signal.signal_ref().index() as u32, self.writer.position, data.len() as u64));
+      self.writer.write(&data);
+    }
+    self.next = end;
+    if self.next < self.signal_refs.len() {return Ok(false);}
+
+    let table_offset = self.writer.position;
+    self.writer.write(&serialize(&self.table, "signal table")?);
+    self.writer.write(&table_offset.to_le_bytes());
+    self.writer.flush();
+    Ok(true)
+  }
+}
+
+/// Signals of a document that was opened from a sidecar index
+pub struct SidecarIndex {
+  reader: WasmFileReader,
+  signals: HashMap<u32, (u64, u64)>,
+}
+
+pub struct IndexContents {
+  pub file_format: FileFormat,
+  pub hierarchy: Hierarchy,
+  pub time_table: TimeTable,
+  pub index: SidecarIndex,
+}
+
+fn read_at(reader: &mut WasmFileReader, offset: u64, length: u64) -> Result<Vec<u8>, LoadError> {
+  if offset.saturating_add(length) > reader.file_size {
+    return Err(corrupt("section extends past the end of the file"));
+  }
+  let mut data = vec![0; length as usize];
+  reader.seek(SeekFrom::Start(offset))
+    .and_then(|_| reader.read_exact(&mut data))
+    .map_err(|e| LoadError::new(ErrorKind::IoError, format!("Error reading index file: {}", e)).at_offset(Some(offset)))?;
+  Ok(data)
+}
+
+fn read_u64(reader: &mut WasmFileReader, offset: u64) -> Result<u64, LoadError> {
+  let data = read_at(reader, offset, 8)?;
+  data.try_into().map(u64::from_le_bytes).map_err(|_| corrupt("short read"))
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(data: &[u8], what: &str) -> Result<T, LoadError> {
+  BINCODE_OPTIONS.deserialize(data).map_err(|_| corrupt(what))
+}
+
+/// Opens a sidecar index. Returns None if it was written for a different
+/// version of the source file or by a different version of the filehandler.
+pub fn read_index(fd: u32, size: u64, fingerprint: u64) -> Result<Option<IndexContents>, LoadError> {
+  let mut reader = WasmFileReader::new(fd, size);
+  if size < FIXED_HEADER_SIZE + 8 {return Ok(None);}
+
+  let fixed = read_at(&mut reader, 0, FIXED_HEADER_SIZE)?;
+  let version = u32::from_le_bytes([fixed[6], fixed[7], fixed[8], fixed[9]]);
+  let index_fingerprint = read_u64(&mut reader, 10)?;
+  if &fixed[..6] != MAGIC || version != VERSION || index_fingerprint != fingerprint {
+    return Ok(None);
+  }
+
+  let header_length = read_u64(&mut reader, FIXED_HEADER_SIZE)?;
+  let header = read_at(&mut reader, FIXED_HEADER_SIZE + 8, header_length)?;
+  let (file_format, hierarchy): (FileFormat, Hierarchy) = deserialize(&header, "hierarchy")?;
+
+  let time_table_offset = FIXED_HEADER_SIZE + 8 + header_length;
+  let time_table_length = read_u64(&mut reader, time_table_offset)?;
+  let time_table = read_at(&mut reader, time_table_offset + 8, time_table_length)?;
+  let time_table: CompressedTimeTable = deserialize(&time_table, "time table")?;
+
+  let table_offset = read_u64(&mut reader, size - 8)?;
+  let table_length = (size - 8).checked_sub(table_offset).ok_or_else(|| corrupt("bad signal table offset"))?;
+  let table = read_at(&mut reader, table_offset, table_length)?;
+  let table: Vec<(u32, u64, u64)> = deserialize(&table, "signal table")?;
+  let signals = table.into_iter().map(|(id, offset, length)| (id, (offset, length))).collect();
+
+  Ok(Some(IndexContents {
+    file_format,
+    hierarchy,
+    time_table: time_table.uncompress(),
+    index: SidecarIndex { reader, signals },
+  }))
+}
+
+impl SidecarIndex {
+  pub fn load_signals(&mut self, signal_refs: &[SignalRef]) -> Result<Vec<Signal>, LoadError> {
+    let mut signals = Vec::with_capacity(signal_refs.len());
+    for signal_ref in signal_refs {
+      let (offset, length) = match self.signals.get(&(signal_ref.index() as u32)) {
+        Some(location) => *location,
+        None => {continue;}
+      };
+      let data = read_at(&mut self.reader, offset, length)?;
+      let compressed: CompressedSignal = deserialize(&data, "signal")?;
+      signals.push(compressed.uncompress());
+    }
+    Ok(signals)
+  }
+}
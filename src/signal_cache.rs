@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use wellen::{Signal, SignalRef};
+
+use crate::lod::LodPyramid;
+
+pub const DEFAULT_BUDGET: u64 = 256 * 1024 * 1024;
+
+struct CachedSignal {
+  signal: Signal,
+  lod: Option<LodPyramid>,
+  size: u64,
+  last_used: u64,
+}
+
+/// Signals that were loaded for the host, so that adding a signal again
+/// doesn't load it again. Least recently used signals are evicted when the
+/// cache grows over its budget, except for the ones used by the current
+/// request, which can take the cache over budget on their own.
+pub struct SignalCache {
+  signals: HashMap<SignalRef, CachedSignal>,
+  tick: u64,
+  size: u64,
+  pub budget: u64,
+}
+
+impl SignalCache {
+  pub fn new(budget: u64) -> Self {
+    SignalCache { signals: HashMap::new(), tick: 0, size: 0, budget }
+  }
+
+  /// Starts a request for the signals, and returns the ones that have to be
+  /// loaded and inserted
+  pub fn request(&mut self, signal_refs: &[SignalRef]) -> Vec<SignalRef> {
+    self.tick += 1;
+    let mut missing = Vec::new();
+    for signal_ref in signal_refs {
+      match self.signals.get_mut(signal_ref) {
+        Some(cached) => {cached.last_used = self.tick;},
+        None => {if !missing.contains(signal_ref) {missing.push(*signal_ref);}}
+      }
+    }
+    missing
+  }
+
+  pub fn insert(&mut self, signal: Signal) {
+    let size = signal.size_in_memory() as u64;
+    let cached = CachedSignal { signal, lod: None, size, last_used: self.tick };
+    self.size += size;
+    if let Some(old) = self.signals.insert(cached.signal.signal_ref(), cached) {
+      self.size -= old.size;
+    }
+  }
+
+  pub fn get(&self, signal_ref: SignalRef) -> Option<&Signal> {
+    self.signals.get(&signal_ref).map(|cached| &cached.signal)
+  }
+
+  /// The signal with its level of detail summaries, which are built the
+  /// first time they are asked for
+  pub fn get_with_lod(&mut self, signal_ref: SignalRef) -> Option<(&Signal, &LodPyramid)> {
+    let cached = self.signals.get_mut(&signal_ref)?;
+    if cached.lod.is_none() {
+      let lod = LodPyramid::build(&cached.signal);
+      cached.size += lod.size_in_memory() as u64;
+      self.size += lod.size_in_memory() as u64;
+      cached.lod = Some(lod);
+    }
+    cached.lod.as_ref().map(|lod| (&cached.signal, lod))
+  }
+
+  pub fn evict(&mut self) {
+    while self.size > self.budget {
+      let oldest = self.signals.iter()
+        .filter(|(_, cached)| cached.last_used < self.tick)
+        .min_by_key(|(_, cached)| cached.last_used)
+        .map(|(signal_ref, _)| *signal_ref);
+      match oldest {
+        Some(signal_ref) => self.remove(signal_ref),
+        None => {break;}
+      }
+    }
+  }
+
+  pub fn remove(&mut self, signal_ref: SignalRef) {
+    if let Some(cached) = self.signals.remove(&signal_ref) {
+      self.size -= cached.size;
+    }
+  }
+
+  pub fn clear(&mut self) {
+    self.signals.clear();
+    self.size = 0;
+  }
+
+  pub fn size(&self) -> u64 {self.size}
+
+  pub fn signal_refs(&self) -> Vec<SignalRef> {
+    self.signals.keys().copied().collect()
+  }
+
+  /// Signal ids and sizes of the cached signals
+  pub fn usage(&self) -> Vec<(u32, u64)> {
+    let mut usage: Vec<(u32, u64)> = self.signals.iter().map(|(signal_ref, cached)| (signal_ref.index() as u32, cached.size)).collect();
+    usage.sort();
+    usage
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use wellen::LoadOptions;
+  use wellen::viewers::{read_body, read_header};
+
+  // Signals 0 to 2 of a file with three vars
+  fn signals() -> Vec<Signal> {
+    let file = "$scope module top $end\n$var wire 1 ! a $end\n$var wire 1 \" b $end\n$var wire 4 # c $end\n$upscope $end\n$enddefinitions $end\n#0\n0!\n1\"\nb0000 #\n#1\n1!\nb0101 #\n#2\n0!\n0\"\n";
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &LoadOptions::default()).unwrap();
+    let mut body = read_body(header.body, &header.hierarchy, None).unwrap();
+    let signal_refs: Vec<_> = (0..3).map(|i| SignalRef::from_index(i).unwrap()).collect();
+    body.source.load_signals(&signal_refs, &header.hierarchy, false)
+  }
+
+  fn cached(cache: &SignalCache) -> Vec<u32> {
+    cache.usage().iter().map(|(id, _)| *id).collect()
+  }
+
+  #[test]
+  fn evicts_the_least_recently_used_signal_over_budget() {
+    let signals = signals();
+    let refs: Vec<_> = signals.iter().map(|signal| signal.signal_ref()).collect();
+    let total: u64 = signals.iter().map(|signal| signal.size_in_memory() as u64).sum();
+    let mut cache = SignalCache::new(total - 1);
+    for signal in signals.into_iter() {
+      let signal_ref = signal.signal_ref();
+      assert_eq!(cache.request(&[signal_ref]), [signal_ref]);
+      cache.insert(signal);
+      if signal_ref == refs[1] {
+        // Signal 0 is used again, so signal 1 becomes the oldest
+        assert!(cache.request(&[refs[0]]).is_empty());
+      }
+      cache.evict();
+    }
+    assert_eq!(cached(&cache), [0, 2]);
+    assert!(cache.size() <= cache.budget);
+    assert!(cache.get(refs[1]).is_none());
+  }
+
+  #[test]
+  fn keeps_the_signals_of_the_current_request_over_budget() {
+    let mut signals = signals();
+    let last = signals.pop().unwrap();
+    let mut cache = SignalCache::new(0);
+    let refs: Vec<_> = signals.iter().map(|signal| signal.signal_ref()).collect();
+    assert_eq!(cache.request(&refs), refs);
+    for signal in signals {
+      cache.insert(signal);
+    }
+    cache.evict();
+    assert_eq!(cached(&cache), [0, 1]);
+
+    cache.request(&[last.signal_ref()]);
+    cache.insert(last);
+    cache.evict();
+    assert_eq!(cached(&cache), [2]);
+    assert_eq!(cache.size(), cache.usage()[0].1);
+  }
+
+  #[test]
+  fn builds_the_lod_once_and_counts_its_size() {
+    let signal = signals().remove(2);
+    let signal_ref = signal.signal_ref();
+    let signal_size = signal.size_in_memory() as u64;
+    let mut cache = SignalCache::new(DEFAULT_BUDGET);
+    assert!(cache.get_with_lod(signal_ref).is_none());
+    cache.request(&[signal_ref]);
+    cache.insert(signal);
+
+    let lod_size = cache.get_with_lod(signal_ref).unwrap().1.size_in_memory() as u64;
+    assert_eq!(cache.size(), signal_size + lod_size);
+    assert!(cache.get_with_lod(signal_ref).is_some());
+    assert_eq!(cache.size(), signal_size + lod_size);
+    assert_eq!(cache.usage(), [(2, signal_size + lod_size)]);
+
+    cache.remove(signal_ref);
+    assert_eq!(cache.size(), 0);
+  }
+}
@@ -0,0 +1,92 @@
+use wellen::SignalRef;
+
+struct QueuedSignal {
+  request_id: u32,
+  signal_ref: SignalRef,
+  priority: u32,
+  order: u64,
+}
+
+/// Signals requested by the host that are still to be loaded. Higher
+/// priorities are loaded first, and signals of the same priority in the order
+/// they were queued.
+pub struct SignalQueue {
+  entries: Vec<QueuedSignal>,
+  next_order: u64,
+}
+
+impl SignalQueue {
+  pub fn new() -> Self {
+    SignalQueue { entries: Vec::new(), next_order: 0 }
+  }
+
+  pub fn push(&mut self, request_id: u32, signal_refs: &[SignalRef], priority: u32) {
+    for signal_ref in signal_refs {
+      self.entries.push(QueuedSignal { request_id, signal_ref: *signal_ref, priority, order: self.next_order });
+      self.next_order += 1;
+    }
+  }
+
+  /// Drops the signals of a request that weren't loaded yet, and returns how many
+  pub fn cancel(&mut self, request_id: u32) -> u32 {
+    let length = self.entries.len();
+    self.entries.retain(|entry| entry.request_id != request_id);
+    (length - self.entries.len()) as u32
+  }
+
+  /// Takes up to `max` signals off the front of the queue, but at least one,
+  /// with the request each of them belongs to
+  pub fn next_batch(&mut self, max: usize) -> Vec<(u32, SignalRef)> {
+    self.entries.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.order.cmp(&b.order)));
+    let count = std::cmp::min(std::cmp::max(max, 1), self.entries.len());
+    self.entries.drain(..count).map(|entry| (entry.request_id, entry.signal_ref)).collect()
+  }
+
+  pub fn len(&self) -> usize {self.entries.len()}
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn signal_refs(indices: &[usize]) -> Vec<SignalRef> {
+    indices.iter().map(|i| SignalRef::from_index(*i).unwrap()).collect()
+  }
+
+  fn ids(batch: &[(u32, SignalRef)]) -> Vec<(u32, usize)> {
+    batch.iter().map(|(request_id, signal_ref)| (*request_id, signal_ref.index())).collect()
+  }
+
+  #[test]
+  fn loads_higher_priorities_first_and_in_queue_order_within_one() {
+    let mut queue = SignalQueue::new();
+    queue.push(1, &signal_refs(&[3, 1]), 0);
+    queue.push(2, &signal_refs(&[5]), 2);
+    queue.push(3, &signal_refs(&[2, 4]), 0);
+    queue.push(4, &signal_refs(&[0]), 2);
+    assert_eq!(ids(&queue.next_batch(10)), [(2, 5), (4, 0), (1, 3), (1, 1), (3, 2), (3, 4)]);
+    assert_eq!(queue.len(), 0);
+  }
+
+  #[test]
+  fn takes_batches_of_at_most_max_but_at_least_one() {
+    let mut queue = SignalQueue::new();
+    queue.push(1, &signal_refs(&[0, 1, 2, 3, 4]), 0);
+    assert_eq!(ids(&queue.next_batch(2)), [(1, 0), (1, 1)]);
+    assert_eq!(ids(&queue.next_batch(0)), [(1, 2)]);
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.next_batch(3).len(), 2);
+    assert!(queue.next_batch(3).is_empty());
+  }
+
+  #[test]
+  fn cancels_only_the_signals_still_queued() {
+    let mut queue = SignalQueue::new();
+    queue.push(1, &signal_refs(&[0, 1, 2]), 0);
+    queue.push(2, &signal_refs(&[1]), 1);
+    assert_eq!(ids(&queue.next_batch(2)), [(2, 1), (1, 0)]);
+    assert_eq!(queue.cancel(1), 2);
+    assert_eq!(queue.cancel(2), 0);
+    assert_eq!(queue.len(), 0);
+  }
+}
@@ -0,0 +1,38 @@
+use std::io::Cursor;
+use wellen::simple::{read_from_reader, Waveform};
+use wellen::{Signal, SignalRef, TimeTable};
+
+/// Parses a VCD file given as text
+pub fn waveform(vcd: &str) -> Waveform {
+  read_from_reader(Cursor::new(vcd.as_bytes().to_vec())).unwrap()
+}
+
+/// A parsed file with one signal loaded
+pub struct LoadedSignal {
+  waveform: Waveform,
+  signal_ref: SignalRef,
+  pub time_table: TimeTable,
+}
+
+impl LoadedSignal {
+  pub fn signal(&self) -> &Signal {
+    self.waveform.get_signal(self.signal_ref).unwrap()
+  }
+}
+
+/// Parses a VCD file and loads the signal of the var at a dotted path
+pub fn load_signal(vcd: &str, path: &str) -> LoadedSignal {
+  let mut waveform = waveform(vcd);
+  let (scope, name) = path.rsplit_once('.').unwrap();
+  let scope: Vec<&str> = scope.split('.').collect();
+  let var = waveform.hierarchy().lookup_var(&scope, name).unwrap();
+  let signal_ref = waveform.hierarchy()[var].signal_ref();
+  waveform.load_signals(&[signal_ref]);
+  let time_table = waveform.time_table().to_vec();
+  LoadedSignal { waveform, signal_ref, time_table }
+}
+
+/// A VCD file with a 1 bit var top.clk and a 4 bit var top.data
+pub fn vcd(changes: &str) -> String {
+  format!("$timescale 1fs $end\n$scope module top $end\n$var wire 1 ! clk $end\n$var wire 4 \" data [3:0] $end\n$upscope $end\n$enddefinitions $end\n{}", changes)
+}
@@ -0,0 +1,91 @@
+use crate::vaporview::fileparser::types::TimeWindow;
+
+/// Returns the offset of the first timestamp in a VCD body that is past
+/// `end`, so that parsing can stop there instead of reading the rest of the
+/// file. Timestamps inside $comment blocks are skipped.
+fn find_window_end(body: &[u8], end: u64) -> Option<usize> {
+  let mut in_comment = false;
+  let mut i = 0;
+  while i < body.len() {
+    if body[i].is_ascii_whitespace() {
+      i += 1;
+      continue;
+    }
+    let start = i;
+    while i < body.len() && !body[i].is_ascii_whitespace() {i += 1;}
+    let token = &body[start..i];
+    if in_comment {
+      in_comment = token != b"$end";
+    } else if token == b"$comment" {
+      in_comment = true;
+    } else if token[0] == b'#' {
+      let time = token[1..].iter().take_while(|b| b.is_ascii_digit())
+        .fold(0u64, |time, digit| time.saturating_mul(10).saturating_add((digit - b'0') as u64));
+      if time > end {return Some(start);}
+    }
+  }
+  None
+}
+
+/// Cuts an in-memory VCD file after the end of the time window
+pub fn truncate_vcd(file: &mut Vec<u8>, window: &TimeWindow) {
+  // Only VCD is text, and the scan must not run over the header, where
+  // comments could contain a '#'
+  if file.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'$') {return;}
+  let marker = b"$enddefinitions";
+  let body_start = match file.windows(marker.len()).position(|w| w == marker) {
+    Some(position) => position + marker.len(),
+    None => {return;}
+  };
+  if let Some(length) = find_window_end(&file[body_start..], window.end) {
+    file.truncate(body_start + length);
+  }
+}
+
+/// Maps the time of a value change into the time window. Changes outside the
+/// window are dropped, except for the last change before the window starts,
+/// which is moved to the start of the window as its initial value.
+pub fn window_time(window: &Option<TimeWindow>, time: u64, next_time: Option<u64>) -> Option<u64> {
+  let window = match window {
+    Some(window) => window,
+    None => {return Some(time);}
+  };
+  if time > window.end {return None;}
+  if time >= window.start {return Some(time);}
+  match next_time {
+    Some(next) if next <= window.start => None,
+    _ => Some(window.start),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn stops_before_the_first_time_past_the_end() {
+    let body = b"\n#0\n1!\n#5\n0!\n#10\n1!\n";
+    assert_eq!(find_window_end(body, 5), Some(13));
+    assert_eq!(&body[13..16], b"#10");
+    assert_eq!(find_window_end(body, 10), None);
+  }
+
+  #[test]
+  fn ignores_a_hash_inside_a_value() {
+    let body = b"#0\nsa#99 !\nb#99 \"\n";
+    assert_eq!(find_window_end(body, 5), None);
+  }
+
+  #[test]
+  fn skips_comments_in_the_body() {
+    let body = b"#0\n1!\n$comment skip to #99 $end\n#5\n0!\n#12\n";
+    assert_eq!(find_window_end(body, 10), Some(body.len() - 4));
+  }
+
+  #[test]
+  fn truncates_only_the_body() {
+    let mut file = b"$comment #99 $end\n$enddefinitions $end\n#0\n1!\n#99\n0!\n".to_vec();
+    truncate_vcd(&mut file, &TimeWindow { start: 0, end: 10 });
+    assert_eq!(file, b"$comment #99 $end\n$enddefinitions $end\n#0\n1!\n");
+  }
+}
@@ -0,0 +1,259 @@
+use std::io::{self, Write};
+use lz4_flex::frame::FrameEncoder;
+use wellen::{Signal, SignalValueRef, TimeTable};
+
+use crate::error::{ErrorKind, LoadError};
+use crate::table_time;
+use crate::time_window::window_time;
+use crate::vaporview::fileparser::types::TimeWindow;
+
+/*
+  Binary encoding of the value changes of a signal, which is sent as one LZ4
+  frame with sendcompressedtransitiondata(). It is encoded and compressed
+  while it is sent, so memory use doesn't depend on the size of the signal.
+  Chunks are sent as they fill up, only the last one carries the number of
+  chunks and what is only known at the end. After decompression it is:
+
+    kind (u8), see below, with TIME_INDICES set if times are time table indices
+    for every value change:
+      time or time table index since the previous change (LEB128)
+      the value, depending on the kind:
+        BIT_VECTOR: bits per bit (u8, 1, 2 or 4), then the bits packed most
+                    significant first, with the first byte holding the
+                    leftover high bits. The states are 0 1 for 1 bit,
+                    0 1 x z for 2 bits, and 0 1 x z h u w l - for 4 bits.
+        REAL:       IEEE 754 double (8 bytes, little endian)
+        STRING:     length (LEB128) + UTF-8
+        EVENT:      nothing
+
+  The width in bits of a bit vector is sent along with the data. Each value
+  of a 4 or 9 state signal takes only as many bits per bit as it needs.
+
+  The time table is sent the same way with sendtimetablechunk(), as the
+  LEB128 differences between its times.
+ */
+
+pub const EVENT: u8 = 0;
+pub const BIT_VECTOR: u8 = 1;
+pub const REAL: u8 = 2;
+pub const STRING: u8 = 3;
+pub const TIME_INDICES: u8 = 0x80;
+
+// Size of the pieces the compressed data is sent to the host in
+const MAX_CHUNK_SIZE: usize = 65000;
+
+/// What is known about encoded value changes once they are all written
+pub struct TransitionSummary {
+  pub width: u32,
+  pub min: f64,
+  pub max: f64,
+  pub size: u64,
+}
+
+/// Collects compressed data, and hands every full chunk of it to `send`
+/// with its chunk number
+pub struct ChunkWriter<F: FnMut(u32, &[u8])> {
+  send: F,
+  buffer: Vec<u8>,
+  chunks: u32,
+}
+
+impl<F: FnMut(u32, &[u8])> ChunkWriter<F> {
+  pub fn new(send: F) -> Self {
+    ChunkWriter { send, buffer: Vec::with_capacity(MAX_CHUNK_SIZE), chunks: 0 }
+  }
+
+  /// The number and data of the last chunk, which the caller sends with
+  /// the total number of chunks
+  pub fn finish(self) -> (u32, Vec<u8>) {
+    (self.chunks, self.buffer)
+  }
+}
+
+impl<F: FnMut(u32, &[u8])> Write for ChunkWriter<F> {
+  fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+    let length = std::cmp::min(data.len(), MAX_CHUNK_SIZE - self.buffer.len());
+    self.buffer.extend_from_slice(&data[..length]);
+    if self.buffer.len() == MAX_CHUNK_SIZE {
+      (self.send)(self.chunks, &self.buffer);
+      self.buffer.clear();
+      self.chunks += 1;
+    }
+    Ok(length)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {Ok(())}
+}
+
+/// Compresses what `encode` writes, and sends it in chunks with `send`,
+/// which gets the total number of chunks only with the last one
+pub fn send_compressed<T, F: FnMut(u32, u32, &[u8], Option<&T>)>(encode: impl FnOnce(&mut dyn Write) -> io::Result<T>, mut send: F) -> Result<(), LoadError> {
+  let compression_error = |e: io::Error| LoadError::new(ErrorKind::Internal, format!("LZ4 compression failed: {}", e));
+  let mut encoder = FrameEncoder::new(ChunkWriter::new(|chunknum, chunk| send(0, chunknum, chunk, None)));
+  let result = encode(&mut encoder).map_err(compression_error)?;
+  let (chunknum, chunk) = encoder.finish().map_err(|e| compression_error(e.into()))?.finish();
+  send(chunknum + 1, chunknum, &chunk, Some(&result));
+  Ok(())
+}
+
+fn write_varint(out: &mut dyn Write, value: u64) -> io::Result<usize> {
+  leb128::write::unsigned(out, value)
+}
+
+fn kind(value: &SignalValueRef) -> u8 {
+  match value {
+    SignalValueRef::Event => EVENT,
+    SignalValueRef::BitVec(_) => BIT_VECTOR,
+    SignalValueRef::Real(_) => REAL,
+    SignalValueRef::String(_) => STRING,
+  }
+}
+
+/// Encodes the value changes in the time window. With time_indices, the
+/// value in effect at the start of the window keeps the time table index of
+/// the change that set it, as the window start may not be in the time table.
+pub fn encode_transitions(signal: &Signal, time_table: &TimeTable, window: &Option<TimeWindow>, time_indices: bool, out: &mut dyn Write) -> io::Result<TransitionSummary> {
+  let time_index = signal.time_indices();
+  let mut summary = TransitionSummary { width: 0, min: 0.0, max: 0.0, size: 1 };
+  let first_kind = signal.iter_changes().next().map_or(EVENT, |(_, value)| kind(&value));
+  out.write_all(&[if time_indices {first_kind | TIME_INDICES} else {first_kind}])?;
+
+  let mut prev_time = 0;
+  let mut bytes = Vec::new();
+  for (i, (time_table_index, value)) in signal.iter_changes().enumerate() {
+    let next_time = time_index.get(i + 1).map(|t| table_time(time_table, *t));
+    let time = match window_time(window, table_time(time_table, time_table_index), next_time) {
+      Some(_) if time_indices => time_table_index as u64,
+      Some(time) => time,
+      None => {continue;}
+    };
+    summary.size += write_varint(out, time - prev_time)? as u64;
+    prev_time = time;
+
+    match value {
+      SignalValueRef::Event => {},
+      SignalValueRef::BitVec(bits) => {
+        bytes.clear();
+        bits.append_to_vec(&mut bytes);
+        summary.width = bits.width();
+        out.write_all(&[bits.states().bits() as u8])?;
+        out.write_all(&bytes)?;
+        summary.size += 1 + bytes.len() as u64;
+      },
+      SignalValueRef::Real(v) => {
+        summary.min = f64::min(summary.min, v);
+        summary.max = f64::max(summary.max, v);
+        out.write_all(&v.to_le_bytes())?;
+        summary.size += 8;
+      },
+      SignalValueRef::String(s) => {
+        summary.size += write_varint(out, s.len() as u64)? as u64;
+        out.write_all(s.as_bytes())?;
+        summary.size += s.len() as u64;
+      },
+    }
+  }
+  Ok(summary)
+}
+
+pub fn encode_time_table(time_table: &TimeTable, out: &mut dyn Write) -> io::Result<()> {
+  let mut prev_time = 0;
+  for time in time_table {
+    write_varint(out, time - prev_time)?;
+    prev_time = *time;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{load_signal, vcd};
+
+  fn read_varint(data: &mut &[u8]) -> u64 {
+    leb128::read::unsigned(data).unwrap()
+  }
+
+  #[test]
+  fn time_table_near_u64_max() {
+    let time_table = vec![0, u64::MAX - 1, u64::MAX];
+    let mut out = Vec::new();
+    encode_time_table(&time_table, &mut out).unwrap();
+    let mut data = &out[..];
+    let mut time = 0;
+    let decoded: Vec<u64> = (0..3).map(|_| {time += read_varint(&mut data); time}).collect();
+    assert_eq!(decoded, time_table);
+    assert!(data.is_empty());
+  }
+
+  #[test]
+  fn transition_times_near_u64_max() {
+    let loaded = load_signal(&vcd("#0\n0!\n#18446744073709551614\n1!\n#18446744073709551615\nz!\n"), "top.clk");
+    let mut out = Vec::new();
+    encode_transitions(loaded.signal(), &loaded.time_table, &None, false, &mut out).unwrap();
+    let mut data = &out[1..];
+    let mut times = Vec::new();
+    let mut time = 0;
+    while !data.is_empty() {
+      time += read_varint(&mut data);
+      times.push(time);
+      data = &data[2..];
+    }
+    assert_eq!(times, [0, u64::MAX - 1, u64::MAX]);
+  }
+
+  // Decodes the (time, value) pairs of a string signal
+  fn read_strings(mut data: &[u8]) -> Vec<(u64, String)> {
+    let mut changes = Vec::new();
+    let mut time = 0;
+    while !data.is_empty() {
+      time += read_varint(&mut data);
+      let length = read_varint(&mut data) as usize;
+      changes.push((time, String::from_utf8(data[..length].to_vec()).unwrap()));
+      data = &data[length..];
+    }
+    changes
+  }
+
+  #[test]
+  fn moves_the_value_before_the_window_to_its_start() {
+    let file = "$scope module top $end\n$var string 1 ! s $end\n$upscope $end\n$enddefinitions $end\n#0\nsa !\n#5\nsbb !\n#10\nsc !\n";
+    let loaded = load_signal(file, "top.s");
+    let window = Some(TimeWindow { start: 3, end: 7 });
+
+    let mut out = Vec::new();
+    let summary = encode_transitions(loaded.signal(), &loaded.time_table, &window, false, &mut out).unwrap();
+    assert_eq!(out[0], STRING);
+    assert_eq!(read_strings(&out[1..]), [(3, "a".to_string()), (5, "bb".to_string())]);
+    assert_eq!(summary.size, out.len() as u64);
+
+    // With time indices, the first change keeps the index of time 0
+    let mut out = Vec::new();
+    encode_transitions(loaded.signal(), &loaded.time_table, &window, true, &mut out).unwrap();
+    assert_eq!(out[0], STRING | TIME_INDICES);
+    assert_eq!(read_strings(&out[1..]), [(0, "a".to_string()), (1, "bb".to_string())]);
+  }
+
+  #[test]
+  fn sends_full_chunks_then_the_last_one_with_the_total() {
+    // Incompressible data, so it needs more than one chunk
+    let mut state: u32 = 1;
+    let data: Vec<u8> = (0..150_000).map(|_| {state = state.wrapping_mul(1_103_515_245).wrapping_add(12345); (state >> 16) as u8}).collect();
+    let mut chunks: Vec<(u32, u32, Vec<u8>, Option<u32>)> = Vec::new();
+    send_compressed(|out| out.write_all(&data).map(|_| 42u32), |total, chunknum, chunk, result| {
+      chunks.push((total, chunknum, chunk.to_vec(), result.copied()));
+    }).unwrap();
+
+    assert!(chunks.len() > 2);
+    let (last, full) = chunks.split_last().unwrap();
+    assert!(full.iter().enumerate().all(|(i, (total, chunknum, chunk, result))| {
+      *total == 0 && *chunknum == i as u32 && chunk.len() == MAX_CHUNK_SIZE && result.is_none()
+    }));
+    assert_eq!((last.0, last.1, last.3), (chunks.len() as u32, chunks.len() as u32 - 1, Some(42)));
+
+    let compressed: Vec<u8> = chunks.iter().flat_map(|(_, _, chunk, _)| chunk.iter().copied()).collect();
+    let mut decompressed = Vec::new();
+    io::Read::read_to_end(&mut lz4_flex::frame::FrameDecoder::new(&compressed[..]), &mut decompressed).unwrap();
+    assert_eq!(decompressed, data);
+  }
+}
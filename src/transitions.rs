@@ -0,0 +1,135 @@
+use wellen::{Signal, SignalValueRef, TimeTable};
+
+use crate::{json_time, table_time};
+use crate::vaporview::fileparser::types::TimeWindow;
+
+/*
+  Parts of the value changes of a signal, for signals that are too large to
+  be sent in full. The result uses the same [[time,"value"],...] format as
+  getsignaldata(), with times as in json_time(). Changes are found with a binary search over the time
+  indices of the signal, and all delta cycles of a time step are always
+  returned together.
+ */
+
+fn change_time(signal: &Signal, time_table: &TimeTable, position: usize) -> u64 {
+  table_time(time_table, signal.time_indices()[position])
+}
+
+// Appends the values of the time step at position, and returns the position
+// after it
+fn push_time_step(result: &mut String, signal: &Signal, time: u64, position: usize) -> usize {
+  let offset = match signal.get_offset(signal.time_indices()[position]) {
+    Some(offset) => offset,
+    None => {return position + 1;}
+  };
+  for element in 0..offset.elements {
+    push_change(result, time, signal.get_value_at(&offset, element));
+  }
+  offset.start + offset.elements as usize
+}
+
+fn push_change(result: &mut String, time: u64, value: SignalValueRef) {
+  if result.len() > 1 {result.push(',');}
+  // Serializing a string can't fail
+  let value = serde_json::to_string(&value.to_string()).unwrap_or_default();
+  result.push_str(&format!("[{},{}]", json_time(time), value));
+}
+
+/// Value changes from start to end, both included. The value in effect at
+/// start comes first, at time start, unless the signal changes at start.
+pub fn transitions_in_range(signal: &Signal, time_table: &TimeTable, start: u64, end: u64) -> String {
+  let changes = signal.time_indices().len();
+  let mut position = signal.time_indices().partition_point(|t| table_time(time_table, *t) < start);
+  let mut result = String::from("[");
+
+  if position > 0 && (position == changes || change_time(signal, time_table, position) > start) {
+    if let Some(offset) = signal.get_offset(signal.time_indices()[position - 1]) {
+      push_change(&mut result, start, signal.get_value_at(&offset, offset.elements - 1));
+    }
+  }
+  while position < changes {
+    let time = change_time(signal, time_table, position);
+    if time > end {break;}
+    position = push_time_step(&mut result, signal, time, position);
+  }
+  result.push(']');
+  result
+}
+
+/// The first `count` value changes after time, or a few more if the last
+/// time step has delta cycles, from the changes in the time window. The time
+/// of the last change returned is the cursor for the next page, an empty list
+/// is the end of the signal.
+pub fn transitions_after(signal: &Signal, time_table: &TimeTable, time: u64, count: u32, window: &Option<TimeWindow>) -> String {
+  let changes = signal.time_indices().len();
+  let (window_start, window_end) = window.map_or((0, u64::MAX), |window| (window.start, window.end));
+  let mut position = signal.time_indices().partition_point(|t| {
+    let change = table_time(time_table, *t);
+    change <= time || change < window_start
+  });
+  let mut result = String::from("[");
+
+  let mut returned = 0;
+  while position < changes && returned < count {
+    let time = change_time(signal, time_table, position);
+    if time > window_end {break;}
+    let next = push_time_step(&mut result, signal, time, position);
+    returned += (next - position) as u32;
+    position = next;
+  }
+  result.push(']');
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{load_signal, vcd};
+
+  #[test]
+  fn keeps_times_near_u64_max_exact() {
+    let loaded = load_signal(&vcd("#0\n0!\n#18446744073709551614\n1!\n#18446744073709551615\n0!\n"), "top.clk");
+    let (signal, time_table) = (loaded.signal(), &loaded.time_table);
+    assert_eq!(
+      transitions_in_range(signal, time_table, 18446744073709551613, u64::MAX),
+      r#"[["18446744073709551613","0"],["18446744073709551614","1"],["18446744073709551615","0"]]"#
+    );
+    assert_eq!(transitions_after(signal, time_table, 18446744073709551614, 10, &None), r#"[["18446744073709551615","0"]]"#);
+  }
+
+  #[test]
+  fn starts_with_the_value_in_effect() {
+    let loaded = load_signal(&vcd("#2\n0!\n#5\n1!\n#10\n0!\n"), "top.clk");
+    let (signal, time_table) = (loaded.signal(), &loaded.time_table);
+    assert_eq!(transitions_in_range(signal, time_table, 0, 4), r#"[[2,"0"]]"#);
+    assert_eq!(transitions_in_range(signal, time_table, 3, 10), r#"[[3,"0"],[5,"1"],[10,"0"]]"#);
+    assert_eq!(transitions_in_range(signal, time_table, 5, 9), r#"[[5,"1"]]"#);
+    assert_eq!(transitions_in_range(signal, time_table, 11, 20), r#"[[11,"0"]]"#);
+    assert_eq!(transitions_in_range(signal, time_table, 0, 1), "[]");
+  }
+
+  #[test]
+  fn returns_every_delta_cycle_of_a_time_step() {
+    let loaded = load_signal(&vcd("#0\n0!\n#5\n1!\n0!\n#10\n1!\n"), "top.clk");
+    let (signal, time_table) = (loaded.signal(), &loaded.time_table);
+    assert_eq!(transitions_in_range(signal, time_table, 5, 5), r#"[[5,"1"],[5,"0"]]"#);
+    assert_eq!(transitions_in_range(signal, time_table, 7, 10), r#"[[7,"0"],[10,"1"]]"#);
+    assert_eq!(transitions_after(signal, time_table, 0, 1, &None), r#"[[5,"1"],[5,"0"]]"#);
+  }
+
+  #[test]
+  fn escapes_values_as_json() {
+    let file = "$scope module top $end\n$var string 1 ! s $end\n$upscope $end\n$enddefinitions $end\n#0\nsa\\x01\\x22b !\n";
+    let loaded = load_signal(file, "top.s");
+    let result = transitions_in_range(loaded.signal(), &loaded.time_table, 0, 10);
+    let parsed: serde_json::Value = serde_json::from_str(&result).unwrap();
+    assert_eq!(parsed, serde_json::json!([[0, "a\u{1}\"b"]]));
+  }
+
+  #[test]
+  fn pages_only_through_the_window() {
+    let loaded = load_signal(&vcd("#0\n0!\n#5\n1!\n#10\n0!\n#15\n1!\n"), "top.clk");
+    let window = Some(TimeWindow { start: 3, end: 12 });
+    assert_eq!(transitions_after(loaded.signal(), &loaded.time_table, 0, 10, &window), r#"[[5,"1"],[10,"0"]]"#);
+  }
+}
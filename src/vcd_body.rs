@@ -0,0 +1,626 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use wellen::{Encoder, Hierarchy, SignalRef, SignalSource, TimeTable, VarRef};
+
+use crate::error::{ErrorKind, LoadError};
+use crate::vaporview::fileparser::types::TimeWindow;
+
+/*
+  Incremental parsing of VCD files. wellen's read_body() parses the whole
+  body in one call, which keeps the instance busy until the file is read and
+  can't be cancelled. This parser is fed the file a piece at a time, from its
+  first byte, and hands the value changes to wellen's encoder, which builds
+  the same signal source as read_body(). Between pieces the instance is free
+  to serve other calls. The signal of each VCD id is read off the hierarchy
+  that wellen's header parser built from the same $var declarations.
+
+  Changes before the time window only update the value each signal has when
+  the window starts, which is handed to the encoder at the start time.
+ */
+
+const READ_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+  Header,
+  // wellen starts the body on the line after $enddefinitions
+  SkippingLine,
+  FirstToken,
+  IdToken,
+  Comment,
+  Done,
+}
+
+// How VCD ids map to signals. wellen uses the id as the signal index, unless
+// the ids are too sparse for that.
+enum IdLookup {
+  Dense(Vec<bool>),
+  Map(HashMap<Vec<u8>, SignalRef>),
+}
+
+impl IdLookup {
+  /// `var_signals` are the signals of the hierarchy's vars in the order they
+  /// were declared, one var per $var. wellen merges vars that hold the bits
+  /// of a split vector, then there is no such list and the ids are mapped
+  /// with wellen's own rule.
+  fn new(ids: &[Vec<u8>], var_signals: Option<&[SignalRef]>) -> Self {
+    match var_signals {
+      Some(var_signals) if var_signals.len() == ids.len() => IdLookup::from_vars(ids, var_signals),
+      _ => IdLookup::like_wellen(ids),
+    }
+  }
+
+  fn from_vars(ids: &[Vec<u8>], var_signals: &[SignalRef]) -> Self {
+    if ids.iter().zip(var_signals).all(|(id, signal_ref)| id_to_int(id) == Some(signal_ref.index() as u64)) {
+      let mut dense = Vec::new();
+      for signal_ref in var_signals {
+        set_flag(&mut dense, signal_ref.index());
+      }
+      return IdLookup::Dense(dense);
+    }
+    IdLookup::Map(ids.iter().cloned().zip(var_signals.iter().copied()).collect())
+  }
+
+  // The rule of wellen 0.25.7 for choosing between the two, which only
+  // files with split vectors depend on
+  fn like_wellen(ids: &[Vec<u8>]) -> Self {
+    let mut var_count = 0;
+    let mut range: Option<(u64, u64)> = None;
+    let mut dense = Vec::new();
+    for id in ids {
+      let value = match id_to_int(id) {
+        Some(value) => value,
+        None => {return IdLookup::map(ids);}
+      };
+      var_count += 1;
+      let (min, max) = range.map_or((value, value), |(min, max)| (min.min(value), max.max(value)));
+      range = Some((min, max));
+      if value / var_count > 1024 * 1024 || (max - min) / var_count > 1000 {
+        return IdLookup::map(ids);
+      }
+      set_flag(&mut dense, value as usize);
+    }
+    IdLookup::Dense(dense)
+  }
+
+  fn map(ids: &[Vec<u8>]) -> Self {
+    let mut map = HashMap::new();
+    for id in ids {
+      let next = SignalRef::from_index(map.len() + 1);
+      if let (false, Some(signal_ref)) = (map.contains_key(id), next) {
+        map.insert(id.clone(), signal_ref);
+      }
+    }
+    IdLookup::Map(map)
+  }
+
+  fn signal(&self, id: &[u8]) -> Option<SignalRef> {
+    match self {
+      IdLookup::Dense(declared) => {
+        let index = id_to_int(id)? as usize;
+        if *declared.get(index)? {SignalRef::from_index(index)} else {None}
+      },
+      IdLookup::Map(map) => map.get(id).copied(),
+    }
+  }
+}
+
+fn set_flag(flags: &mut Vec<bool>, index: usize) {
+  if flags.len() <= index {flags.resize(index + 1, false);}
+  flags[index] = true;
+}
+
+// VCD ids are numbers in base 94, written with the printable characters
+fn id_to_int(id: &[u8]) -> Option<u64> {
+  if id.is_empty() {return None;}
+  let mut result: u64 = 0;
+  for c in id.iter().rev() {
+    if !(b'!'..=b'~').contains(c) {return None;}
+    result = result.checked_mul(94)?.checked_add((c - b'!') as u64 + 1)?;
+  }
+  Some(result - 1)
+}
+
+fn parse_time(token: &[u8]) -> Option<u64> {
+  let text = std::str::from_utf8(token).ok()?;
+  text.parse::<u64>().ok().or_else(|| {
+    let value = text.parse::<f64>().ok()?;
+    if value.fract() == 0.0 {Some(value as u64)} else {None}
+  })
+}
+
+// Substitutes the escape sequences that writers put into string values, as
+// wellen does: \xHH, \OOO and the C escapes
+fn unescape(value: &[u8]) -> Vec<u8> {
+  let mut result = Vec::with_capacity(value.len());
+  let mut substituted = false;
+  let mut i = 0;
+  while i < value.len() {
+    if value[i] == b'\\' && i + 1 < value.len() {
+      let hex = |b: u8| (b as char).to_digit(16);
+      let octal = |b: u8| (b as char).to_digit(8);
+      if i + 3 < value.len() && value[i + 1] == b'x' {
+        if let (Some(high), Some(low)) = (hex(value[i + 2]), hex(value[i + 3])) {
+          result.push((high << 4 | low) as u8);
+          substituted = true;
+          i += 4;
+          continue;
+        }
+      }
+      if i + 3 < value.len() {
+        if let (Some(a), Some(b), Some(c)) = (octal(value[i + 1]), octal(value[i + 2]), octal(value[i + 3])) {
+          result.push(((a << 6) + (b << 3) + c) as u8);
+          substituted = true;
+          i += 4;
+          continue;
+        }
+      }
+      let mapped = match value[i + 1] {
+        b'a' => Some(0x07), b'b' => Some(0x08), b'f' => Some(0x0c), b'n' => Some(b'\n'),
+        b'r' => Some(b'\r'), b't' => Some(b'\t'), b'v' => Some(0x0b),
+        c @ (b'"' | b'\'' | b'\\' | b'?') => Some(c),
+        _ => None,
+      };
+      if let Some(c) = mapped {
+        result.push(c);
+        substituted = true;
+        i += 2;
+        continue;
+      }
+    }
+    result.push(value[i]);
+    i += 1;
+  }
+  // Like wellen, bytes of a value with escapes are read as Latin-1
+  if !substituted {return result;}
+  result.iter().map(|b| *b as char).collect::<String>().into_bytes()
+}
+
+/// What a live tail parser added since the last call to take_changes()
+#[derive(Default)]
+pub struct ParsedChanges {
+  pub times: Vec<u64>,
+  // Flags by signal index of the signals that changed
+  pub signals: Vec<bool>,
+}
+
+pub struct VcdBodyParser {
+  encoder: Encoder,
+  state: State,
+  // Parsing stops at the first timestamp after window_end
+  window_start: u64,
+  window_end: u64,
+  // Last value of every signal while the body is before the window start
+  before_window: Option<Vec<Option<Vec<u8>>>>,
+  // Header command being read, and how many of its arguments came so far
+  command: Vec<u8>,
+  argument: usize,
+  declared_ids: Vec<Vec<u8>>,
+  var_signals: Option<Vec<SignalRef>>,
+  ids: IdLookup,
+  // Flags by signal index of the signals whose changes are kept, all are
+  // kept if None
+  kept: Option<Vec<bool>>,
+  // Token being read, which may continue in the next piece of data
+  token: Vec<u8>,
+  value: Vec<u8>,
+  found_time: bool,
+  // Last time handed to the encoder, which skips times that don't increase
+  last_time: Option<u64>,
+  changes: Option<ParsedChanges>,
+  position: u64,
+}
+
+impl VcdBodyParser {
+  pub fn new(hierarchy: &Hierarchy, window: Option<TimeWindow>) -> Self {
+    let (window_start, window_end) = window.map_or((0, u64::MAX), |window| (window.start, window.end));
+    let var_signals = if hierarchy.has_derived_signals() {None} else {
+      let mut vars: Vec<VarRef> = hierarchy.all_vars().collect();
+      vars.sort_by_key(|var| var.index());
+      Some(vars.iter().map(|var| hierarchy[*var].signal_ref()).collect())
+    };
+    VcdBodyParser {
+      encoder: Encoder::new(hierarchy),
+      state: State::Header,
+      window_start,
+      window_end,
+      before_window: if window_start > 0 {Some(Vec::new())} else {None},
+      command: Vec::new(),
+      argument: 0,
+      declared_ids: Vec::new(),
+      var_signals,
+      ids: IdLookup::Dense(Vec::new()),
+      kept: None,
+      token: Vec::new(),
+      value: Vec::new(),
+      found_time: false,
+      last_time: None,
+      changes: None,
+      position: 0,
+    }
+  }
+
+  /// Drops the value changes of signals that aren't flagged, so that signals
+  /// filtered out by the load settings take no memory
+  pub fn keep_only(mut self, kept: Vec<bool>) -> Self {
+    self.kept = Some(kept);
+    self
+  }
+
+  /// Whether the rest of the file can be skipped, because the time window
+  /// ended
+  pub fn is_done(&self) -> bool {self.state == State::Done}
+
+  /// Starts collecting the times and signals that are added from here on,
+  /// for take_changes()
+  pub fn track_changes(&mut self) {
+    self.changes = Some(ParsedChanges::default());
+  }
+
+  pub fn take_changes(&mut self) -> ParsedChanges {
+    self.changes.as_mut().map(std::mem::take).unwrap_or_default()
+  }
+
+  /// Number of bytes fed so far, which is where the next piece starts
+  pub fn position(&self) -> u64 {self.position}
+
+  /// Parses the next piece of the file
+  pub fn feed(&mut self, data: &[u8]) -> Result<(), LoadError> {
+    for b in data {
+      self.position += 1;
+      match self.state {
+        State::Done => {return Ok(());},
+        State::SkippingLine => {
+          if *b == b'\n' {self.state = State::FirstToken;}
+        },
+        _ if b.is_ascii_whitespace() => {
+          if !self.token.is_empty() {
+            let token = std::mem::take(&mut self.token);
+            let result = self.end_token(&token);
+            self.token = token;
+            self.token.clear();
+            result?;
+            // The line after $enddefinitions ends here already
+            if self.state == State::SkippingLine && *b == b'\n' {self.state = State::FirstToken;}
+          }
+        },
+        _ => self.token.push(*b),
+      }
+    }
+    Ok(())
+  }
+
+  /// Parses what is left at the end of the file, and returns the signals
+  pub fn finish(mut self) -> Result<(SignalSource, TimeTable), LoadError> {
+    if !self.token.is_empty() && self.state != State::Done {
+      let token = std::mem::take(&mut self.token);
+      self.end_token(&token)?;
+    }
+    if self.state == State::Header {
+      return Err(LoadError::new(ErrorKind::ParseError, "VCD header has no $enddefinitions"));
+    }
+    self.enter_window();
+    Ok(self.encoder.finish())
+  }
+
+  /// The signals parsed so far, while the parser stays usable for data that
+  /// is appended to the file. A token at the very end isn't included until
+  /// whitespace after it is fed, since it may still be incomplete.
+  pub fn snapshot(&self) -> Result<(SignalSource, TimeTable), LoadError> {
+    if self.state == State::Header {
+      return Err(LoadError::new(ErrorKind::ParseError, "VCD header has no $enddefinitions"));
+    }
+    Ok(self.encoder.snapshot())
+  }
+
+  fn error(&self, message: String) -> LoadError {
+    LoadError::new(ErrorKind::ParseError, message).at_offset(Some(self.position.saturating_sub(1)))
+  }
+
+  fn end_token(&mut self, token: &[u8]) -> Result<(), LoadError> {
+    match self.state {
+      State::Header => self.header_token(token),
+      State::FirstToken => self.first_token(token),
+      State::IdToken => {
+        let value = std::mem::take(&mut self.value);
+        let result = self.value_change(&value, token);
+        self.value = value;
+        self.state = State::FirstToken;
+        result
+      },
+      State::Comment => {
+        if token == b"$end" {self.state = State::FirstToken;}
+        Ok(())
+      },
+      State::SkippingLine | State::Done => Ok(()),
+    }
+  }
+
+  fn header_token(&mut self, token: &[u8]) -> Result<(), LoadError> {
+    if self.command.is_empty() {
+      self.command.extend_from_slice(token);
+      self.argument = 0;
+    } else if token == b"$end" {
+      if self.command == b"$enddefinitions" {
+        self.ids = IdLookup::new(&self.declared_ids, self.var_signals.take().as_deref());
+        self.declared_ids = Vec::new();
+        self.state = State::SkippingLine;
+      }
+      self.command.clear();
+    } else {
+      if self.command == b"$var" && self.argument == 2 {
+        self.declared_ids.push(token.to_vec());
+      }
+      self.argument += 1;
+    }
+    Ok(())
+  }
+
+  fn first_token(&mut self, token: &[u8]) -> Result<(), LoadError> {
+    match token[0] {
+      b'#' => {
+        let time = match parse_time(&token[1..]) {
+          Some(time) => time,
+          None => {return Err(self.error(format!("Invalid timestamp: {}", String::from_utf8_lossy(token))));}
+        };
+        if time > self.window_end {
+          self.state = State::Done;
+        } else if time >= self.window_start {
+          self.enter_window();
+          self.time_change(time);
+        }
+        Ok(())
+      },
+      b'0' | b'1' | b'z' | b'Z' | b'x' | b'X' | b'h' | b'H' | b'u' | b'U' | b'w' | b'W' | b'l' | b'L' | b'-' => {
+        self.value_change(&token[..1], &token[1..])
+      },
+      b'b' | b'B' | b'r' | b'R' | b's' | b'S' => {
+        self.value.clear();
+        self.value.extend_from_slice(token);
+        self.state = State::IdToken;
+        Ok(())
+      },
+      _ => match token {
+        b"$comment" => {
+          self.state = State::Comment;
+          Ok(())
+        },
+        b"$dumpvars" | b"$end" | b"$dumpoff" | b"$dumpon" | b"$dumpall" => Ok(()),
+        _ => Err(self.error(format!("Unexpected token in VCD body: {}", String::from_utf8_lossy(token)))),
+      },
+    }
+  }
+
+  // Hands the values signals have at the start of the window to the encoder,
+  // at the start time
+  fn enter_window(&mut self) {
+    let values = match self.before_window.take() {
+      Some(values) => values,
+      None => {return;}
+    };
+    self.time_change(self.window_start);
+    for (index, value) in values.into_iter().enumerate() {
+      if let (Some(signal_ref), Some(value)) = (SignalRef::from_index(index), value) {
+        self.signal_change(signal_ref, &value);
+      }
+    }
+  }
+
+  fn time_change(&mut self, time: u64) {
+    if self.last_time.is_none_or(|last| time > last) {
+      self.last_time = Some(time);
+      if let Some(changes) = &mut self.changes {changes.times.push(time);}
+    }
+    self.encoder.time_change(time);
+    self.found_time = true;
+  }
+
+  fn signal_change(&mut self, signal_ref: SignalRef, value: &[u8]) {
+    if let Some(changes) = &mut self.changes {set_flag(&mut changes.signals, signal_ref.index());}
+    self.encoder.vcd_value_change(signal_ref, value);
+  }
+
+  fn value_change(&mut self, value: &[u8], id: &[u8]) -> Result<(), LoadError> {
+    let signal_ref = match self.ids.signal(id) {
+      Some(signal_ref) => signal_ref,
+      None => {return Err(self.error(format!("Value change for undeclared VCD id: {}", String::from_utf8_lossy(id))));}
+    };
+    if let Some(kept) = &self.kept {
+      if !kept.get(signal_ref.index()).copied().unwrap_or(false) {return Ok(());}
+    }
+    if let Some(values) = &mut self.before_window {
+      if values.len() <= signal_ref.index() {values.resize(signal_ref.index() + 1, None);}
+      values[signal_ref.index()] = Some(if value.contains(&b'\\') {unescape(value)} else {value.to_vec()});
+      return Ok(());
+    }
+    // Values dumped before the first timestamp are at time 0
+    if !self.found_time {
+      self.time_change(0);
+    }
+    if value.contains(&b'\\') {
+      self.signal_change(signal_ref, &unescape(value));
+    } else {
+      self.signal_change(signal_ref, value);
+    }
+    Ok(())
+  }
+}
+
+/// A VCD file that is being parsed a step at a time
+pub struct VcdBodyReader {
+  reader: Box<dyn Read + Send>,
+  parser: VcdBodyParser,
+  // Offset in the file, which is behind the parser for compressed files
+  read_position: Arc<AtomicU64>,
+  buffer: Vec<u8>,
+  at_end: bool,
+}
+
+impl VcdBodyReader {
+  /// `reader` reads the file from its start
+  pub fn new(reader: Box<dyn Read + Send>, read_position: Arc<AtomicU64>, parser: VcdBodyParser) -> Self {
+    VcdBodyReader { reader, parser, read_position, buffer: vec![0; READ_SIZE], at_end: false }
+  }
+
+  pub fn file_position(&self) -> u64 {self.read_position.load(Ordering::Relaxed)}
+
+  /// Reads and parses about `budget` bytes, and returns whether the file has
+  /// been read to the end, or to the end of the time window
+  pub fn step(&mut self, budget: u64) -> Result<bool, LoadError> {
+    let mut remaining = std::cmp::max(budget, 1);
+    while remaining > 0 && !self.at_end && !self.parser.is_done() {
+      let length = std::cmp::min(remaining, READ_SIZE as u64) as usize;
+      let bytes_read = self.reader.read(&mut self.buffer[..length]).map_err(|e| {
+        LoadError::new(ErrorKind::IoError, format!("Error reading file: {}", e)).at_offset(Some(self.file_position()))
+      })?;
+      if bytes_read == 0 {
+        self.at_end = true;
+      }
+      self.parser.feed(&self.buffer[..bytes_read])?;
+      remaining = remaining.saturating_sub(bytes_read as u64);
+    }
+    Ok(self.at_end || self.parser.is_done())
+  }
+
+  pub fn finish(self) -> Result<(SignalSource, TimeTable), LoadError> {
+    self.parser.finish()
+  }
+
+  pub fn into_parser(self) -> VcdBodyParser {self.parser}
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use wellen::viewers::{read_body, read_header};
+  use crate::test_util::vcd;
+
+  // Parses the file with wellen and with the incremental parser, feeding it
+  // `piece` bytes at a time, and compares the signals of every variable
+  fn assert_same_as_wellen(file: &str, piece: usize) {
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    let hierarchy = header.hierarchy;
+    let expected = read_body(header.body, &hierarchy, None).unwrap();
+
+    let mut parser = VcdBodyParser::new(&hierarchy, None);
+    for data in file.as_bytes().chunks(piece) {
+      parser.feed(data).unwrap();
+    }
+    let (mut source, time_table) = parser.finish().unwrap();
+    assert_eq!(time_table, expected.time_table);
+
+    let mut expected_source = expected.source;
+    let signal_refs: Vec<_> = hierarchy.all_vars().map(|var| hierarchy[var].signal_ref()).collect();
+    let signals = source.load_signals(&signal_refs, &hierarchy, false);
+    let expected_signals = expected_source.load_signals(&signal_refs, &hierarchy, false);
+    for (signal, expected) in signals.iter().zip(expected_signals.iter()) {
+      let changes: Vec<_> = signal.iter_changes().map(|(t, v)| (t, v.to_string())).collect();
+      let expected_changes: Vec<_> = expected.iter_changes().map(|(t, v)| (t, v.to_string())).collect();
+      assert_eq!(changes, expected_changes);
+    }
+  }
+
+  #[test]
+  fn matches_wellen_in_any_piece_size() {
+    let file = vcd("$dumpvars\n0!\n$end\n#5\n1!\n$comment a # b $end\n#10\n0!\n#12\n1!\n");
+    for piece in [1, 2, 3, 7, 64, 4096] {
+      assert_same_as_wellen(&file, piece);
+    }
+  }
+
+  #[test]
+  fn maps_sparse_ids_like_wellen() {
+    let file = "$timescale 1ns $end\n$scope module top $end\n$var wire 1 ! a $end\n$var wire 4 ~~~~ b $end\n$var string 1 % s $end\n$upscope $end\n$enddefinitions $end\n#0\n1!\nb1010 ~~~~\nsx\\x41\\n %\n#3\n0!\n";
+    assert_same_as_wellen(file, 5);
+  }
+
+  #[test]
+  fn maps_aliased_ids_like_wellen() {
+    let file = "$scope module top $end\n$var wire 1 # a $end\n$var wire 1 # b $end\n$var wire 1 !! c $end\n$upscope $end\n$enddefinitions $end\n#0\n1#\n0!!\n#1\n0#\n";
+    assert_same_as_wellen(file, 3);
+  }
+
+  #[test]
+  fn maps_the_bits_of_split_vectors_like_wellen() {
+    let file = "$scope module top $end\n$var wire 1 ! a [0] $end\n$var wire 1 \" a [1] $end\n$var wire 1 zz b $end\n$upscope $end\n$enddefinitions $end\n#0\n1!\n0\"\n1zz\n#1\n1\"\n";
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    assert!(header.hierarchy.has_derived_signals());
+    assert_same_as_wellen(file, 4);
+  }
+
+  #[test]
+  fn stops_at_the_end_of_the_window() {
+    let file = vcd("#0\n0!\n#5\n1!\n#10\n0!\n");
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    let mut parser = VcdBodyParser::new(&header.hierarchy, Some(TimeWindow { start: 0, end: 7 }));
+    parser.feed(file.as_bytes()).unwrap();
+    assert!(parser.is_done());
+    let (_, time_table) = parser.finish().unwrap();
+    assert_eq!(time_table, vec![0, 5]);
+  }
+
+  #[test]
+  fn resumes_after_a_snapshot() {
+    let file = vcd("#0\n0!\n#5\n1!\n#10\n0!\n");
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    let mut parser = VcdBodyParser::new(&header.hierarchy, None);
+    // The file ends right after "#10", which may still be incomplete
+    let split = file.len() - 4;
+    parser.feed(&file.as_bytes()[..split]).unwrap();
+    let (_, time_table) = parser.snapshot().unwrap();
+    assert_eq!(time_table, vec![0, 5]);
+    assert_eq!(parser.position(), split as u64);
+    parser.feed(&file.as_bytes()[split..]).unwrap();
+    let (_, time_table) = parser.snapshot().unwrap();
+    assert_eq!(time_table, vec![0, 5, 10]);
+  }
+
+  #[test]
+  fn takes_the_times_and_signals_added_since_the_last_take() {
+    let file = vcd("#0\n0!\nb0000 \"\n#5\n1!\n#5\n0!\n#3\n1!\n#10\nb0001 \"\n");
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    let mut parser = VcdBodyParser::new(&header.hierarchy, None);
+    let split = file.find("#5").unwrap();
+    parser.feed(&file.as_bytes()[..split]).unwrap();
+    parser.track_changes();
+    let rest = &file.as_bytes()[split..];
+    let middle = rest.len() - "#10\nb0001 \"\n".len();
+    parser.feed(&rest[..middle]).unwrap();
+    // A repeated or decreasing time isn't added to the time table
+    let changes = parser.take_changes();
+    assert_eq!(changes.times, vec![5]);
+    assert_eq!(changes.signals, vec![true]);
+    parser.feed(&rest[middle..]).unwrap();
+    let changes = parser.take_changes();
+    assert_eq!(changes.times, vec![10]);
+    assert_eq!(changes.signals, vec![false, true]);
+  }
+
+  #[test]
+  fn keeps_only_the_value_at_the_window_start() {
+    let file = vcd("#0\n0!\nb0001 \"\n#2\n1!\n#4\n0!\n#6\n1!\n");
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    let hierarchy = header.hierarchy;
+    let mut parser = VcdBodyParser::new(&hierarchy, Some(TimeWindow { start: 5, end: 10 }));
+    parser.feed(file.as_bytes()).unwrap();
+    let (mut source, time_table) = parser.finish().unwrap();
+    assert_eq!(time_table, vec![5, 6]);
+
+    let signal_refs: Vec<_> = hierarchy.all_vars().map(|var| hierarchy[var].signal_ref()).collect();
+    let changes: Vec<Vec<_>> = source.load_signals(&signal_refs, &hierarchy, false).iter()
+      .map(|signal| signal.iter_changes().map(|(t, v)| (time_table[t as usize], v.to_string())).collect())
+      .collect();
+    assert_eq!(changes, vec![vec![(5, "0".to_string()), (6, "1".to_string())], vec![(5, "0001".to_string())]]);
+  }
+
+  #[test]
+  fn reports_undeclared_ids() {
+    let file = vcd("#0\n1?\n");
+    let header = read_header(Cursor::new(file.as_bytes().to_vec()), &wellen::LoadOptions::default()).unwrap();
+    let mut parser = VcdBodyParser::new(&header.hierarchy, None);
+    assert!(parser.feed(file.as_bytes()).is_err());
+  }
+}
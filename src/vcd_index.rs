@@ -0,0 +1,310 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::vaporview::fileparser::types::TimeWindow;
+use crate::WasmFileReader;
+use crate::error::{ErrorKind, LoadError};
+
+// Checkpoints are found by reading a little of the body at every interval, so
+// building the index doesn't read the whole file. A timestamp can be at most
+// 20 digits, the overlap makes sure one isn't missed at a block boundary.
+const SCAN_BLOCK_SIZE: u64 = 65536;
+const SCAN_OVERLAP: u64 = 32;
+const MAX_SCAN_BLOCKS: u64 = 64;
+
+// The body before a region is read in blocks of this size to find the values
+// in effect at its start, and the values at the last few region starts are
+// kept, so that loading a region again or after them reads less
+const VALUE_SCAN_BLOCK_SIZE: u64 = 1048576;
+const MAX_SNAPSHOTS: usize = 8;
+
+/// The file offset of a `#time` line in a VCD body
+pub struct Checkpoint {
+  pub time: u64,
+  pub offset: u64,
+}
+
+/// The last value change of every id code before a checkpoint, as it is
+/// written in the file ("1!" or "b0101 \"")
+type Snapshot = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Random access index of a VCD body, for files that are too large to be
+/// parsed in full. Any time range can then be parsed on its own, from the
+/// checkpoint before its start to the checkpoint after its end.
+pub struct VcdIndex {
+  reader: WasmFileReader,
+  body_start: u64,
+  checkpoints: Vec<Checkpoint>,
+  // Values at the start of loaded regions by checkpoint, oldest first
+  snapshots: VecDeque<(usize, Snapshot)>,
+}
+
+impl VcdIndex {
+  /// Records a checkpoint about every `interval` bytes of the body, and one
+  /// for the last timestamp in the file
+  pub fn build(fd: u32, file_size: u64, body_start: u64, interval: u64) -> Result<Self, LoadError> {
+    let mut index = VcdIndex { reader: WasmFileReader::new(fd, file_size), body_start, checkpoints: Vec::new(), snapshots: VecDeque::new() };
+    let interval = std::cmp::max(interval, SCAN_BLOCK_SIZE);
+
+    // The first checkpoint is the start of the body, so that the initial
+    // $dumpvars are part of the first region
+    let first_time = index.find_timestamp(body_start)?.map_or(0, |c| c.time);
+    index.checkpoints.push(Checkpoint { time: first_time, offset: body_start });
+
+    let mut position = body_start + interval;
+    while position < file_size {
+      match index.find_timestamp(position)? {
+        Some(checkpoint) => {
+          position = checkpoint.offset + interval;
+          index.push(checkpoint);
+        },
+        None => {position += interval;}
+      }
+    }
+    if let Some(checkpoint) = index.find_last_timestamp()? {
+      index.push(checkpoint);
+    }
+    Ok(index)
+  }
+
+  // Timestamps only increase, a checkpoint that doesn't is in a comment
+  fn push(&mut self, checkpoint: Checkpoint) {
+    let last = &self.checkpoints[self.checkpoints.len() - 1];
+    if checkpoint.offset > last.offset && checkpoint.time >= last.time {
+      self.checkpoints.push(checkpoint);
+    }
+  }
+
+  pub fn times(&self) -> Vec<u64> {
+    self.checkpoints.iter().map(|c| c.time).collect()
+  }
+
+  fn read_at(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, LoadError> {
+    let mut data = vec![0; length as usize];
+    self.reader.seek(SeekFrom::Start(offset))
+      .and_then(|_| self.reader.read_exact(&mut data))
+      .map_err(|e| LoadError::new(ErrorKind::IoError, format!("Error reading file: {}", e)).at_offset(Some(offset)))?;
+    Ok(data)
+  }
+
+  // Finds the first timestamp at or after offset
+  fn find_timestamp(&mut self, offset: u64) -> Result<Option<Checkpoint>, LoadError> {
+    let file_size = self.reader.file_size;
+    // Start one byte early, a timestamp has to follow whitespace
+    let start = if offset > self.body_start {offset - 1} else {offset};
+    for block in 0..MAX_SCAN_BLOCKS {
+      let block_start = start + block * SCAN_BLOCK_SIZE;
+      if block_start >= file_size {break;}
+      let length = std::cmp::min(SCAN_BLOCK_SIZE + SCAN_OVERLAP, file_size - block_start);
+      let data = self.read_at(block_start, length)?;
+      let at_body_start = block_start == self.body_start;
+      // The first byte of the next block is checked here, where the byte before it is known
+      let scan_end = std::cmp::min(SCAN_BLOCK_SIZE as usize + 1, data.len());
+      for i in 0..scan_end {
+        if data[i] != b'#' {continue;}
+        if !((i == 0 && at_body_start) || (i > 0 && data[i - 1].is_ascii_whitespace())) {continue;}
+        if let Some(time) = parse_timestamp(&data[i + 1..], block_start + length == file_size) {
+          return Ok(Some(Checkpoint { time, offset: block_start + i as u64 }));
+        }
+      }
+    }
+    Ok(None)
+  }
+
+  fn find_last_timestamp(&mut self) -> Result<Option<Checkpoint>, LoadError> {
+    let file_size = self.reader.file_size;
+    let block_start = std::cmp::max(self.body_start, file_size.saturating_sub(SCAN_BLOCK_SIZE));
+    let data = self.read_at(block_start, file_size - block_start)?;
+    for i in (0..data.len()).rev() {
+      if data[i] != b'#' {continue;}
+      if i == 0 || !data[i - 1].is_ascii_whitespace() {continue;}
+      if let Some(time) = parse_timestamp(&data[i + 1..], true) {
+        return Ok(Some(Checkpoint { time, offset: block_start + i as u64 }));
+      }
+    }
+    Ok(None)
+  }
+
+  // The values in effect at a checkpoint. The body is read from the closest
+  // checkpoint before it that values are kept for, or from its start.
+  fn values_at(&mut self, checkpoint: usize) -> Result<Snapshot, LoadError> {
+    let closest = self.snapshots.iter().filter(|(i, _)| *i <= checkpoint).max_by_key(|(i, _)| *i);
+    let (mut offset, mut scanner) = match closest {
+      Some((i, values)) => (self.checkpoints[*i].offset, ValueScanner::new(values.clone())),
+      None => (self.body_start, ValueScanner::new(Snapshot::new())),
+    };
+    let end = self.checkpoints[checkpoint].offset;
+    while offset < end {
+      let length = std::cmp::min(VALUE_SCAN_BLOCK_SIZE, end - offset);
+      scanner.scan(&self.read_at(offset, length)?, false);
+      offset += length;
+    }
+    let values = scanner.finish();
+
+    self.snapshots.retain(|(i, _)| *i != checkpoint);
+    if self.snapshots.len() >= MAX_SNAPSHOTS {
+      self.snapshots.pop_front();
+    }
+    self.snapshots.push_back((checkpoint, values.clone()));
+    Ok(values)
+  }
+
+  /// Builds a VCD file holding the header and the part of the body that
+  /// covers the window. Unless the region starts with the body, its first
+  /// time step begins with a $dumpvars of the values in effect there, so
+  /// that signals which don't change early in the region still have a value.
+  pub fn region(&mut self, window: &TimeWindow) -> Result<Vec<u8>, LoadError> {
+    let first = self.checkpoints.partition_point(|c| c.time < window.start).saturating_sub(1);
+    let start = self.checkpoints[first].offset;
+    let end = self.checkpoints.iter().find(|c| c.time > window.end).map_or(self.reader.file_size, |c| c.offset);
+    if usize::try_from(self.body_start + (end - start)).is_err() {
+      return Err(LoadError::new(ErrorKind::InvalidArgument, "Time window is too large to be loaded"));
+    }
+
+    let mut file = self.read_at(0, self.body_start)?;
+    let body = self.read_at(start, end - start)?;
+    if start == self.body_start {
+      file.extend_from_slice(&body);
+      return Ok(file);
+    }
+
+    let values = self.values_at(first)?;
+    push_body_with_values(&mut file, &body, &values);
+    Ok(file)
+  }
+}
+
+// Appends a part of a body that starts with a timestamp, with the values in
+// effect before it as a $dumpvars after the timestamp. Signals that change
+// in the first time step are left out, so that they don't get two values at
+// the same time.
+fn push_body_with_values(file: &mut Vec<u8>, body: &[u8], values: &Snapshot) {
+  let timestamp = body.iter().position(|b| b.is_ascii_whitespace()).unwrap_or(body.len());
+  let mut first_step = ValueScanner::new(Snapshot::new());
+  first_step.scan(&body[timestamp..], true);
+  let changed: BTreeSet<Vec<u8>> = first_step.finish().into_keys().collect();
+
+  file.extend_from_slice(&body[..timestamp]);
+  file.extend_from_slice(b"\n$dumpvars\n");
+  for (_, change) in values.iter().filter(|(id, _)| !changed.contains(*id)) {
+    file.extend_from_slice(change);
+    file.push(b'\n');
+  }
+  file.extend_from_slice(b"$end");
+  file.extend_from_slice(&body[timestamp..]);
+}
+
+/// Follows the value changes of a VCD body that is read in blocks, keeping
+/// the last change of every id code
+struct ValueScanner {
+  values: Snapshot,
+  // A token that continues in the next block
+  token: Vec<u8>,
+  // A vector, real or string value whose id code comes next
+  value: Option<Vec<u8>>,
+  in_comment: bool,
+}
+
+impl ValueScanner {
+  fn new(values: Snapshot) -> Self {
+    ValueScanner { values, token: Vec::new(), value: None, in_comment: false }
+  }
+
+  /// Reads the tokens of a block. With stop_at_time, stops at the first
+  /// timestamp and returns true.
+  fn scan(&mut self, data: &[u8], stop_at_time: bool) -> bool {
+    for &b in data {
+      if !b.is_ascii_whitespace() {
+        self.token.push(b);
+        continue;
+      }
+      if self.token.is_empty() {continue;}
+      let token = std::mem::take(&mut self.token);
+      if stop_at_time && !self.in_comment && self.value.is_none() && token[0] == b'#' {
+        return true;
+      }
+      self.push_token(token);
+    }
+    false
+  }
+
+  fn push_token(&mut self, token: Vec<u8>) {
+    if self.in_comment {
+      self.in_comment = token != b"$end";
+      return;
+    }
+    if let Some(mut value) = self.value.take() {
+      value.push(b' ');
+      value.extend_from_slice(&token);
+      self.values.insert(token, value);
+      return;
+    }
+    match token[0] {
+      b'$' => {self.in_comment = token == b"$comment";},
+      b'#' => {},
+      b'b' | b'B' | b'r' | b'R' | b's' | b'S' => {self.value = Some(token);},
+      _ if token.len() > 1 => {self.values.insert(token[1..].to_vec(), token);},
+      _ => {},
+    }
+  }
+
+  fn finish(mut self) -> Snapshot {
+    if !self.token.is_empty() {
+      let token = std::mem::take(&mut self.token);
+      self.push_token(token);
+    }
+    self.values
+  }
+}
+
+// Parses the digits after a '#', which have to be followed by whitespace or
+// the end of the file
+fn parse_timestamp(data: &[u8], at_end_of_file: bool) -> Option<u64> {
+  let digits = data.iter().take_while(|b| b.is_ascii_digit()).count();
+  if digits == 0 || digits > 20 {return None;}
+  match data.get(digits) {
+    Some(b) if b.is_ascii_whitespace() => {},
+    None if at_end_of_file => {},
+    _ => {return None;}
+  }
+  std::str::from_utf8(&data[..digits]).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::{load_signal, vcd};
+
+  fn scan(blocks: &[&str]) -> Snapshot {
+    let mut scanner = ValueScanner::new(Snapshot::new());
+    for block in blocks {
+      scanner.scan(block.as_bytes(), false);
+    }
+    scanner.finish()
+  }
+
+  #[test]
+  fn keeps_the_last_change_of_every_id() {
+    let values = scan(&["$dumpvars 0! b0000 \" $end\n#10\n1! b01", "01 \"\n$comment 0! $end\n#20\nb1111 \""]);
+    assert_eq!(values.get(&b"!"[..]), Some(&b"1!".to_vec()));
+    assert_eq!(values.get(&b"\""[..]), Some(&b"b1111 \"".to_vec()));
+  }
+
+  #[test]
+  fn carries_values_into_a_region() {
+    let values = scan(&["#0\n$dumpvars 0! b0011 \" $end\n#10\n1!\n"]);
+    let file = vcd("");
+    let mut region = file.as_bytes().to_vec();
+    push_body_with_values(&mut region, b"#20\nb0101 \"\n#30\n0!\n", &values);
+
+    let region = String::from_utf8(region).unwrap();
+    let clk = load_signal(&region, "top.clk");
+    let data = load_signal(&region, "top.data");
+    let values_at = |loaded: &crate::test_util::LoadedSignal| -> Vec<(u64, String)> {
+      let signal = loaded.signal();
+      signal.iter_changes().map(|(i, value)| (loaded.time_table[i as usize], value.to_string())).collect()
+    };
+    assert_eq!(values_at(&clk), [(20, "1".to_string()), (30, "0".to_string())]);
+    assert_eq!(values_at(&data), [(20, "0101".to_string())]);
+  }
+}
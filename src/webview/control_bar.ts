@@ -2,6 +2,7 @@ import { EnumQueueEntry, SignalId, type RowId, StateChangeType } from '../common
 import { ActionType, type EventHandler } from './event_handler';
 import { vscodeWrapper, viewerState, viewport, rowHandler, config} from './vaporview';
 import { CustomVariable, NetlistVariable } from './signal_item';
+import { relativeTime } from '../common/functions';
 
 enum ButtonState {
   Disabled = 0,
@@ -351,7 +352,7 @@ export class ControlBar {
     const checkSearchValue = format.checkSearchValue;
   
     if (this.searchState === SearchState.Time && direction === 1) {
-      this.events.markerSet(parseInt(this.parsedSearchValue), 0, false);
+      this.events.markerSet(relativeTime(this.parsedSearchValue, viewport.timeOrigin), 0, false);
       updateState = true;
     } else {
       const signalWidth     = data.signalWidth;
@@ -1,8 +1,9 @@
-import { type NetlistId, type SignalId, type RowId, type ValueChange, type EnumData, type EnumEntry, type QueueEntry, type SignalQueueEntry, type EnumQueueEntry, NameType, CollapseState, type BitRangeSource, type ValueChangeDataChunk, type CompressedValueChangeDataChunk, type EnumDataChunk } from '../common/types';
+import { type NetlistId, type SignalId, type RowId, type ValueChange, type WaveformDumpMetadata, type EnumData, type EnumEntry, type QueueEntry, type SignalQueueEntry, type EnumQueueEntry, NameType, CollapseState, type BitRangeSource, type ValueChangeDataChunk, type CompressedValueChangeDataChunk, type EnumDataChunk, type TimeTableChunk } from '../common/types';
 import { ActionType, type EventHandler } from './event_handler';
 import { viewerState, viewport, dataManager, getParentGroupId, labelsPanel, controlBar, rowHandler, events, vscodeWrapper, config } from './vaporview';
 import { SignalGroup, NetlistVariable, RowItem, SignalSeparator, isAnalogSignal, CustomVariable } from './signal_item';
 
+import { addTime, relativeTime } from '../common/functions';
 import * as LZ4 from 'lz4js';
 
 export type FormattedValueData = {
@@ -40,6 +41,115 @@ export interface CustomWaveformData extends WaveformData {
   dataLoaded: boolean;
 }
 
+// Value kinds of the binary transition encoding, see transition_encoding.rs
+const TRANSITION_EVENT        = 0;
+const TRANSITION_BIT_VECTOR   = 1;
+const TRANSITION_REAL         = 2;
+const TRANSITION_STRING       = 3;
+const TRANSITION_TIME_INDICES = 0x80; // flag, times are time table indices
+
+// Bit characters by state, for 1, 2 and 4 bits per bit
+const bitStateLookup: Record<number, string> = {1: "01", 2: "01xz", 4: "01xzhuwl-"};
+
+// Decodes LEB128 numbers, which can be wider than 32 bits, so no bitwise
+// operators. Numbers past what a number holds exactly are returned as a bigint.
+function readVarint(data: Uint8Array, offset: number): [number | bigint, number] {
+  let value = 0;
+  let scale = 1;
+  let byte  = 0;
+  const start = offset;
+  do {
+    byte   = data[offset++];
+    value += (byte & 0x7f) * scale;
+    scale *= 128;
+  } while (byte & 0x80);
+  if (value <= Number.MAX_SAFE_INTEGER) {return [value, offset];}
+
+  let exact = 0n;
+  for (let i = offset - 1; i >= start; i--) {
+    exact = (exact << 7n) | BigInt(data[i] & 0x7f);
+  }
+  return [exact, offset];
+}
+
+// Decodes the time table sent by sendtimetablechunk() after decompression,
+// with times relative to the time origin
+export function decodeTimeTable(data: Uint8Array, length: number, origin: bigint): number[] {
+  const timeTable: number[] = new Array(length);
+  let offset = 0;
+  let time: number | bigint = 0;
+  for (let i = 0; i < length; i++) {
+    let delta: number | bigint = 0;
+    [delta, offset] = readVarint(data, offset);
+    time = addTime(time, delta);
+    timeTable[i] = relativeTime(time, origin);
+  }
+  return timeTable;
+}
+
+// Decodes the value changes sent by sendcompressedtransitiondata() after
+// decompression. Signal data that holds time table indices needs the time
+// table, other times are made relative to the time origin.
+export function decodeTransitionData(data: Uint8Array, signalWidth: number, timeTable: number[], origin: bigint): ValueChange[] {
+  const transitionData: ValueChange[] = [];
+  if (data.length === 0) {return transitionData;}
+  const dataView    = new DataView(data.buffer, data.byteOffset, data.byteLength);
+  const textDecoder = new TextDecoder();
+  const kind        = data[0] & ~TRANSITION_TIME_INDICES;
+  const timeIndices = (data[0] & TRANSITION_TIME_INDICES) !== 0;
+  let offset = 1;
+  let time: number | bigint = 0;
+
+  const nextVarint = () => {
+    let value: number | bigint = 0;
+    [value, offset] = readVarint(data, offset);
+    return value;
+  };
+
+  while (offset < data.length) {
+    time = addTime(time, nextVarint());
+    const changeTime = timeIndices ? timeTable[Number(time)] : relativeTime(time, origin);
+    switch (kind) {
+      case TRANSITION_BIT_VECTOR: {
+        const stateBits  = data[offset++];
+        const lookup     = bitStateLookup[stateBits];
+        const perByte    = 8 / stateBits;
+        const mask       = (1 << stateBits) - 1;
+        const byteCount  = Math.ceil(signalWidth / perByte);
+        const firstCount = signalWidth % perByte || perByte;
+        let value = "";
+        for (let i = 0; i < byteCount; i++) {
+          const byte  = data[offset + i];
+          const count = i === 0 ? firstCount : perByte;
+          for (let j = count - 1; j >= 0; j--) {
+            value += lookup[(byte >> (j * stateBits)) & mask];
+          }
+        }
+        offset += byteCount;
+        transitionData.push([changeTime, value]);
+        break;
+      }
+      case TRANSITION_REAL: {
+        transitionData.push([changeTime, dataView.getFloat64(offset, true).toString()]);
+        offset += 8;
+        break;
+      }
+      case TRANSITION_STRING: {
+        const length = Number(nextVarint());
+        transitionData.push([changeTime, textDecoder.decode(data.subarray(offset, offset + length))]);
+        offset += length;
+        break;
+      }
+      case TRANSITION_EVENT:
+      default: {
+        transitionData.push([changeTime, "Event"]);
+        break;
+      }
+    }
+  }
+  return transitionData;
+}
+
 export class WaveformDataManager {
   private events: EventHandler;
   requested: QueueEntry[] = [];
@@ -52,6 +162,8 @@ export class WaveformDataManager {
   customValueChangeData: CustomWaveformData[] = [];
   enumTable: Record<string, EnumData> = {}; // enum type is the key/index, array of enum values is the value
   enumTableTemp: Record<string, { totalChunks: number; chunkLoaded: boolean[]; chunkData: string[] } | undefined> = {};
+  timeTable: number[] = []; // time by time table index, for signal data that holds indices
+  timeTableTemp: Uint8Array[] = [];
 
   private nextCustomSignalId: number = 0;
 
@@ -75,6 +187,8 @@ export class WaveformDataManager {
     this.valueChangeDataTemp = [];
     this.enumTable           = {};
     this.enumTableTemp       = {};
+    this.timeTable           = [];
+    this.timeTableTemp       = [];
     this.waveDromClock       = {netlistId: null, edge: ""};
 
     this.requested           = [];
@@ -84,6 +198,35 @@ export class WaveformDataManager {
     rowHandler.unload();
   }
 
+  // Drops the loaded signal data and requests the signals of all rows again,
+  // after the host loaded another part of the file or the file grew
+  reloadSignalData(metadata: WaveformDumpMetadata) {
+    viewport.timeTableCount  = metadata.timeTableCount;
+    if (metadata.timeEnd !== viewport.timeStop) {
+      viewport.timeStop = metadata.timeEnd;
+      viewport.updateViewportWidth();
+    }
+    this.valueChangeData     = [];
+    this.valueChangeDataTemp = [];
+    this.requested           = [];
+    this.queued              = [];
+    this.requestActive       = false;
+
+    const signalIdList: SignalQueueEntry[] = [];
+    rowHandler.rowItems.forEach((rowItem) => {
+      if (!(rowItem instanceof NetlistVariable) || rowItem.signalId === undefined) {return;}
+      signalIdList.push({type: 'signal', signalWidth: rowItem.signalWidth, signalId: rowItem.signalId, rowId: rowItem.rowId});
+    });
+    this.customValueChangeData.forEach((customSignal, customSignalId) => {
+      customSignal.dataLoaded = false;
+      customSignal.source.forEach((s) => {
+        if (s.signalId === undefined) {return;}
+        signalIdList.push({type: 'signal', signalWidth: s.signalWidth, signalId: s.signalId, customSignalId: customSignalId});
+      });
+    });
+    this.requestData(signalIdList, []);
+  }
+
   // This is a simple queue to handle the fetching of waveform data
   // It's overkill for everything except large FST waveform dumps with lots of
   // Value Change Blocks. Batch fetching is much faster than individual fetches,
@@ -172,6 +315,12 @@ export class WaveformDataManager {
     let transitionData: ValueChange[];
     if (typeof firstChunk === "string") {
       transitionData = JSON.parse((chunkData as string[]).join(""));
+      // Times too large for a number to hold exactly are sent as strings.
+      // Times only increase, so there are none if the last time is a number.
+      const origin = viewport.timeOrigin;
+      if (origin !== 0n || typeof transitionData[transitionData.length - 1]?.[0] === "string") {
+        transitionData = transitionData.map(([time, value]) => [relativeTime(time, origin), value]);
+      }
     } else if (Array.isArray(firstChunk)) { // We're receiving array from fsdb worker
       transitionData = (chunkData as ValueChange[][]).flat();
     } else {
@@ -212,22 +361,40 @@ export class WaveformDataManager {
     this.updateEnum(enumName, enumData);
   }
 
+  updateTimeTableChunk(message: TimeTableChunk) {
+    // Like signal data, only the last chunk has the total
+    if (message.chunkNum === 0) {this.timeTableTemp = [];}
+    this.timeTableTemp[message.chunkNum] = new Uint8Array(message.compressedDataChunk);
+    if (message.totalChunks === 0 || this.timeTableTemp.filter((chunk) => chunk !== undefined).length < message.totalChunks) {return;}
+
+    const totalCompressedSize = this.timeTableTemp.reduce((total, chunk) => total + chunk.length, 0);
+    const fullCompressedData  = new Uint8Array(totalCompressedSize);
+    let offset = 0;
+    for (const chunk of this.timeTableTemp) {
+      fullCompressedData.set(chunk, offset);
+      offset += chunk.length;
+    }
+    this.timeTable     = decodeTimeTable(LZ4.decompress(fullCompressedData), message.timeTableLength, viewport.timeOrigin);
+    this.timeTableTemp = [];
+  }
+
   updateWaveformChunkCompressed(message: CompressedValueChangeDataChunk) {
     const signalId = message.signalId;
-    
-    if (this.valueChangeDataTemp[signalId].totalChunks === 0) {
+
+    // Chunks are sent while the data is compressed, so the total is only
+    // known with the last chunk
+    if (message.totalChunks > 0) {
       this.valueChangeDataTemp[signalId].totalChunks = message.totalChunks;
-      this.valueChangeDataTemp[signalId].chunkLoaded = new Array(message.totalChunks).fill(false);
-      this.valueChangeDataTemp[signalId].compressedChunks = new Array(message.totalChunks);
       this.valueChangeDataTemp[signalId].originalSize = message.originalSize;
     }
 
     // Store the compressed chunk as Uint8Array
     this.valueChangeDataTemp[signalId].compressedChunks[message.chunkNum] = new Uint8Array(message.compressedDataChunk);
     this.valueChangeDataTemp[signalId].chunkLoaded[message.chunkNum] = true;
-    const allChunksLoaded = this.valueChangeDataTemp[signalId].chunkLoaded.every((chunk: boolean) => {return chunk;});
+    const totalChunks  = this.valueChangeDataTemp[signalId].totalChunks;
+    const loadedChunks = this.valueChangeDataTemp[signalId].chunkLoaded.filter((chunk: boolean) => {return chunk;}).length;
 
-    if (!allChunksLoaded) {return;}
+    if (totalChunks === 0 || loadedChunks < totalChunks) {return;}
 
     //console.log('all compressed chunks loaded');
 
@@ -245,23 +412,8 @@ export class WaveformDataManager {
       }
 
       // Decompress the LZ4 frame data (size is included in frame header)
-      const originalSize = this.valueChangeDataTemp[signalId].originalSize;
       const decompressedData = LZ4.decompress(fullCompressedData);
-      const byteIncrement = 8 + message.signalWidth;
-      let time = 0;
-      const transitionData: ValueChange[] = [];
-      
-      // Create DataView once from the entire decompressed data
-      const dataView = new DataView(decompressedData.buffer, decompressedData.byteOffset, decompressedData.byteLength);
-      
-      for (let i = 0; i < decompressedData.length; i += byteIncrement) {
-        const j = i + 8;
-        // Read u64 directly from the DataView at offset i
-        const deltaTime = dataView.getBigUint64(i, true);
-        const value = String.fromCharCode(...decompressedData.slice(j, j + message.signalWidth));
-        time += Number(deltaTime);
-        transitionData.push([time, value]);
-      }
+      const transitionData = decodeTransitionData(decompressedData, message.signalWidth, this.timeTable, viewport.timeOrigin);
 
       if (!this.requestActive) {
         vscodeWrapper.outputLog("Compressed request complete, time: " + (Date.now() - this.requestStart) / 1000 + " seconds");
@@ -449,7 +449,7 @@ export class NetlistVariable extends SignalItem implements RowItem {
       numberFormat: this.valueFormat.id,
       value: value,
       formattedValue: formattedValue,
-      time: timeValue,
+      time: viewport.toAbsoluteTime(timeValue),
     };
 
     vscodeWrapper.emitValueLinkEvent(event);
@@ -1,5 +1,5 @@
 import { NetlistId, SignalId, type RowId, EnumData, EnumEntry, StateChangeType, type DocumentId, type DefaultWebviewContext, type RulerContext, type WaveformDumpMetadata } from '../common/types';
-import { logScaleFromUnits } from '../common/functions';
+import { logScaleFromUnits, formatTime, absoluteTime } from '../common/functions';
 import { ActionType, type EventHandler } from './event_handler';
 import { viewerState, dataManager, handleClickSelection, controlBar, dragController } from "./vaporview";
 import { ValueFormat } from './value_format';
@@ -75,6 +75,8 @@ export class Viewport {
   displayTimeUnit: string     = 'ns';
   timeStop: number            = 0;
   timeTableCount: number      = 0;
+  // Times are relative to this, see WaveformDumpMetadata.timeOrigin
+  timeOrigin: bigint          = 0n;
   // Shared drag state for whichever scrollbar is currently being dragged
   scrollbarDragStart: number  = 0;
   pointerDragStart: number    = 0;
@@ -109,6 +111,8 @@ export class Viewport {
   scrollEventPending: boolean = false;
   resizePending: boolean      = false;
   hoverItemRowId: RowId | null = null;
+  // Reports the time range in view once scrolling or zooming stops
+  viewportRangeTimer: ReturnType<typeof setTimeout> | undefined = undefined;
 
   constructor(
     private events: EventHandler,
@@ -239,6 +243,7 @@ export class Viewport {
     this.timeUnit         = metadata.timeUnit;
     this.displayTimeUnit  = metadata.timeUnit;
     this.timeStop         = metadata.timeEnd;
+    this.timeOrigin       = BigInt(metadata.timeOrigin);
     this.timeTableCount   = metadata.timeTableCount;
     this.defaultPixelTime = 10 ** (Math.round(Math.log10(Number(metadata.minTimeStep))) | 0);
     this.zoomRatio        = 1 / this.defaultPixelTime;
@@ -723,7 +728,7 @@ export class Viewport {
     this.updateMarker();
     this.updateScrollContainer();
     labelElement.style.display = 'block';
-    labelElement.innerText = this.scaleTime(time) + ' ' + this.displayTimeUnit;
+    labelElement.innerText = this.formatTime(time) + ' ' + this.displayTimeUnit;
   }
 
   updateScrollContainer() {
@@ -788,6 +793,17 @@ export class Viewport {
     }
   }
 
+  // A time in the display unit, exact for times past what a number holds
+  formatTime(time: number) {
+    if (this.timeOrigin === 0n) {return this.scaleTime(time).toString();}
+    return formatTime(time, this.timeOrigin, this.timeScale, this.adjustedLogTimeScale);
+  }
+
+  // A time as in the file, for events sent outside of the webview
+  toAbsoluteTime(time: number) {
+    return absoluteTime(time, this.timeOrigin);
+  }
+
   updateUnits(units: string, updateContext: boolean) {
     const validUnits = ['fs', 'ps', 'ns', 'µs', 'us', 'ms', 's'];
 
@@ -800,10 +816,10 @@ export class Viewport {
     this.displayTimeUnit = newUnits;
     this.adjustedLogTimeScale = logScaleFromUnits(this.timeUnit) - logScaleFromUnits(units);
     if (viewerState.markerTime !== null) {
-      this.markerLabelElement.innerText = this.scaleTime(viewerState.markerTime) + ' ' + this.displayTimeUnit;
+      this.markerLabelElement.innerText = this.formatTime(viewerState.markerTime) + ' ' + this.displayTimeUnit;
     }
     if (viewerState.altMarkerTime !== null) {
-      this.altMarkerLabelElement.innerText = this.scaleTime(viewerState.altMarkerTime) + ' ' + this.displayTimeUnit;
+      this.altMarkerLabelElement.innerText = this.formatTime(viewerState.altMarkerTime) + ' ' + this.displayTimeUnit;
     }
     this.updateRuler();
     if (updateContext) {
@@ -862,7 +878,9 @@ export class Viewport {
     this.rulerLineX = [];
     ctx.fillStyle = styles.rulerTextColor;
     while (numberX <= this.rulerWidth + 50) {
-      if (this.adjustedLogTimeScale > 0) {
+      if (this.timeOrigin !== 0n) {
+        valueString = this.formatTime(number);
+      } else if (this.adjustedLogTimeScale > 0) {
         valueString = (number * this.timeScale * scale).toString();
       } else {
         valueString = (number * this.timeScale / scale).toString();
@@ -1142,6 +1160,10 @@ export class Viewport {
     this.updateBackgroundCanvas();
     this.renderAllWaveforms();
     this.updatePending = false;
+    clearTimeout(this.viewportRangeTimer);
+    this.viewportRangeTimer = setTimeout(() => {
+      vscodeWrapper.viewportRangeChanged(Math.floor(this.timeScrollLeft), Math.ceil(this.timeScrollRight));
+    }, 250);
   }
 
   updateElementHeight() {
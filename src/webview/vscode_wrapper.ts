@@ -449,6 +449,7 @@ export class ThemeColors {
 export class VscodeWrapper {
 
   private initComplete: boolean = false;
+  private lastViewportRange: [number, number] = [-1, -1];
 
   constructor(private events: EventHandler) {
 
@@ -471,6 +472,7 @@ export class VscodeWrapper {
 
   setInitComplete() {
     this.initComplete = true;
+    this.lastViewportRange = [-1, -1];
   }
 
   handleMessage(e: MessageEvent) {
@@ -496,6 +498,8 @@ export class VscodeWrapper {
       case 'update-waveform-chunk': {dataManager.updateWaveformChunk(message); break;}
       case 'update-waveform-chunk-compressed': {dataManager.updateWaveformChunkCompressed(message); break;}
       case 'update-enum-chunk':     {dataManager.updateEnumChunk(message); break;}
+      case 'update-time-table-chunk': {dataManager.updateTimeTableChunk(message); break;}
+      case 'reloadSignalData':      {dataManager.reloadSignalData(message.metadata); break;}
       case 'handle-keypress':       {this.externalKeyDownHandler(message); break;}
       case 'setWaveDromClock':      {dataManager.waveDromClock = {netlistId: message.netlistId, edge:  message.edge,}; break;}
       case 'setMarker':             {this.setMarker(message.time, message.markerType); break;}
@@ -705,10 +709,18 @@ export class VscodeWrapper {
     } as EmitEventMessage);
   }
 
+  // The host loads only part of some files, and loads the part that is in
+  // view when it changes
+  viewportRangeChanged(startTime: number, endTime: number) {
+    if (startTime === this.lastViewportRange[0] && endTime === this.lastViewportRange[1]) {return;}
+    this.lastViewportRange = [startTime, endTime];
+    vscode.postMessage({command: 'viewportRange', startTime: startTime, endTime: endTime});
+  }
+
   emitMarkerSetEvent(time: number, units: string) {
     const eventData: MarkerSetEvent = {
       uri: viewerState.uri?.toString() || "",
-      time: time,
+      time: viewport.toAbsoluteTime(time),
       units: units,
     };
     vscode.postMessage({